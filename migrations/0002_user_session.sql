@@ -0,0 +1,7 @@
+CREATE TABLE user_session (
+    token VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX user_session_user_idx ON user_session (user_id);
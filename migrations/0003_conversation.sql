@@ -0,0 +1,18 @@
+CREATE TABLE conversation (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    title VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX conversation_user_idx ON conversation (user_id);
+
+CREATE TABLE message (
+    id BIGSERIAL PRIMARY KEY,
+    conversation_id BIGINT NOT NULL REFERENCES conversation (id) ON DELETE CASCADE,
+    from_llm BOOLEAN NOT NULL,
+    text TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX message_conversation_idx ON message (conversation_id, id);
@@ -0,0 +1,2 @@
+-- NULL means the configured default model
+ALTER TABLE conversation ADD COLUMN model_id VARCHAR;
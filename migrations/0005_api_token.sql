@@ -0,0 +1,9 @@
+CREATE TABLE api_token (
+    id SERIAL PRIMARY KEY,
+    token VARCHAR NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX api_token_user_idx ON api_token (user_id);
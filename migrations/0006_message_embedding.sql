@@ -0,0 +1,7 @@
+-- Plain REAL[] instead of a pgvector column, so the extension isn't required.
+-- Ranking by cosine similarity happens in the server.
+CREATE TABLE message_embedding (
+    message_id BIGINT PRIMARY KEY REFERENCES message (id) ON DELETE CASCADE,
+    model_id VARCHAR NOT NULL,
+    embedding REAL[] NOT NULL
+);
@@ -0,0 +1,41 @@
+-- Uploaded documents, split into embedded chunks, grouped into collections per user
+CREATE TABLE collection (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX collection_user_idx ON collection (user_id);
+
+CREATE TABLE document (
+    id BIGSERIAL PRIMARY KEY,
+    collection_id BIGINT NOT NULL REFERENCES collection (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    -- chunks the document was split into, they are embedded in the background
+    chunks INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX document_collection_idx ON document (collection_id);
+
+CREATE TABLE document_chunk (
+    id BIGSERIAL PRIMARY KEY,
+    document_id BIGINT NOT NULL REFERENCES document (id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    model_id VARCHAR NOT NULL,
+    embedding REAL[] NOT NULL
+);
+
+CREATE INDEX document_chunk_document_idx ON document_chunk (document_id, position);
+
+ALTER TABLE conversation ADD COLUMN collection_id BIGINT REFERENCES collection (id) ON DELETE SET NULL;
+
+-- the chunks an answer was grounded on, `rank` is the number it cites them by
+CREATE TABLE message_source (
+    message_id BIGINT NOT NULL REFERENCES message (id) ON DELETE CASCADE,
+    chunk_id BIGINT NOT NULL REFERENCES document_chunk (id) ON DELETE CASCADE,
+    rank INTEGER NOT NULL,
+    PRIMARY KEY (message_id, chunk_id)
+);
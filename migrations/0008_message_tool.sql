@@ -0,0 +1,4 @@
+-- Tool invocations are stored as messages of their own, with the call and its result
+ALTER TABLE message ADD COLUMN tool_name VARCHAR;
+ALTER TABLE message ADD COLUMN tool_arguments TEXT;
+ALTER TABLE message ADD COLUMN tool_result TEXT;
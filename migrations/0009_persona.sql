@@ -0,0 +1,21 @@
+ALTER TABLE user_table ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT false;
+
+-- Saved system prompts with default sampling settings and model. Global personas
+-- are published by admins and offered to every user.
+CREATE TABLE persona (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    system_prompt TEXT NOT NULL,
+    model_id VARCHAR,
+    temperature REAL,
+    top_p REAL,
+    top_k INTEGER,
+    repeat_penalty REAL,
+    global BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX persona_user_idx ON persona (user_id);
+
+ALTER TABLE conversation ADD COLUMN persona_id BIGINT REFERENCES persona (id) ON DELETE SET NULL;
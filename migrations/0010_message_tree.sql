@@ -0,0 +1,12 @@
+-- Messages form a tree: regenerating an answer or editing a message adds a sibling.
+-- The conversation remembers the leaf of the active path.
+ALTER TABLE message ADD COLUMN parent_id BIGINT REFERENCES message (id) ON DELETE CASCADE;
+ALTER TABLE conversation ADD COLUMN leaf_id BIGINT REFERENCES message (id) ON DELETE SET NULL;
+
+-- existing conversations become a single path
+UPDATE message m SET parent_id = (
+    SELECT max(p.id) FROM message p WHERE p.conversation_id = m.conversation_id AND p.id < m.id
+);
+UPDATE conversation c SET leaf_id = (SELECT max(m.id) FROM message m WHERE m.conversation_id = c.id);
+
+CREATE INDEX message_parent_idx ON message (parent_id);
@@ -0,0 +1,19 @@
+-- Every inference with its cost, for accounting and quotas
+CREATE TABLE usage (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    model_id VARCHAR NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    wall_ms INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX usage_user_idx ON usage (user_id, created_at);
+
+-- Token budgets of single users, NULL keeps the budget of their role from jippity.toml
+CREATE TABLE user_quota (
+    user_id INTEGER PRIMARY KEY REFERENCES user_table (id) ON DELETE CASCADE,
+    daily_tokens BIGINT,
+    monthly_tokens BIGINT
+);
@@ -0,0 +1,7 @@
+-- The sampling settings and seed an answer was generated with, so it can be replayed
+ALTER TABLE message
+    ADD COLUMN temperature REAL,
+    ADD COLUMN top_p REAL,
+    ADD COLUMN top_k INTEGER,
+    ADD COLUMN repeat_penalty REAL,
+    ADD COLUMN seed BIGINT;
@@ -0,0 +1,11 @@
+-- The Postgres tier of the response cache, see [cache] in jippity.toml
+CREATE TABLE response_cache (
+    key VARCHAR PRIMARY KEY,
+    model_id VARCHAR NOT NULL,
+    response TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX response_cache_model_idx ON response_cache (model_id);
+
+ALTER TABLE usage ADD COLUMN cached BOOLEAN NOT NULL DEFAULT false;
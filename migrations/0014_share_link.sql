@@ -0,0 +1,13 @@
+-- Read-only snapshots of a conversation, opened by their token without an account.
+-- Deleting a row revokes its link.
+CREATE TABLE share_link (
+    token VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    conversation_id BIGINT NOT NULL REFERENCES conversation (id) ON DELETE CASCADE,
+    title VARCHAR NOT NULL,
+    -- the messages of the active path as JSON, later turns don't show up
+    snapshot TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX share_link_user_idx ON share_link (user_id);
@@ -0,0 +1,7 @@
+-- Thumbs up (1) or down (-1) on an answer with an optional comment, for evaluation datasets
+ALTER TABLE message
+    ADD COLUMN rating SMALLINT CHECK (rating IN (-1, 1)),
+    ADD COLUMN feedback TEXT,
+    ADD COLUMN rated_at TIMESTAMPTZ;
+
+CREATE INDEX message_rated_idx ON message (rated_at) WHERE rating IS NOT NULL;
@@ -0,0 +1,19 @@
+-- Every decision of the moderation rules, on chat messages and API requests. The matched
+-- text itself is not kept, flagged chat messages are reviewed through message_id;
+-- blocked ones and API requests were never stored.
+CREATE TABLE moderation_audit (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user_table (id) ON DELETE CASCADE,
+    origin VARCHAR NOT NULL CHECK (origin IN ('chat', 'api')),
+    conversation_id BIGINT REFERENCES conversation (id) ON DELETE SET NULL,
+    message_id BIGINT REFERENCES message (id) ON DELETE SET NULL,
+    stage VARCHAR NOT NULL CHECK (stage IN ('input', 'output')),
+    rule VARCHAR NOT NULL,
+    action VARCHAR NOT NULL CHECK (action IN ('block', 'redact', 'flag')),
+    matches INTEGER NOT NULL,
+    reviewed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX moderation_audit_user_idx ON moderation_audit (user_id, created_at);
+CREATE INDEX moderation_audit_review_idx ON moderation_audit (created_at) WHERE action = 'flag' AND reviewed_at IS NULL;
@@ -0,0 +1,4 @@
+-- Sessions end after SESSION_MAX_AGE_SECS, like their cookie. Existing ones get the same
+-- lifetime from now on.
+ALTER TABLE user_session ADD COLUMN expires_at TIMESTAMPTZ NOT NULL DEFAULT now() + interval '30 days';
+ALTER TABLE user_session ALTER COLUMN expires_at DROP DEFAULT;
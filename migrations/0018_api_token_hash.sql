@@ -0,0 +1,12 @@
+-- Tokens are kept as their SHA-256 only, with the first characters for the token list
+ALTER TABLE api_token
+    ADD COLUMN token_hash VARCHAR,
+    ADD COLUMN prefix VARCHAR;
+
+UPDATE api_token SET token_hash = encode(sha256(convert_to(token, 'UTF8')), 'hex'), prefix = left(token, 8);
+
+ALTER TABLE api_token
+    ALTER COLUMN token_hash SET NOT NULL,
+    ALTER COLUMN prefix SET NOT NULL,
+    ADD CONSTRAINT api_token_hash_key UNIQUE (token_hash),
+    DROP COLUMN token;
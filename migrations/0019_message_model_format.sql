@@ -0,0 +1,5 @@
+-- The model and response format an answer was generated with, a replay needs them
+-- besides the sampling settings. Answers stored before have neither and can't be replayed.
+ALTER TABLE message
+    ADD COLUMN model_id VARCHAR,
+    ADD COLUMN response_format TEXT;
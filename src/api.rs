@@ -0,0 +1,613 @@
+// OpenAI compatible REST API, so existing tooling can talk to Jippity.
+// Requests run through the same queue, inference path and moderation rules as the chat.
+use crate::app::ssr::{create_db_conn, AppState};
+use crate::components::api_tokens::token_hash;
+use crate::components::jippity::{Conversation, Message};
+use crate::inference::cache::CacheMode;
+use crate::inference::config::ModelConfig;
+use crate::inference::context::fit_prompt;
+use crate::inference::embeddings::{embed, EmbeddingError};
+use crate::inference::engine::{
+    count_tokens, run_generation, FinishReason, GenerationError, GenerationJob, GenerationOutput,
+};
+use crate::inference::events::ChatEvent;
+use crate::inference::grammar::ResponseFormat;
+use crate::inference::moderation::{hold_tokens, moderate, Checked, Stage};
+use crate::inference::registry::RegistryError;
+use crate::inference::sampling::SamplingSettings;
+use crate::moderation::{record_decisions, AuditSource};
+use crate::usage::usage_info;
+use axum::extract::Extension;
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub struct ApiError {
+    status: StatusCode,
+    kind: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, kind: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError { status, kind, message: message.into() }
+    }
+
+    fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+    }
+
+    fn internal(err: impl std::fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server_error", err.to_string())
+    }
+}
+
+impl From<GenerationError> for ApiError {
+    fn from(err: GenerationError) -> ApiError {
+        match err {
+            GenerationError::Queue(err) => {
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limit_error", err.to_string())
+            }
+            GenerationError::Sampling(message) => ApiError::bad_request(message),
+            err => ApiError::internal(err),
+        }
+    }
+}
+
+impl From<RegistryError> for ApiError {
+    fn from(err: RegistryError) -> ApiError {
+        match err {
+            RegistryError::Loading(_) | RegistryError::Unavailable(_) => {
+                ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "server_error", err.to_string())
+            }
+            err => ApiError::internal(err),
+        }
+    }
+}
+
+impl From<EmbeddingError> for ApiError {
+    fn from(err: EmbeddingError) -> ApiError {
+        match err {
+            EmbeddingError::Queue(err) => GenerationError::Queue(err).into(),
+            EmbeddingError::Registry(err) => err.into(),
+            err => ApiError::internal(err),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({ "error": { "message": self.message, "type": self.kind, "code": null } });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+// `Authorization: Bearer <token>` with a token from the api_token table
+async fn authenticate(headers: &HeaderMap) -> Result<i32, ApiError> {
+    let unauthorized = || {
+        ApiError::new(StatusCode::UNAUTHORIZED, "invalid_request_error", "Invalid or missing API token")
+    };
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(unauthorized)?;
+
+    let pool = create_db_conn().await.map_err(ApiError::internal)?;
+    let user: Option<(i32,)> = sqlx::query_as("SELECT user_id FROM api_token WHERE token_hash = $1")
+        .bind(token_hash(token.trim()))
+        .fetch_optional(&pool)
+        .await
+        .map_err(ApiError::internal)?;
+
+    user.map(|(id,)| id).ok_or_else(unauthorized)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct StreamOptions {
+    #[serde(default)]
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    model: Option<String>,
+    messages: Vec<ApiMessage>,
+    max_tokens: Option<usize>,
+    #[serde(flatten)]
+    sampling: SamplingSettings,
+    stop: Option<OneOrMany>,
+    #[serde(default)]
+    stream: bool,
+    stream_options: Option<StreamOptions>,
+    n: Option<usize>,
+    #[serde(default)]
+    response_format: ResponseFormat,
+}
+
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    model: Option<String>,
+    prompt: OneOrMany,
+    max_tokens: Option<usize>,
+    #[serde(flatten)]
+    sampling: SamplingSettings,
+    stop: Option<OneOrMany>,
+    #[serde(default)]
+    stream: bool,
+    stream_options: Option<StreamOptions>,
+    n: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingRequest {
+    model: Option<String>,
+    input: OneOrMany,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Chat,
+    Text,
+}
+
+// A request turned into a prompt for one of our models
+struct Completion {
+    endpoint: Endpoint,
+    model_config: ModelConfig,
+    prompt: String,
+    prompt_tokens: usize,
+    stop: Vec<String>,
+    max_tokens: usize,
+    sampling: SamplingSettings,
+    // response_format of a chat request, Text for plain completions
+    format: ResponseFormat,
+    stream: bool,
+    include_usage: bool,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn find_model(state: &AppState, id: &str) -> Result<ModelConfig, ApiError> {
+    state
+        .config
+        .model(id)
+        .cloned()
+        .map_err(|err| ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", err.to_string()))
+}
+
+fn model_config(state: &AppState, model: Option<&str>) -> Result<ModelConfig, ApiError> {
+    match model {
+        Some(id) => find_model(state, id),
+        None => Ok(state.config.default_model().clone()),
+    }
+}
+
+async fn check_quota(state: &AppState, user_id: i32) -> Result<(), ApiError> {
+    let usage = usage_info(state, user_id).await.map_err(ApiError::internal)?;
+    usage
+        .check()
+        .map_err(|err| ApiError::new(StatusCode::TOO_MANY_REQUESTS, "insufficient_quota", err.to_string()))
+}
+
+fn check_n(n: Option<usize>) -> Result<(), ApiError> {
+    match n {
+        None | Some(1) => Ok(()),
+        Some(_) => Err(ApiError::bad_request("Only n = 1 is supported")),
+    }
+}
+
+fn max_tokens(model_config: &ModelConfig, requested: Option<usize>) -> Result<usize, ApiError> {
+    match requested {
+        Some(0) => Err(ApiError::bad_request("max_tokens must be at least 1")),
+        Some(n) if n >= model_config.context_size => Err(ApiError::bad_request(format!(
+            "max_tokens must be smaller than the context size of {} tokens",
+            model_config.context_size
+        ))),
+        Some(n) => Ok(n),
+        None => Ok(model_config.max_response_tokens),
+    }
+}
+
+fn context_exceeded(model_config: &ModelConfig) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!("The prompt does not fit into the context of {} tokens", model_config.context_size),
+    )
+}
+
+// The caller writes every message, earlier answers included, so all of them are input
+fn input_texts(messages: &mut [ApiMessage]) -> Vec<&mut String> {
+    messages.iter_mut().map(|message| &mut message.content).collect()
+}
+
+// Checks and redacts the request's text in place. The decisions go to the audit log,
+// a blocked text fails the whole request.
+async fn moderate_input(state: &AppState, user_id: i32, texts: Vec<&mut String>) -> Result<(), ApiError> {
+    let mut all = Checked { text: String::new(), decisions: Vec::new() };
+    for text in texts {
+        let checked = moderate(state, user_id, Stage::Input, text).await;
+        *text = checked.text;
+        all.decisions.extend(checked.decisions);
+    }
+    let blocked_by = all.blocked().then(|| all.blocked_by());
+    tokio::spawn(record_decisions(user_id, AuditSource::Api, Stage::Input, all.decisions));
+    match blocked_by {
+        Some(rules) => Err(ApiError::bad_request(format!("The request was blocked by the moderation rules ({rules})"))),
+        None => Ok(()),
+    }
+}
+
+// The generated text as it may be returned, with its finish reason. Withheld text
+// finishes with "content_filter" like OpenAI's.
+async fn moderate_output(state: &AppState, user_id: i32, output: &GenerationOutput) -> (String, &'static str) {
+    let checked = moderate(state, user_id, Stage::Output, &output.text).await;
+    let blocked = checked.blocked();
+    tokio::spawn(record_decisions(user_id, AuditSource::Api, Stage::Output, checked.decisions));
+    if blocked {
+        (String::new(), "content_filter")
+    } else {
+        (checked.text, finish_reason(output.finish_reason))
+    }
+}
+
+pub async fn chat_completions(
+    Extension(state): Extension<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, ApiError> {
+    let user_id = authenticate(&headers).await?;
+    check_quota(&state, user_id).await?;
+    check_n(request.n)?;
+    request.response_format.constraint().map_err(|err| ApiError::bad_request(err.to_string()))?;
+    let mut messages = request.messages;
+    moderate_input(&state, user_id, input_texts(&mut messages)).await?;
+    let model_config = model_config(&state, request.model.as_deref())?;
+    let max_tokens = max_tokens(&model_config, request.max_tokens)?;
+    let template = state.config.template(&model_config.template).map_err(ApiError::internal)?;
+    let model = state.models.get(&model_config.id).await?;
+
+    let mut system = Vec::new();
+    let mut conversation = Conversation::new();
+    for message in messages {
+        match message.role.as_str() {
+            "system" => system.push(message.content),
+            "user" => conversation.messages.push(Message::user(message.content)),
+            "assistant" => conversation.messages.push(Message::llm(message.content)),
+            role => return Err(ApiError::bad_request(format!("Unsupported message role: {role}"))),
+        }
+    }
+    let system_prompt = if system.is_empty() { model_config.system_prompt.clone() } else { system.join("\n\n") };
+
+    // like the chat, drop the oldest messages rather than failing right away
+    let budget = model_config.context_size - max_tokens;
+    let fitted = fit_prompt(&template, Some(&system_prompt), &conversation, budget, |text| {
+        count_tokens(model.as_ref(), text)
+    });
+    if fitted.prompt_tokens > budget {
+        return Err(context_exceeded(&model_config));
+    }
+
+    let mut stop = template.stop_sequences.clone();
+    stop.extend(request.stop.map(OneOrMany::into_vec).unwrap_or_default());
+    let completion = Completion {
+        endpoint: Endpoint::Chat,
+        model_config,
+        prompt: fitted.prompt,
+        prompt_tokens: fitted.prompt_tokens,
+        stop,
+        max_tokens,
+        sampling: request.sampling,
+        format: request.response_format,
+        stream: request.stream,
+        include_usage: request.stream_options.unwrap_or_default().include_usage,
+    };
+    complete(state, user_id, model, completion).await
+}
+
+pub async fn completions(
+    Extension(state): Extension<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CompletionRequest>,
+) -> Result<Response, ApiError> {
+    let user_id = authenticate(&headers).await?;
+    check_quota(&state, user_id).await?;
+    check_n(request.n)?;
+    let mut prompts = request.prompt.into_vec();
+    if prompts.len() != 1 {
+        return Err(ApiError::bad_request("Exactly one prompt is supported"));
+    }
+    let mut prompt = prompts.remove(0);
+    moderate_input(&state, user_id, vec![&mut prompt]).await?;
+
+    let model_config = model_config(&state, request.model.as_deref())?;
+    let max_tokens = max_tokens(&model_config, request.max_tokens)?;
+    let model = state.models.get(&model_config.id).await?;
+
+    let prompt_tokens = count_tokens(model.as_ref(), &prompt);
+    if prompt_tokens + max_tokens > model_config.context_size {
+        return Err(context_exceeded(&model_config));
+    }
+
+    let completion = Completion {
+        endpoint: Endpoint::Text,
+        model_config,
+        prompt,
+        prompt_tokens,
+        stop: request.stop.map(OneOrMany::into_vec).unwrap_or_default(),
+        max_tokens,
+        sampling: request.sampling,
+        format: ResponseFormat::Text,
+        stream: request.stream,
+        include_usage: request.stream_options.unwrap_or_default().include_usage,
+    };
+    complete(state, user_id, model, completion).await
+}
+
+pub async fn models(
+    Extension(state): Extension<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    authenticate(&headers).await?;
+    let data: Vec<Value> = state
+        .config
+        .models
+        .iter()
+        .map(|model| json!({ "id": model.id, "object": "model", "created": 0, "owned_by": "jippity" }))
+        .collect();
+    Ok(Json(json!({ "object": "list", "data": data })))
+}
+
+// Without `model` the configured embedding model is used, the one behind the history search
+pub async fn embeddings(
+    Extension(state): Extension<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let user_id = authenticate(&headers).await?;
+    check_quota(&state, user_id).await?;
+    let model_config = match request.model.as_deref() {
+        Some(id) => find_model(&state, id)?,
+        None => state.config.embedding_model().clone(),
+    };
+
+    let mut data = Vec::new();
+    let mut tokens = 0;
+    for (index, input) in request.input.into_vec().into_iter().enumerate() {
+        let embedding = embed(&state, Some(user_id), &model_config, &input).await?;
+        tokens += embedding.tokens;
+        data.push(json!({ "object": "embedding", "index": index, "embedding": embedding.vector }));
+    }
+
+    Ok(Json(json!({
+        "object": "list",
+        "data": data,
+        "model": model_config.id,
+        "usage": { "prompt_tokens": tokens, "total_tokens": tokens },
+    })))
+}
+
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length => "length",
+        FinishReason::Stop | FinishReason::Cancelled => "stop",
+        FinishReason::ToolCall => "tool_calls",
+    }
+}
+
+fn usage(completion: &Completion, output: &GenerationOutput) -> Value {
+    json!({
+        "prompt_tokens": completion.prompt_tokens,
+        "completion_tokens": output.completion_tokens,
+        "total_tokens": completion.prompt_tokens + output.completion_tokens,
+    })
+}
+
+// one choice in the shape of the endpoint, `text` is a delta when streaming
+fn choice(endpoint: Endpoint, text: Option<&str>, finish: Option<&str>, streaming: bool) -> Value {
+    match (endpoint, streaming) {
+        (Endpoint::Chat, false) => json!({
+            "index": 0,
+            "message": { "role": "assistant", "content": text.unwrap_or_default() },
+            "finish_reason": finish,
+        }),
+        (Endpoint::Chat, true) => json!({
+            "index": 0,
+            "delta": text.map(|text| json!({ "content": text })).unwrap_or_else(|| json!({})),
+            "finish_reason": finish,
+        }),
+        (Endpoint::Text, _) => json!({
+            "index": 0,
+            "text": text.unwrap_or_default(),
+            "logprobs": null,
+            "finish_reason": finish,
+        }),
+    }
+}
+
+// With a JSON format, Length means the answer was cut off before its JSON was complete
+fn is_incomplete_json(format: &ResponseFormat, output: &GenerationOutput) -> bool {
+    *format != ResponseFormat::Text && output.finish_reason == FinishReason::Length
+}
+
+fn incomplete_json(max_tokens: usize) -> ApiError {
+    ApiError::bad_request(format!(
+        "The answer reached max_tokens ({max_tokens}) before its JSON was complete, raise max_tokens"
+    ))
+}
+
+async fn complete(
+    state: AppState,
+    user_id: i32,
+    model: Arc<dyn llm::Model>,
+    completion: Completion,
+) -> Result<Response, ApiError> {
+    let generation = Arc::new(state.generations.start(user_id));
+    let (id, object) = match completion.endpoint {
+        Endpoint::Chat if completion.stream => (format!("chatcmpl-{:x}", generation.id), "chat.completion.chunk"),
+        Endpoint::Chat => (format!("chatcmpl-{:x}", generation.id), "chat.completion"),
+        Endpoint::Text => (format!("cmpl-{:x}", generation.id), "text_completion"),
+    };
+    let created = unix_now();
+
+    let (tx, mut rx) = mpsc::channel(16);
+    // text the output rules may block or redact is only sent once it is checked
+    let hold = state.moderation.holds_output();
+    let events = if hold { hold_tokens(tx) } else { tx };
+    let job = GenerationJob {
+        user_id,
+        model_id: completion.model_config.id.clone(),
+        model,
+        prompt: completion.prompt.clone(),
+        stop_sequences: completion.stop.clone(),
+        max_tokens: completion.max_tokens,
+        sampling: completion.sampling.clone(),
+        session_key: None,
+        session_bytes: completion.model_config.session_bytes(),
+        session_config: completion.model_config.session_config(),
+        tool_calls: false,
+        format: completion.format.clone(),
+        cache: CacheMode::Read,
+    };
+    let generation_state = state.clone();
+    let handle: JoinHandle<Result<GenerationOutput, GenerationError>> =
+        tokio::spawn(async move { run_generation(&generation_state, job, &generation, events).await });
+
+    let envelope = move |choices: Value| {
+        json!({
+            "id": id,
+            "object": object,
+            "created": created,
+            "model": completion.model_config.id,
+            "choices": [choices],
+        })
+    };
+
+    if !completion.stream {
+        // the tokens have to be drained, a dropped receiver stops the generation
+        while rx.recv().await.is_some() {}
+        let output = handle.await.map_err(ApiError::internal)??;
+        if is_incomplete_json(&completion.format, &output) {
+            return Err(incomplete_json(completion.max_tokens));
+        }
+        let (text, finish) = moderate_output(&state, user_id, &output).await;
+        let mut body = envelope(choice(completion.endpoint, Some(&text), Some(finish), false));
+        body["usage"] = usage(&completion, &output);
+        return Ok(Json(body).into_response());
+    }
+
+    // Server sent events, `data: {chunk}` per token and `data: [DONE]` at the end.
+    // A closed connection drops `events`, which drops `rx` and halts the generation.
+    let (events_tx, events_rx) = mpsc::channel::<Event>(16);
+    tokio::spawn(async move {
+        let send = |value: Value| Event::default().data(value.to_string());
+        if completion.endpoint == Endpoint::Chat {
+            let role = json!({ "index": 0, "delta": { "role": "assistant", "content": "" }, "finish_reason": null });
+            if events_tx.send(send(envelope(role))).await.is_err() {
+                return;
+            }
+        }
+
+        while let Some(event) = rx.recv().await {
+            if let ChatEvent::Token(text) = event {
+                let chunk = envelope(choice(completion.endpoint, Some(&text), None, true));
+                if events_tx.send(send(chunk)).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let last = match handle.await {
+            Ok(Ok(output)) if is_incomplete_json(&completion.format, &output) => {
+                let err = incomplete_json(completion.max_tokens);
+                json!({ "error": { "message": err.message, "type": err.kind, "code": null } })
+            }
+            Ok(Ok(output)) => {
+                let (text, finish) = moderate_output(&state, user_id, &output).await;
+                if hold && !text.is_empty() {
+                    let chunk = envelope(choice(completion.endpoint, Some(&text), None, true));
+                    if events_tx.send(send(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                let mut chunk = envelope(choice(completion.endpoint, None, Some(finish), true));
+                if completion.include_usage {
+                    chunk["usage"] = usage(&completion, &output);
+                }
+                chunk
+            }
+            Ok(Err(err)) => {
+                let err = ApiError::from(err);
+                json!({ "error": { "message": err.message, "type": err.kind, "code": null } })
+            }
+            Err(err) => json!({ "error": { "message": err.to_string(), "type": "server_error", "code": null } }),
+        };
+        let _ = events_tx.send(send(last)).await;
+        let _ = events_tx.send(Event::default().data("[DONE]")).await;
+    });
+
+    let events = futures::stream::unfold(events_rx, |mut events_rx| async move {
+        events_rx.recv().await.map(|event| (Ok::<_, Infallible>(event), events_rx))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::config::{BlocklistConfig, ModerationConfig};
+    use crate::inference::moderation::Moderator;
+
+    #[test]
+    fn earlier_answers_are_moderated_as_input() {
+        let moderator = Moderator::new(&ModerationConfig {
+            enabled: true,
+            blocklist: BlocklistConfig { words: vec!["secret plan".to_string()], ..Default::default() },
+            ..Default::default()
+        })
+        .unwrap();
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "messages": [
+                {"role": "user", "content": "Go on"},
+                {"role": "assistant", "content": "Sure, the secret plan is"},
+            ]
+        }))
+        .unwrap();
+        let mut messages = request.messages;
+        let blocked = input_texts(&mut messages).into_iter().any(|text| moderator.check(Stage::Input, text).blocked());
+        assert!(blocked);
+    }
+}
@@ -0,0 +1,251 @@
+// Reads ChatGPT's data export (conversations.json). Each conversation is a `mapping` of
+// node id to node, a node holds an optional message and links to its parent and children,
+// `current_node` is the end of the branch that was shown last.
+// Messages Jippity has no equivalent for (system prompts, tool calls and their output,
+// images) are skipped and reported; their replies move up to the nearest kept message.
+use crate::components::archive::{ExportedConversation, ExportedMessage, Role, SkippedMessage};
+use chrono::{DateTime, SecondsFormat};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ChatgptError {
+    #[error("Not a ChatGPT conversations.json: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Deserialize)]
+struct ChatgptConversation {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    create_time: Option<f64>,
+    #[serde(default)]
+    mapping: HashMap<String, Node>,
+    #[serde(default)]
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    #[serde(default)]
+    message: Option<ChatgptMessage>,
+    #[serde(default)]
+    parent: Option<String>,
+    #[serde(default)]
+    children: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatgptMessage {
+    author: Author,
+    #[serde(default)]
+    create_time: Option<f64>,
+    #[serde(default)]
+    content: Option<Content>,
+    // "all" for messages to the user, a tool name for calls
+    #[serde(default)]
+    recipient: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    role: String,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    content_type: String,
+    // strings for text, objects for images and files
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+// The conversations that kept at least one message, and what was left out
+pub fn parse_export(json: &str) -> Result<(Vec<ExportedConversation>, Vec<SkippedMessage>), ChatgptError> {
+    let conversations: Vec<ChatgptConversation> = serde_json::from_str(json)?;
+    let mut skipped = Vec::new();
+    let converted = conversations
+        .into_iter()
+        .filter_map(|conversation| convert(conversation, &mut skipped))
+        .collect();
+    Ok((converted, skipped))
+}
+
+fn timestamp(seconds: Option<f64>) -> Option<String> {
+    let seconds = seconds?;
+    let nanos = (seconds.fract() * 1e9) as u32;
+    DateTime::from_timestamp(seconds.trunc() as i64, nanos).map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+// The text of a message, or why it is skipped
+fn message_text(message: &ChatgptMessage) -> Result<(Role, String), String> {
+    let role = match message.author.role.as_str() {
+        "user" => Role::User,
+        "assistant" => Role::Assistant,
+        "system" => return Err("system message".to_string()),
+        "tool" => {
+            let name = message.author.name.as_deref().unwrap_or("unknown");
+            return Err(format!("output of the {name} tool"));
+        }
+        other => return Err(format!("message from {other}")),
+    };
+    if let Some(recipient) = message.recipient.as_deref().filter(|recipient| *recipient != "all") {
+        return Err(format!("call of the {recipient} tool"));
+    }
+    let Some(content) = &message.content else {
+        return Err("no content".to_string());
+    };
+    if !matches!(content.content_type.as_str(), "text" | "multimodal_text") {
+        return Err(format!("{} content", content.content_type));
+    }
+    let parts: Vec<&str> = content.parts.iter().filter_map(Value::as_str).collect();
+    let text = parts.join("\n").trim().to_string();
+    if text.is_empty() {
+        let attachments = parts.len() < content.parts.len();
+        return Err(if attachments { "only attachments" } else { "empty" }.to_string());
+    }
+    Ok((role, text))
+}
+
+fn convert(conversation: ChatgptConversation, skipped: &mut Vec<SkippedMessage>) -> Option<ExportedConversation> {
+    let title = conversation.title.clone().unwrap_or_default();
+    let mapping = &conversation.mapping;
+    let mut roots: Vec<&String> = mapping
+        .iter()
+        .filter(|(_, node)| node.parent.as_ref().is_none_or(|parent| !mapping.contains_key(parent)))
+        .map(|(id, _)| id)
+        .collect();
+    roots.sort();
+
+    // depth first, so a parent always comes before its replies and siblings keep their order
+    let mut messages = Vec::new();
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut stack: Vec<(&String, Option<usize>)> = roots.into_iter().rev().map(|root| (root, None)).collect();
+    while let Some((id, parent)) = stack.pop() {
+        // a malformed export may link a node twice
+        if !visited.insert(id) {
+            continue;
+        }
+        let Some(node) = mapping.get(id) else {
+            continue;
+        };
+        let mut position = parent;
+        if let Some(message) = &node.message {
+            match message_text(message) {
+                Ok((role, text)) => {
+                    position = Some(messages.len());
+                    positions.insert(id.as_str(), messages.len());
+                    messages.push(ExportedMessage {
+                        parent,
+                        role,
+                        text,
+                        created_at: timestamp(message.create_time),
+                        tool: None,
+                        sampling: None,
+                    });
+                }
+                Err(reason) => skipped.push(SkippedMessage {
+                    conversation: title.clone(),
+                    role: message.author.role.clone(),
+                    reason,
+                }),
+            }
+        }
+        stack.extend(node.children.iter().rev().map(|child| (child, position)));
+    }
+    if messages.is_empty() {
+        return None;
+    }
+
+    // the branch shown last stays active, up to its last kept message
+    let mut leaf = None;
+    let mut next = conversation.current_node.as_deref();
+    let mut steps = 0;
+    while let Some(id) = next.filter(|_| steps <= mapping.len()) {
+        if let Some(&position) = positions.get(id) {
+            leaf = Some(position);
+            break;
+        }
+        next = mapping.get(id).and_then(|node| node.parent.as_deref());
+        steps += 1;
+    }
+
+    Some(ExportedConversation {
+        title,
+        model: None,
+        created_at: timestamp(conversation.create_time),
+        leaf,
+        messages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a hidden system message, a question answered twice (the first answer is active)
+    // and a code interpreter call in between the second answer
+    const EXPORT: &str = r#"[{
+        "title": "Rust help",
+        "create_time": 1700000000.5,
+        "current_node": "a1",
+        "mapping": {
+            "root": {"id": "root", "message": null, "parent": null, "children": ["sys"]},
+            "sys": {"message": {"author": {"role": "system"}, "content": {"content_type": "text", "parts": [""]}},
+                    "parent": "root", "children": ["q"]},
+            "q": {"message": {"author": {"role": "user"}, "create_time": 1700000001,
+                              "content": {"content_type": "text", "parts": ["What is a trait?"]}, "recipient": "all"},
+                  "parent": "sys", "children": ["a1", "call"]},
+            "a1": {"message": {"author": {"role": "assistant"}, "create_time": 1700000002,
+                               "content": {"content_type": "text", "parts": ["An interface."]}, "recipient": "all"},
+                   "parent": "q", "children": []},
+            "call": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "code", "text": "1+1"},
+                                 "recipient": "python"},
+                     "parent": "q", "children": ["out"]},
+            "out": {"message": {"author": {"role": "tool", "name": "python"},
+                                "content": {"content_type": "execution_output", "text": "2"}},
+                    "parent": "call", "children": ["a2"]},
+            "a2": {"message": {"author": {"role": "assistant"},
+                               "content": {"content_type": "text", "parts": ["A set of methods."]}, "recipient": "all"},
+                   "parent": "out", "children": []}
+        }
+    }, {"title": "Empty", "mapping": {"root": {"message": null, "parent": null, "children": []}}}]"#;
+
+    #[test]
+    fn the_message_tree_is_kept() {
+        let (conversations, _) = parse_export(EXPORT).unwrap();
+        assert_eq!(conversations.len(), 1);
+        let conversation = &conversations[0];
+        assert_eq!(conversation.title, "Rust help");
+        assert_eq!(conversation.created_at.as_deref(), Some("2023-11-14T22:13:20Z"));
+
+        let texts: Vec<_> = conversation.messages.iter().map(|message| message.text.as_str()).collect();
+        assert_eq!(texts, ["What is a trait?", "An interface.", "A set of methods."]);
+        assert_eq!(conversation.messages[0].role, Role::User);
+        assert_eq!(conversation.messages[0].parent, None);
+        assert_eq!(conversation.messages[0].created_at.as_deref(), Some("2023-11-14T22:13:21Z"));
+        // both answers reply to the question, the skipped call in between is gone
+        assert_eq!(conversation.messages[1].parent, Some(0));
+        assert_eq!(conversation.messages[2].parent, Some(0));
+        assert_eq!(conversation.leaf, Some(1));
+    }
+
+    #[test]
+    fn skipped_messages_are_reported() {
+        let (_, skipped) = parse_export(EXPORT).unwrap();
+        let reasons: Vec<_> = skipped.iter().map(|skipped| skipped.reason.as_str()).collect();
+        assert_eq!(reasons, ["system message", "call of the python tool", "output of the python tool"]);
+        assert!(skipped.iter().all(|skipped| skipped.conversation == "Rust help"));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(parse_export(r#"{"version": 1, "conversations": []}"#).is_err());
+    }
+}
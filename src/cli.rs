@@ -0,0 +1,45 @@
+// Admin commands, run by the server binary instead of serving, e.g.
+//   leptos-axum-proj import-chatgpt <username> <conversations.json>
+// They use the same database as the server.
+use crate::app::ssr::create_db_conn;
+use crate::history::import_conversations;
+use std::error::Error;
+
+const USAGE: &str = "usage: leptos-axum-proj [import-chatgpt <username> <conversations.json>]";
+
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args {
+        [command, username, path] if command == "import-chatgpt" => import_chatgpt(username, path).await,
+        _ => Err(USAGE.into()),
+    }
+}
+
+// Unlike an upload on the Archive page, the file may be of any size; the imported
+// messages are not embedded, so "Search my chats" doesn't find them
+async fn import_chatgpt(username: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let pool = create_db_conn().await?;
+    let user: Option<(i32,)> = sqlx::query_as("SELECT id FROM user_table WHERE username = $1")
+        .bind(username)
+        .fetch_optional(&pool)
+        .await?;
+    let Some((user_id,)) = user else {
+        return Err(format!("Unknown user: {username}").into());
+    };
+
+    let json = std::fs::read_to_string(path)?;
+    let (conversations, skipped) = crate::chatgpt::parse_export(&json)?;
+    // the default model of this instance answers in every imported conversation
+    let imports: Vec<_> = conversations.iter().map(|conversation| (conversation, None)).collect();
+    let ids = import_conversations(user_id, &imports).await?;
+
+    for skipped in &skipped {
+        println!("skipped in {:?}: {} ({})", skipped.conversation, skipped.role, skipped.reason);
+    }
+    println!(
+        "Imported {} conversations with {} messages for {username}, skipped {} messages",
+        ids.len(),
+        ids.iter().map(Vec::len).sum::<usize>(),
+        skipped.len(),
+    );
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use leptos::*;
+use leptos_router::ActionForm;
+use crate::components::archive::ExportFile;
+use crate::components::jippity::list_models;
+use crate::components::nav::Nav;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use crate::app::ssr::{require_admin, AppState};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub postgres: bool,
+    pub ttl_secs: u64,
+    // answers in the memory tier of this server
+    pub memory_entries: usize,
+}
+
+// A message one of the moderation rules flagged for review
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlaggedMessage {
+    // of the audit record
+    pub id: i64,
+    pub username: String,
+    // chat or api
+    pub origin: String,
+    pub conversation_id: Option<i64>,
+    // input for the user's message, output for an answer
+    pub stage: String,
+    pub rule: String,
+    // None for an API request and once the message was deleted
+    pub text: Option<String>,
+    pub created_at: String,
+}
+
+const FLAGGED_SHOWN: i64 = 50;
+
+#[server(GetCacheStats, "/admin")]
+pub async fn get_cache_stats() -> Result<CacheStats, ServerFnError> {
+    use axum::Extension;
+
+    require_admin().await?;
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let config = &state.config.cache;
+    Ok(CacheStats {
+        enabled: config.enabled,
+        postgres: config.postgres,
+        ttl_secs: config.ttl_secs,
+        memory_entries: state.cache.memory_entries(),
+    })
+}
+
+// An empty model clears the answers of every model
+#[server(ClearResponseCache, "/admin")]
+pub async fn clear_response_cache(model: String) -> Result<u64, ServerFnError> {
+    use axum::Extension;
+
+    require_admin().await?;
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let model = Some(model.trim()).filter(|model| !model.is_empty());
+    state.cache.clear(model).await
+}
+
+// Rated answers as JSONL for evaluation and fine-tuning. Empty fields don't filter,
+// the dates (YYYY-MM-DD) are both included.
+#[server(ExportFeedback, "/admin")]
+pub async fn export_feedback(model: String, from: String, to: String) -> Result<ExportFile, ServerFnError> {
+    use crate::feedback::{dataset, parse_day, rated_answers, to_jsonl, DatasetFilter};
+    use axum::Extension;
+
+    require_admin().await?;
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let model = Some(model.trim().to_string()).filter(|model| !model.is_empty());
+    let day = |field: &str, value: &str| parse_day(field, value).map_err(ServerFnError::ServerError);
+    let filter = DatasetFilter { model, from: day("from", &from)?, to: day("to", &to)? };
+    if filter.from.zip(filter.to).is_some_and(|(from, to)| from > to) {
+        return Err(ServerFnError::ServerError("The from date is after the to date".to_string()));
+    }
+    let answers = rated_answers(&filter, &state.config.default_model().id).await?;
+    Ok(ExportFile {
+        name: "jippity-feedback.jsonl".to_string(),
+        mime: "application/x-ndjson".to_string(),
+        content: to_jsonl(&dataset(answers))?,
+    })
+}
+
+// The oldest flags nobody reviewed yet
+#[server(ListFlaggedMessages, "/admin")]
+pub async fn list_flagged_messages() -> Result<Vec<FlaggedMessage>, ServerFnError> {
+    require_admin().await?;
+    crate::moderation::unreviewed_flags(FLAGGED_SHOWN).await
+}
+
+#[server(MarkReviewed, "/admin")]
+pub async fn mark_reviewed(id: i64) -> Result<(), ServerFnError> {
+    require_admin().await?;
+    crate::moderation::mark_reviewed(id).await
+}
+
+#[component]
+pub fn Admin() -> impl IntoView {
+    let clear = create_server_action::<ClearResponseCache>();
+    let export = create_server_action::<ExportFeedback>();
+    let review = create_server_action::<MarkReviewed>();
+    let flagged = create_resource(move || review.version().get(), |_| list_flagged_messages());
+    let stats = create_resource(move || clear.version().get(), |_| get_cache_stats());
+    let models = create_resource(|| (), |_| list_models());
+
+    view! {
+        <Nav />
+        <h2>"Admin"</h2>
+        <h3>"Response cache"</h3>
+        <p>"Answers to requests with temperature 0 or a fixed seed are cached, a repeated request doesn't run the model again."</p>
+        <Transition fallback=move || view! { <p>"Loading..."</p> }>
+            {move || stats.get().map(|stats| match stats {
+                Ok(stats) => view! {
+                    <ul>
+                        <li>{if stats.enabled { "Enabled" } else { "Disabled" }}</li>
+                        <li>{format!("{} answers in memory", stats.memory_entries)}</li>
+                        <li>{if stats.postgres { "Also stored in Postgres" } else { "Memory only" }}</li>
+                        <li>{format!("Answers expire after {} seconds", stats.ttl_secs)}</li>
+                    </ul>
+                    <ActionForm action=clear>
+                        <label for="model"><b>"Model"</b></label>
+                        <select id="model" name="model">
+                            <option value="">"All models"</option>
+                            {move || models.get().and_then(Result::ok).unwrap_or_default().into_iter().map(|model| view! {
+                                <option value=model.id.clone()>{model.id.clone()}</option>
+                            }).collect_view()}
+                        </select>
+                        <button type="submit">"Clear cache"</button>
+                    </ActionForm>
+                }.into_view(),
+                Err(err) => view! { <p>{err.to_string()}</p> }.into_view(),
+            })}
+        </Transition>
+        {move || match clear.value().get() {
+            Some(Ok(dropped)) => view! { <p>{format!("Dropped {dropped} cached answers")}</p> }.into_view(),
+            Some(Err(err)) => view! { <p>{err.to_string()}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+
+        <h3>"Feedback dataset"</h3>
+        <p>"Rated answers as JSONL: the conversation up to the question as prompt, a thumbs up answer as chosen and a thumbs down answer to the same question as rejected."</p>
+        <ActionForm action=export>
+            <label for="feedback-model"><b>"Model"</b></label>
+            <select id="feedback-model" name="model">
+                <option value="">"All models"</option>
+                {move || models.get().and_then(Result::ok).unwrap_or_default().into_iter().map(|model| view! {
+                    <option value=model.id.clone()>{model.id.clone()}</option>
+                }).collect_view()}
+            </select>
+            <label for="from"><b>"Rated from"</b></label>
+            <input type="date" id="from" name="from"/>
+            <label for="to"><b>"to"</b></label>
+            <input type="date" id="to" name="to"/>
+            <button type="submit">"Export JSONL"</button>
+        </ActionForm>
+        {move || match export.value().get() {
+            Some(Ok(file)) => view! {
+                <p><a href=file.href() download=file.name.clone()>"Download "{file.name.clone()}</a></p>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{err.to_string()}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+
+        <h3>"Flagged for review"</h3>
+        <p>"Messages and answers a moderation rule flagged, oldest first. Every decision is kept in the moderation_audit table."</p>
+        <Transition fallback=move || view! { <p>"Loading..."</p> }>
+            {move || flagged.get().map(|flagged| match flagged {
+                Ok(flagged) if flagged.is_empty() => view! { <p>"Nothing to review."</p> }.into_view(),
+                Ok(flagged) => view! {
+                    <ul>
+                        {flagged.into_iter().map(|flag| {
+                            let id = flag.id;
+                            let conversation = match flag.conversation_id {
+                                _ if flag.origin == "api" => "API request".to_string(),
+                                Some(id) => format!("conversation {id}"),
+                                None => "no conversation".to_string(),
+                            };
+                            let text = flag.text.unwrap_or_else(|| "(not stored)".to_string());
+                            view! {
+                                <li>
+                                    <b>{format!("{} ({}, {})", flag.rule, flag.stage, flag.created_at)}</b>
+                                    {format!(" {}, {conversation}: ", flag.username)}
+                                    <span class="whitespace-pre-wrap">{text}</span>" "
+                                    <button on:click=move |_| review.dispatch(MarkReviewed { id })>"Reviewed"</button>
+                                </li>
+                            }
+                        }).collect_view()}
+                    </ul>
+                }.into_view(),
+                Err(err) => view! { <p>{err.to_string()}</p> }.into_view(),
+            })}
+        </Transition>
+    }
+}
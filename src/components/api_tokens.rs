@@ -0,0 +1,126 @@
+use leptos::*;
+use leptos_router::*;
+use crate::components::nav::Nav;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use crate::app::ssr::{create_db_conn, new_session_token, require_user};
+
+// Tokens for the OpenAI compatible API under /v1, sent as `Authorization: Bearer <token>`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    // only the first characters, the full token is shown once after creating it
+    pub prefix: String,
+}
+
+// Tokens are looked up by their SHA-256, the table doesn't keep them
+#[cfg(feature = "ssr")]
+pub fn token_hash(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[server(ListApiTokens, "/tokens")]
+pub async fn list_api_tokens() -> Result<Vec<ApiToken>, ServerFnError> {
+    let user = require_user().await?;
+    let pool = create_db_conn().await?;
+
+    let rows: Vec<(i32, String, String)> = sqlx::query_as(
+        "SELECT id, name, prefix FROM api_token WHERE user_id = $1 ORDER BY id"
+    )
+    .bind(user.id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|(id, name, prefix)| ApiToken { id, name, prefix }).collect())
+}
+
+#[server(CreateApiToken, "/tokens")]
+pub async fn create_api_token(name: String) -> Result<String, ServerFnError> {
+    let user = require_user().await?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ServerFnError::ServerError("The token needs a name".to_string()));
+    }
+
+    let pool = create_db_conn().await?;
+    let token = new_session_token();
+    let prefix: String = token.chars().take(8).collect();
+    sqlx::query("INSERT INTO api_token (token_hash, prefix, user_id, name) VALUES ($1, $2, $3, $4)")
+        .bind(token_hash(&token))
+        .bind(&prefix)
+        .bind(user.id)
+        .bind(name)
+        .execute(&pool)
+        .await?;
+    Ok(token)
+}
+
+#[server(RevokeApiToken, "/tokens")]
+pub async fn revoke_api_token(id: i32) -> Result<(), ServerFnError> {
+    let user = require_user().await?;
+    let pool = create_db_conn().await?;
+    sqlx::query("DELETE FROM api_token WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user.id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+#[component]
+pub fn ApiTokens() -> impl IntoView {
+    let create_action = create_server_action::<CreateApiToken>();
+    let revoke_action = create_server_action::<RevokeApiToken>();
+    let tokens = create_resource(
+        move || (create_action.version().get(), revoke_action.version().get()),
+        |_| list_api_tokens(),
+    );
+
+    view! {
+        <Nav />
+        <h2>"API tokens"</h2>
+        <p>"Use a token with any OpenAI compatible client, the base URL is "<code>"/v1"</code>"."</p>
+        <ActionForm action=create_action>
+            <label for="name"><b>"Name"</b></label>
+            <input type="text" placeholder="e.g. my editor" id="name" name="name" required/>
+            <button type="submit">"Create token"</button>
+        </ActionForm>
+        {move || match create_action.value().get() {
+            Some(Ok(token)) => view! {
+                <p>"New token, copy it now, it won't be shown again: "<code>{token}</code></p>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{err.to_string()}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+        <Transition fallback=move || view! { <p>"Loading..."</p> }>
+            {move || tokens.get().map(|tokens| match tokens {
+                Ok(tokens) => view! {
+                    <ul>
+                        {tokens.into_iter().map(|token| view! {
+                            <li>
+                                {token.name}" ("<code>{token.prefix}"..."</code>") "
+                                <button on:click=move |_| revoke_action.dispatch(RevokeApiToken { id: token.id })>
+                                    "Revoke"
+                                </button>
+                            </li>
+                        }).collect_view()}
+                    </ul>
+                }.into_view(),
+                Err(err) => view! { <p>{err.to_string()}</p> }.into_view(),
+            })}
+        </Transition>
+    }
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_like_postgres_sha256() {
+        // SELECT encode(sha256('abc'), 'hex')
+        assert_eq!(token_hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -0,0 +1,558 @@
+use leptos::*;
+use leptos::ev::SubmitEvent;
+use leptos::server_fn::codec::{MultipartData, MultipartFormData};
+use crate::components::jippity::ToolInvocation;
+use crate::components::nav::Nav;
+use crate::components::share::{ShareConversation, ShareLinks};
+use crate::inference::sampling::SamplingSettings;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+#[cfg(feature = "ssr")]
+use crate::app::ssr::{require_user, AppState};
+
+// Bumped whenever the JSON layout changes, imports only accept the current one
+pub const EXPORT_VERSION: u64 = 1;
+
+// A JSON export of one or all conversations of a user, the whole message tree of each
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConversationExport {
+    pub version: u64,
+    #[serde(default)]
+    pub exported_at: Option<String>,
+    pub conversations: Vec<ExportedConversation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedConversation {
+    pub title: String,
+    // None means the default model of the instance
+    #[serde(default)]
+    pub model: Option<String>,
+    // RFC 3339, the import time if missing
+    #[serde(default)]
+    pub created_at: Option<String>,
+    // position of the end of the active path in `messages`, the last message if missing
+    #[serde(default)]
+    pub leaf: Option<usize>,
+    // oldest first, so a parent always comes before its replies
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedMessage {
+    // position of the message this one replies to, None for a first message
+    #[serde(default)]
+    pub parent: Option<usize>,
+    pub role: Role,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<ToolInvocation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<SamplingSettings>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+    // a tool Jippity called, with its arguments and result
+    Tool,
+}
+
+impl Role {
+    pub fn new(from_llm: bool, tool: bool) -> Role {
+        match (from_llm, tool) {
+            (_, true) => Role::Tool,
+            (true, false) => Role::Assistant,
+            (false, false) => Role::User,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("Not a Jippity export: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Not a Jippity export, the version is missing")]
+    NoVersion,
+    #[error("Export version {0} is not supported, this instance reads version {EXPORT_VERSION}")]
+    UnsupportedVersion(u64),
+    #[error("Conversation {conversation}: {reason}")]
+    Invalid { conversation: usize, reason: String },
+}
+
+impl ConversationExport {
+    pub fn new(exported_at: Option<String>, conversations: Vec<ExportedConversation>) -> ConversationExport {
+        ConversationExport { version: EXPORT_VERSION, exported_at, conversations }
+    }
+
+    // The version is checked before the rest, a newer layout may not parse at all
+    pub fn parse(json: &str) -> Result<ConversationExport, ImportError> {
+        let value: Value = serde_json::from_str(json)?;
+        match value.get("version").and_then(Value::as_u64) {
+            Some(EXPORT_VERSION) => {}
+            Some(version) => return Err(ImportError::UnsupportedVersion(version)),
+            None => return Err(ImportError::NoVersion),
+        }
+        let export: ConversationExport = serde_json::from_value(value)?;
+        for (index, conversation) in export.conversations.iter().enumerate() {
+            conversation
+                .validate()
+                .map_err(|reason| ImportError::Invalid { conversation: index + 1, reason })?;
+        }
+        Ok(export)
+    }
+
+    // The active path of every conversation, for reading rather than importing
+    pub fn to_markdown(&self) -> String {
+        self.conversations.iter().map(ExportedConversation::to_markdown).collect::<Vec<_>>().join("\n---\n\n")
+    }
+}
+
+// The import stores the times as TIMESTAMPTZ, a missing one means the import time
+fn is_timestamp(created_at: &Option<String>) -> bool {
+    created_at.as_deref().map_or(true, |created_at| chrono::DateTime::parse_from_rfc3339(created_at).is_ok())
+}
+
+impl ExportedConversation {
+    fn validate(&self) -> Result<(), String> {
+        if self.messages.is_empty() {
+            return Err("has no messages".to_string());
+        }
+        if !is_timestamp(&self.created_at) {
+            return Err("its creation time isn't an RFC 3339 timestamp".to_string());
+        }
+        for (index, message) in self.messages.iter().enumerate() {
+            let number = index + 1;
+            if !is_timestamp(&message.created_at) {
+                return Err(format!("message {number} has a creation time that isn't an RFC 3339 timestamp"));
+            }
+            if message.parent.is_some_and(|parent| parent >= index) {
+                return Err(format!("message {number} replies to a message that doesn't come before it"));
+            }
+            if (message.role == Role::Tool) != message.tool.is_some() {
+                return Err(format!("message {number} needs a tool exactly if its role is tool"));
+            }
+        }
+        if self.leaf.is_some_and(|leaf| leaf >= self.messages.len()) {
+            return Err("the active path ends at a message that doesn't exist".to_string());
+        }
+        Ok(())
+    }
+
+    // Positions of the messages from the first one down to the leaf
+    pub fn active_path(&self) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut next = self.leaf.or(self.messages.len().checked_sub(1));
+        while let Some(index) = next.filter(|&index| index < self.messages.len()) {
+            path.push(index);
+            // parents come first, anything else would loop
+            next = self.messages[index].parent.filter(|&parent| parent < index);
+        }
+        path.reverse();
+        path
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n\n", self.title);
+        let model = self.model.as_deref().unwrap_or("default model");
+        match &self.created_at {
+            Some(created_at) => markdown.push_str(&format!("*{model}, started {created_at}*\n\n")),
+            None => markdown.push_str(&format!("*{model}*\n\n")),
+        }
+        for message in self.active_path().into_iter().map(|index| &self.messages[index]) {
+            let who = match (&message.role, &message.tool) {
+                (Role::Tool, Some(tool)) => format!("Tool `{}`", tool.name),
+                (Role::Assistant, _) | (Role::Tool, None) => "Jippity".to_string(),
+                (Role::User, _) => "You".to_string(),
+            };
+            match &message.created_at {
+                Some(created_at) => markdown.push_str(&format!("## {who} ({created_at})\n\n")),
+                None => markdown.push_str(&format!("## {who}\n\n")),
+            }
+            if let Some(tool) = &message.tool {
+                let (arguments, result) = (fenced(&tool.arguments), fenced(&tool.result));
+                markdown.push_str(&format!("Arguments:\n\n{arguments}\n\nResult:\n\n{result}\n\n"));
+            }
+            if !message.text.is_empty() {
+                markdown.push_str(message.text.trim_end());
+                markdown.push_str("\n\n");
+            }
+            if let Some(seed) = message.sampling.as_ref().and_then(|sampling| sampling.seed) {
+                markdown.push_str(&format!("*seed {seed}*\n\n"));
+            }
+        }
+        markdown
+    }
+}
+
+// A code block whose fence is longer than any backtick run inside `text`
+fn fenced(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}\n{}\n{fence}", text.trim_end())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportFile {
+    pub name: String,
+    pub mime: String,
+    pub content: String,
+}
+
+impl ExportFile {
+    // a data: URL, so the browser downloads the file without another request
+    pub fn href(&self) -> String {
+        let mut href = format!("data:{};charset=utf-8,", self.mime);
+        for byte in self.content.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => href.push(byte as char),
+                _ => href.push_str(&format!("%{byte:02X}")),
+            }
+        }
+        href
+    }
+}
+
+// A message of another tool's export that has no equivalent in Jippity
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedMessage {
+    pub conversation: String,
+    pub role: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub conversations: usize,
+    pub messages: usize,
+    pub skipped: Vec<SkippedMessage>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub id: i64,
+    pub title: String,
+    pub created_at: String,
+    pub messages: usize,
+}
+
+#[server(ListConversations, "/archive")]
+pub async fn list_conversations() -> Result<Vec<ConversationSummary>, ServerFnError> {
+    let user = require_user().await?;
+    crate::history::list_conversations(user.id).await
+}
+
+// One conversation, or all of the user's with None
+#[server(ExportConversations, "/archive")]
+pub async fn export_conversations(conversation: Option<i64>, format: ExportFormat) -> Result<ExportFile, ServerFnError> {
+    let user = require_user().await?;
+    let conversations = crate::history::export_conversations(user.id, conversation).await?;
+    if conversation.is_some() && conversations.is_empty() {
+        return Err(ServerFnError::ServerError("Conversation not found".to_string()));
+    }
+    let export = ConversationExport::new(Some(chrono::Utc::now().to_rfc3339()), conversations);
+    let name = match conversation {
+        Some(id) => format!("jippity-conversation-{id}"),
+        None => "jippity-conversations".to_string(),
+    };
+    Ok(match format {
+        ExportFormat::Json => ExportFile {
+            name: format!("{name}.json"),
+            mime: "application/json".to_string(),
+            content: serde_json::to_string_pretty(&export)?,
+        },
+        ExportFormat::Markdown => ExportFile {
+            name: format!("{name}.md"),
+            mime: "text/markdown".to_string(),
+            content: export.to_markdown(),
+        },
+    })
+}
+
+// The `file` field of an upload as text
+#[cfg(feature = "ssr")]
+async fn read_file(data: MultipartData) -> Result<String, ServerFnError> {
+    let mut data = data.into_inner().expect("multipart data on the server");
+    let mut file = None;
+    while let Some(field) = data.next_field().await? {
+        if field.name() == Some("file") {
+            file = Some(field.bytes().await?);
+        }
+    }
+    let Some(bytes) = file else {
+        return Err(ServerFnError::ServerError("Pick an export file".to_string()));
+    };
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+// Stores the conversations under the user and embeds their messages in the background,
+// one after the other. Returns the number of stored messages.
+// The embeddings are not billed: they run as background queue jobs (see index_message),
+// so a large import neither uses up the user's quota nor makes their chat answers
+// wait behind it.
+#[cfg(feature = "ssr")]
+async fn store_imports(
+    state: AppState,
+    user_id: i32,
+    conversations: &[ExportedConversation],
+) -> Result<usize, ServerFnError> {
+    use crate::history::import_conversations;
+    use crate::inference::embeddings::index_message;
+
+    // a model this instance doesn't serve becomes the default one
+    let imports: Vec<_> = conversations
+        .iter()
+        .map(|conversation| {
+            let served = |id: &&str| state.config.models.iter().any(|spec| spec.id == *id);
+            (conversation, conversation.model.as_deref().filter(served))
+        })
+        .collect();
+    let ids = import_conversations(user_id, &imports).await?;
+
+    let texts: Vec<(i64, String)> = ids
+        .iter()
+        .flatten()
+        .zip(conversations.iter().flat_map(|conversation| &conversation.messages))
+        .filter(|(_, message)| message.tool.is_none())
+        .map(|(id, message)| (*id, message.text.clone()))
+        .collect();
+    let count = texts.len();
+    tokio::spawn(async move {
+        for (id, text) in texts {
+            index_message(state.clone(), id, text).await;
+        }
+    });
+    Ok(count)
+}
+
+// Expects a `file` field with a JSON export. All conversations are validated before
+// any is stored. Returns the number of imported conversations.
+#[server(name = ImportConversations, prefix = "/archive", input = MultipartFormData)]
+pub async fn import_conversations(data: MultipartData) -> Result<usize, ServerFnError> {
+    use axum::Extension;
+
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let user = require_user().await?;
+    let json = read_file(data).await?;
+    let export = ConversationExport::parse(&json).map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+    store_imports(state, user.id, &export.conversations).await?;
+    Ok(export.conversations.len())
+}
+
+// Expects a `file` field with ChatGPT's conversations.json
+#[server(name = ImportChatgpt, prefix = "/archive", input = MultipartFormData)]
+pub async fn import_chatgpt(data: MultipartData) -> Result<ImportReport, ServerFnError> {
+    use axum::Extension;
+
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let user = require_user().await?;
+    let json = read_file(data).await?;
+    let (conversations, skipped) =
+        crate::chatgpt::parse_export(&json).map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+    let messages = store_imports(state, user.id, &conversations).await?;
+    Ok(ImportReport { conversations: conversations.len(), messages, skipped })
+}
+
+#[component]
+pub fn Archive() -> impl IntoView {
+    let export = create_server_action::<ExportConversations>();
+    let share = create_server_action::<ShareConversation>();
+    let import = create_action(|data: &web_sys::FormData| {
+        let data = data.clone();
+        async move { import_conversations(data.into()).await }
+    });
+    let import_from_chatgpt = create_action(|data: &web_sys::FormData| {
+        let data = data.clone();
+        async move { import_chatgpt(data.into()).await }
+    });
+    let conversations = create_resource(
+        move || (import.version().get(), import_from_chatgpt.version().get()),
+        |_| list_conversations(),
+    );
+
+    let form_data = |ev: SubmitEvent| {
+        ev.prevent_default();
+        let form = event_target::<web_sys::HtmlFormElement>(&ev);
+        web_sys::FormData::new_with_form(&form).expect("form data from a form")
+    };
+    let on_import = move |ev: SubmitEvent| import.dispatch(form_data(ev));
+    let on_chatgpt_import = move |ev: SubmitEvent| import_from_chatgpt.dispatch(form_data(ev));
+    let export_as = move |conversation, format| export.dispatch(ExportConversations { conversation, format });
+
+    view! {
+        <Nav />
+        <h2>"Archive"</h2>
+        <p>"Export conversations as JSON to import them on another instance, or as Markdown to read them."</p>
+        <button on:click=move |_| export_as(None, ExportFormat::Json)>"Export all as JSON"</button>
+        <button on:click=move |_| export_as(None, ExportFormat::Markdown)>"Export all as Markdown"</button>
+        {move || match export.value().get() {
+            Some(Ok(file)) => view! {
+                <p><a href=file.href() download=file.name.clone()>"Download "{file.name.clone()}</a></p>
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{err.to_string()}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+
+        <form on:submit=on_import>
+            <label for="file"><b>"Import a JSON export"</b></label>
+            <input type="file" id="file" name="file" accept=".json,application/json" required/>
+            <button type="submit">"Import"</button>
+        </form>
+        {move || match import.value().get() {
+            Some(Ok(count)) => format!("Imported {count} conversations"),
+            Some(Err(err)) => err.to_string(),
+            None => String::new(),
+        }}
+
+        <form on:submit=on_chatgpt_import>
+            <label for="chatgpt-file"><b>"Import ChatGPT's conversations.json"</b></label>
+            <input type="file" id="chatgpt-file" name="file" accept=".json,application/json" required/>
+            <button type="submit">"Import"</button>
+        </form>
+        {move || match import_from_chatgpt.value().get() {
+            Some(Ok(report)) => view! {
+                <p>{format!(
+                    "Imported {} conversations with {} messages, skipped {} messages",
+                    report.conversations, report.messages, report.skipped.len(),
+                )}</p>
+                {(!report.skipped.is_empty()).then(|| view! {
+                    <details>
+                        <summary>"Skipped messages"</summary>
+                        <ul>
+                            {report.skipped.iter().map(|skipped| view! {
+                                <li>{format!("{}: {} ({})", skipped.conversation, skipped.role, skipped.reason)}</li>
+                            }).collect_view()}
+                        </ul>
+                    </details>
+                })}
+            }.into_view(),
+            Some(Err(err)) => view! { <p>{err.to_string()}</p> }.into_view(),
+            None => ().into_view(),
+        }}
+
+        <Transition fallback=move || view! { <p>"Loading..."</p> }>
+            {move || conversations.get().map(|conversations| match conversations {
+                Ok(conversations) => view! {
+                    <ul>
+                        {conversations.into_iter().map(|conversation| {
+                            let conversation_id = conversation.id;
+                            let id = Some(conversation_id);
+                            view! {
+                                <li>
+                                    {conversation.title}" ("{conversation.messages}" messages, "{conversation.created_at}") "
+                                    <button on:click=move |_| export_as(id, ExportFormat::Json)>
+                                        "JSON"
+                                    </button>
+                                    <button on:click=move |_| export_as(id, ExportFormat::Markdown)>
+                                        "Markdown"
+                                    </button>
+                                    <button on:click=move |_| share.dispatch(ShareConversation { conversation: conversation_id })>
+                                        "Share"
+                                    </button>
+                                </li>
+                            }
+                        }).collect_view()}
+                    </ul>
+                }.into_view(),
+                Err(err) => view! { <p>{err.to_string()}</p> }.into_view(),
+            })}
+        </Transition>
+        {move || match share.value().get() {
+            Some(Err(err)) => err.to_string(),
+            _ => String::new(),
+        }}
+        <ShareLinks refresh=share.version()/>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(parent: Option<usize>, role: Role, text: &str) -> ExportedMessage {
+        ExportedMessage { parent, role, text: text.to_string(), created_at: None, tool: None, sampling: None }
+    }
+
+    // "Hi" answered twice, the second answer is active
+    fn branched() -> ExportedConversation {
+        ExportedConversation {
+            title: "Greeting".to_string(),
+            model: None,
+            created_at: None,
+            leaf: Some(2),
+            messages: vec![
+                message(None, Role::User, "Hi"),
+                message(Some(0), Role::Assistant, "Hello"),
+                message(Some(0), Role::Assistant, "Hey there"),
+            ],
+        }
+    }
+
+    #[test]
+    fn exports_parse_back() {
+        let export = ConversationExport::new(None, vec![branched()]);
+        let json = serde_json::to_string(&export).unwrap();
+        assert_eq!(ConversationExport::parse(&json).unwrap(), export);
+    }
+
+    #[test]
+    fn imports_are_validated() {
+        let other_version = r#"{"version": 2, "conversations": []}"#;
+        assert!(matches!(ConversationExport::parse(other_version), Err(ImportError::UnsupportedVersion(2))));
+        assert!(matches!(ConversationExport::parse("[]"), Err(ImportError::NoVersion)));
+
+        let mut forward = branched();
+        forward.messages[0].parent = Some(1);
+        let mut tool_without_call = branched();
+        tool_without_call.messages[1].role = Role::Tool;
+        let mut bad_leaf = branched();
+        bad_leaf.leaf = Some(3);
+        let mut bad_start = branched();
+        bad_start.created_at = Some("yesterday".to_string());
+        let mut bad_message_time = branched();
+        bad_message_time.messages[1].created_at = Some("2024-13-01T00:00:00Z".to_string());
+        for conversation in [forward, tool_without_call, bad_leaf, bad_start, bad_message_time] {
+            let json = serde_json::to_string(&ConversationExport::new(None, vec![conversation])).unwrap();
+            assert!(matches!(ConversationExport::parse(&json), Err(ImportError::Invalid { conversation: 1, .. })));
+        }
+
+        let mut with_times = branched();
+        with_times.created_at = Some("2024-05-01T12:00:00+02:00".to_string());
+        with_times.messages[0].created_at = Some("2024-05-01T10:00:00Z".to_string());
+        let json = serde_json::to_string(&ConversationExport::new(None, vec![with_times])).unwrap();
+        assert!(ConversationExport::parse(&json).is_ok());
+    }
+
+    #[test]
+    fn markdown_shows_the_active_path() {
+        let markdown = branched().to_markdown();
+        assert!(markdown.starts_with("# Greeting\n"));
+        assert!(markdown.contains("## You\n\nHi\n"));
+        assert!(markdown.contains("## Jippity\n\nHey there\n"));
+        assert!(!markdown.contains("Hello"));
+    }
+
+    #[test]
+    fn fences_outlast_backticks_inside() {
+        assert_eq!(fenced("{}"), "```\n{}\n```");
+        assert_eq!(fenced("a ```` b"), "`````\na ```` b\n`````");
+    }
+
+    #[test]
+    fn data_urls_escape_the_content() {
+        let file = ExportFile { name: "a.md".to_string(), mime: "text/markdown".to_string(), content: "# Hi ü".to_string() };
+        assert_eq!(file.href(), "data:text/markdown;charset=utf-8,%23%20Hi%20%C3%BC");
+    }
+}
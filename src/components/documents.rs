@@ -0,0 +1,165 @@
+use leptos::*;
+use leptos::ev::SubmitEvent;
+use leptos::server_fn::codec::{MultipartData, MultipartFormData};
+use leptos_router::*;
+use crate::components::nav::Nav;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use crate::app::ssr::{require_user, AppState};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollectionInfo {
+    pub id: i64,
+    pub name: String,
+    pub documents: Vec<DocumentInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentInfo {
+    pub id: i64,
+    pub name: String,
+    pub chunks: usize,
+    // chunks embedded so far, they can be retrieved once embedded
+    pub indexed: usize,
+}
+
+#[server(ListCollections, "/documents")]
+pub async fn list_collections() -> Result<Vec<CollectionInfo>, ServerFnError> {
+    let user = require_user().await?;
+    crate::documents::list_collections(user.id).await
+}
+
+#[server(CreateCollection, "/documents")]
+pub async fn create_collection(name: String) -> Result<i64, ServerFnError> {
+    let user = require_user().await?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ServerFnError::ServerError("The collection needs a name".to_string()));
+    }
+    crate::documents::create_collection(user.id, name).await
+}
+
+// Expects a `collection` id field and a `file` field. The text is chunked right away,
+// the chunks are embedded in the background. Returns the number of chunks.
+#[server(name = UploadDocument, prefix = "/documents", input = MultipartFormData)]
+pub async fn upload_document(data: MultipartData) -> Result<usize, ServerFnError> {
+    use crate::documents::{create_document, owns_collection};
+    use crate::inference::retrieval::{chunk_text, extract_text, index_document};
+    use axum::Extension;
+
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let user = require_user().await?;
+    let mut data = data.into_inner().expect("multipart data on the server");
+    let limits = &state.config.retrieval;
+
+    let mut collection = None;
+    let mut file = None;
+    while let Some(field) = data.next_field().await? {
+        let field_name = field.name().map(str::to_string);
+        match field_name.as_deref() {
+            Some("collection") => collection = Some(field.text().await?.parse::<i64>()?),
+            Some("file") => {
+                let name = field.file_name().unwrap_or("upload.txt").to_string();
+                file = Some((name, field.bytes().await?));
+            }
+            _ => {}
+        }
+    }
+    let (Some(collection), Some((name, bytes))) = (collection, file) else {
+        return Err(ServerFnError::ServerError("Pick a collection and a file".to_string()));
+    };
+    if bytes.len() > limits.max_upload_mb * 1024 * 1024 {
+        return Err(ServerFnError::ServerError(format!("Files may be at most {} MB", limits.max_upload_mb)));
+    }
+    if !owns_collection(collection, user.id).await? {
+        return Err(ServerFnError::ServerError("Collection not found".to_string()));
+    }
+
+    let text = extract_text(&name, &bytes)?;
+    let chunks = chunk_text(&text, limits.chunk_chars, limits.chunk_overlap);
+    let document = create_document(collection, &name, chunks.len()).await?;
+    let count = chunks.len();
+    tokio::spawn(index_document(state.clone(), user.id, document, chunks));
+    Ok(count)
+}
+
+#[server(DeleteDocument, "/documents")]
+pub async fn delete_document(id: i64) -> Result<(), ServerFnError> {
+    let user = require_user().await?;
+    crate::documents::delete_document(id, user.id).await
+}
+
+#[component]
+pub fn Documents() -> impl IntoView {
+    let create_action = create_server_action::<CreateCollection>();
+    let delete_action = create_server_action::<DeleteDocument>();
+    let upload = create_action(|data: &web_sys::FormData| {
+        let data = data.clone();
+        async move { upload_document(data.into()).await }
+    });
+    let collections = create_resource(
+        move || (create_action.version().get(), delete_action.version().get(), upload.version().get()),
+        |_| list_collections(),
+    );
+
+    let on_upload = move |ev: SubmitEvent| {
+        ev.prevent_default();
+        let form = event_target::<web_sys::HtmlFormElement>(&ev);
+        let data = web_sys::FormData::new_with_form(&form).expect("form data from a form");
+        upload.dispatch(data);
+    };
+
+    view! {
+        <Nav />
+        <h2>"Documents"</h2>
+        <p>"Attach a collection to a Jippity conversation and answers are grounded on its documents."</p>
+
+        <ActionForm action=create_action>
+            <label for="name"><b>"New collection"</b></label>
+            <input type="text" placeholder="e.g. Handbook" id="name" name="name" required/>
+            <button type="submit">"Create"</button>
+        </ActionForm>
+
+        <form on:submit=on_upload>
+            <label for="collection"><b>"Upload to"</b></label>
+            <select id="collection" name="collection" required>
+                {move || collections.get().and_then(Result::ok).unwrap_or_default().into_iter().map(|collection| {
+                    view! { <option value=collection.id>{collection.name}</option> }
+                }).collect_view()}
+            </select>
+            <input type="file" name="file" accept=".txt,.md,.markdown,.pdf" required/>
+            <button type="submit">"Upload"</button>
+        </form>
+        {move || match upload.value().get() {
+            Some(Ok(chunks)) => format!("Uploaded, {chunks} chunks are being indexed"),
+            Some(Err(err)) => err.to_string(),
+            None => String::new(),
+        }}
+
+        <Transition fallback=move || view! { <p>"Loading..."</p> }>
+            {move || collections.get().map(|collections| match collections {
+                Ok(collections) => collections.into_iter().map(|collection| view! {
+                    <h3>{collection.name}</h3>
+                    <ul>
+                        {collection.documents.into_iter().map(|document| {
+                            let status = if document.indexed < document.chunks {
+                                format!(" ({} of {} chunks indexed) ", document.indexed, document.chunks)
+                            } else {
+                                format!(" ({} chunks) ", document.chunks)
+                            };
+                            view! {
+                                <li>
+                                    {document.name}{status}
+                                    <button on:click=move |_| delete_action.dispatch(DeleteDocument { id: document.id })>
+                                        "Delete"
+                                    </button>
+                                </li>
+                            }
+                        }).collect_view()}
+                    </ul>
+                }).collect_view(),
+                Err(err) => view! { <p>{err.to_string()}</p> }.into_view(),
+            })}
+        </Transition>
+    }
+}
@@ -1,181 +1,929 @@
 use serde::{Deserialize, Serialize};
-use leptos::{component, view, IntoView, create_signal, create_action, create_effect, ReadSignal, create_node_ref};
-use leptos::html::{Div, Input};
+use leptos::*;
+use leptos_router::ActionForm;
+use leptos::html::{Div, Input, Textarea};
+use leptos::server_fn::codec::{Json, StreamingText, TextStream};
+use futures::StreamExt;
 use cfg_if::cfg_if;
+use crate::components::documents::list_collections;
+use crate::components::markdown::Markdown;
+use crate::components::nav::Nav;
+use crate::components::personas::list_personas;
+use crate::components::share::ShareButton;
+use crate::components::usage::UsageMeter;
+use crate::inference::context::ContextUsage;
+use crate::inference::events::{ChatEvent, EventDecoder};
+use crate::inference::grammar::{JsonSchemaFormat, ResponseFormat};
+use crate::inference::sampling::SamplingSettings;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct Message {
+    // None until stored
+    #[serde(default)]
+    pub id: Option<i64>,
     pub text: String,
     pub from_llm: bool,
+    // document chunks the answer was grounded on, cited as [1], [2], ... in the text
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    // set for a tool Jippity called, `text` is empty then
+    #[serde(default)]
+    pub tool: Option<ToolInvocation>,
+    // ids of the messages with the same parent, this one included, oldest first
+    #[serde(default)]
+    pub siblings: Vec<i64>,
+    // what an answer was sampled with, including the seed
+    #[serde(default)]
+    pub sampling: Option<SamplingSettings>,
+    // the model and response format of an answer, a replay uses them too
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub format: Option<ResponseFormat>,
+    // the user's rating of an answer
+    #[serde(default)]
+    pub feedback: Option<Feedback>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl Rating {
+    // stored as 1 and -1
+    pub fn score(self) -> i16 {
+        match self {
+            Rating::Up => 1,
+            Rating::Down => -1,
+        }
+    }
+
+    pub fn from_score(score: i16) -> Option<Rating> {
+        match score {
+            1 => Some(Rating::Up),
+            -1 => Some(Rating::Down),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Feedback {
+    pub rating: Rating,
+    // empty if the user didn't comment
+    #[serde(default)]
+    pub comment: String,
+}
+
+impl Message {
+    pub fn user(text: impl Into<String>) -> Message {
+        Message { text: text.into(), from_llm: false, ..Default::default() }
+    }
+
+    pub fn llm(text: impl Into<String>) -> Message {
+        Message { text: text.into(), from_llm: true, ..Default::default() }
+    }
+
+    // What a replay runs with: the sampling settings with the seed, the model and the format
+    pub fn replay_settings(&self) -> Option<(SamplingSettings, String, ResponseFormat)> {
+        let sampling = self.sampling.clone().filter(|sampling| sampling.seed.is_some())?;
+        Some((sampling, self.model.clone()?, self.format.clone()?))
+    }
+
+    pub fn tool(tool: ToolInvocation) -> Message {
+        Message { from_llm: true, tool: Some(tool), ..Default::default() }
+    }
+}
+
+// What a chat request adds to the conversation before Jippity answers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatInput {
+    // a new user message at the end of the active path
+    Message(String),
+    // a changed copy of an earlier user message, stored as its sibling
+    Edit { message: i64, text: String },
+    // another answer to the user message the answer `message` replied to
+    Regenerate { message: i64 },
+    // like Regenerate, with the seed, sampling settings, model and format of `message`
+    Replay { message: i64 },
+}
+
+impl ChatInput {
+    // the user message to store, Regenerate and Replay reuse the stored one
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            ChatInput::Message(text) | ChatInput::Edit { text, .. } => Some(text),
+            ChatInput::Regenerate { .. } | ChatInput::Replay { .. } => None,
+        }
+    }
+
+    // replaces the text with its moderated version
+    pub fn set_text(&mut self, moderated: String) {
+        if let ChatInput::Message(text) | ChatInput::Edit { text, .. } = self {
+            *text = moderated;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ToolInvocation {
+    pub name: String,
+    // the arguments object as JSON
+    pub arguments: String,
+    pub result: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Source {
+    // the number the answer cites it by
+    pub index: usize,
+    pub chunk_id: i64,
+    pub document: String,
+    pub excerpt: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Conversation {
+    // None until the first message got stored
+    pub id: Option<i64>,
+    // None means the configured default model
+    pub model: Option<String>,
+    // document collection answers are grounded on
+    pub collection: Option<i64>,
+    // persona the conversation was started with, None for the model's own system prompt
+    pub persona: Option<i64>,
+    // the active path through the message tree, from the first message to the newest one
     pub messages: Vec<Message>,
 }
 
 impl Conversation {
     pub fn new() -> Conversation {
         Conversation {
+            id: None,
+            model: None,
+            collection: None,
+            persona: None,
             messages: Vec::new(),
         }
     }
+
+    // Cuts the active path back to where `input` branches off: before the edited message,
+    // or after the question of the regenerated or replayed answer. False if the message isn't on the path.
+    pub fn branch_for(&mut self, input: &ChatInput) -> bool {
+        let position = |id: i64| self.messages.iter().position(|message| message.id == Some(id));
+        let keep = match input {
+            ChatInput::Message(_) => return true,
+            ChatInput::Edit { message, .. } => position(*message).filter(|&i| !self.messages[i].from_llm),
+            ChatInput::Regenerate { message } | ChatInput::Replay { message } => position(*message)
+                .filter(|&i| self.messages[i].from_llm)
+                .and_then(|i| self.messages[..i].iter().rposition(|message| !message.from_llm))
+                .map(|question| question + 1),
+        };
+        match keep {
+            Some(keep) => {
+                self.messages.truncate(keep);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ModelInfo {
+    pub id: String,
+    pub context_size: usize,
+    pub status: ModelStatus,
+    pub default: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ModelStatus {
+    // loads on first use
+    Unloaded,
+    Loading,
+    Ready,
+    // the load error, only shown to admins
+    Failed(String),
+}
+
+// A past message matching a history search
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SearchHit {
+    pub conversation_id: i64,
+    pub conversation_title: String,
+    pub message: Message,
+    pub score: f32,
+}
+
+const SEARCH_RESULTS: usize = 10;
+
 cfg_if! {
     if #[cfg(feature = "ssr")] {
-        use llm::models::Llama;
-        use tokio::sync::mpsc;
+        use crate::app::ssr::{current_user, AppState};
+        use crate::documents::owns_collection;
+        use crate::history::{
+            append_message, create_conversation, embedded_messages, load_conversation, message_tree, newest_leaf,
+            set_conversation_collection, set_conversation_model, set_feedback, set_leaf,
+        };
+        use crate::inference::cache::CacheMode;
+        use crate::inference::embeddings::{cosine_similarity, embed, index_message};
+        use crate::inference::moderation::{moderate, Stage};
+        use crate::inference::retrieval::{grounded_system_prompt, retrieve};
+        use crate::inference::turn::ChatTurn;
+        use crate::moderation::{record_decisions, AuditSource};
+        use crate::personas::load_persona;
+        use crate::usage::check_quota;
         use std::sync::Arc;
-        use leptos::{component, view, IntoView, create_signal, create_action, create_effect, ReadSignal, create_node_ref};
-        use leptos::html::{Div, Input};
-
-        // Assuming `tx` is created and used properly within the scope of the function
-        fn inference_callback<'a>(
-            stop_sequence: String,
-            buf: &'a mut String,
-            tx: mpsc::Sender<String>,
-            runtime: &'a mut tokio::runtime::Runtime,
-        ) -> impl FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, std::convert::Infallible> + 'a {
-            use llm::InferenceFeedback::{Halt, Continue};
-
-            move |resp| -> Result<llm::InferenceFeedback, std::convert::Infallible> {
-                match resp {
-                    llm::InferenceResponse::InferredToken(t) => {
-                        let mut reverse_buf = buf.clone();
-                        reverse_buf.push_str(t.as_str());
-                        if stop_sequence.as_str().eq(reverse_buf.as_str()) {
-                            buf.clear();
-                            return Ok(Halt);
-                        } else if stop_sequence.as_str().starts_with(reverse_buf.as_str()) {
-                            buf.push_str(t.as_str());
-                            return Ok(Continue);
-                        }
+        use axum::Extension;
+        use tokio::sync::mpsc;
+    }
+}
 
-                        let text_to_send = if buf.is_empty() {
-                            t.clone()
-                        } else {
-                            reverse_buf
-                        };
+#[server(ListModels, "/jippity")]
+pub async fn list_models() -> Result<Vec<ModelInfo>, ServerFnError> {
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let default = &state.config.default_model().id;
+    // load errors name files on the server
+    let is_admin = current_user().await?.is_some_and(|user| user.is_admin);
 
-                        let tx_cloned = tx.clone();
-                        runtime.block_on(async move {
-                            tx_cloned.send(text_to_send).await.expect("issue sending on channel");
-                        });
+    Ok(state
+        .config
+        .models
+        .iter()
+        .map(|model| ModelInfo {
+            id: model.id.clone(),
+            context_size: model.context_size,
+            status: match state.models.status(&model.id) {
+                ModelStatus::Failed(_) if !is_admin => ModelStatus::Failed("see the server log".to_string()),
+                status => status,
+            },
+            default: &model.id == default,
+        })
+        .collect())
+}
 
-                        Ok(Continue)
-                    }
-                    llm::InferenceResponse::EotToken => Ok(Halt),
-                    _ => Ok(Continue),
-                }
+// Adds `input` to the stored conversation (a new one if `conversation` is None)
+// and streams Jippity's answer back as ChatEvents. `model` switches the conversation
+// to another model, None keeps the current one. `collection` is the document collection
+// the answer is grounded on, None detaches it. `persona` only counts for a new conversation.
+// `format` makes this answer JSON, optionally matching a schema. `seed` fixes the sampling
+// seed, a random one is used (and stored with the answer) otherwise. The user's text and
+// the answer go through the [moderation] rules.
+#[server(name = Jippity, prefix = "/jippity", input = Json, output = StreamingText)]
+pub async fn converse(
+    conversation: Option<i64>,
+    model: Option<String>,
+    collection: Option<i64>,
+    persona: Option<i64>,
+    format: ResponseFormat,
+    seed: Option<u64>,
+    input: ChatInput,
+) -> Result<TextStream, ServerFnError> {
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let Some(user) = current_user().await? else {
+        return Err(ServerFnError::ServerError("Please log in to chat with Jippity".to_string()));
+    };
+    check_quota(&state, user.id).await?;
+    if let Some(id) = &model {
+        state.config.model(id)?;
+    }
+    format.constraint().map_err(|err| ServerFnError::ServerError(err.to_string()))?;
+
+    // the user's text is checked before anything is stored, a blocked message only
+    // leaves its audit record
+    let mut input = input;
+    let input_decisions = match input.text() {
+        Some(text) => {
+            let checked = moderate(&state, user.id, Stage::Input, text).await;
+            if checked.blocked() {
+                let blocked_by = checked.blocked_by();
+                let source = AuditSource::Chat { conversation_id: None, message_id: None };
+                tokio::spawn(record_decisions(user.id, source, Stage::Input, checked.decisions));
+                return Err(ServerFnError::ServerError(format!(
+                    "Your message was blocked by the moderation rules ({blocked_by})"
+                )));
             }
+            input.set_text(checked.text);
+            checked.decisions
         }
+        None => Vec::new(),
+    };
+
+    let mut history = match conversation {
+        Some(id) => load_conversation(id, user.id)
+            .await?
+            .ok_or_else(|| ServerFnError::ServerError("Conversation not found".to_string()))?,
+        None => {
+            let ChatInput::Message(message) = &input else {
+                return Err(ServerFnError::ServerError("Conversation not found".to_string()));
+            };
+            let persona = match persona {
+                Some(id) => Some(
+                    load_persona(id, user.id)
+                        .await?
+                        .ok_or_else(|| ServerFnError::ServerError("Persona not found".to_string()))?,
+                ),
+                None => None,
+            };
+            let model_id = model
+                .clone()
+                .or_else(|| persona.as_ref().and_then(|persona| persona.model.clone()))
+                .filter(|id| state.config.model(id).is_ok())
+                .unwrap_or_else(|| state.config.default_model().id.clone());
+            // no empty conversation is left behind when Jippity can't answer
+            state.models.check_available(&model_id)?;
+            let persona_id = persona.and_then(|persona| persona.id);
+            let id = create_conversation(user.id, message, &model_id, persona_id).await?;
+            Conversation {
+                id: Some(id),
+                model: Some(model_id),
+                collection: None,
+                persona: persona_id,
+                messages: Vec::new(),
+            }
+        }
+    };
+    let conversation_id = history.id.expect("stored conversations have an id");
+    if model.is_some() && model != history.model {
+        set_conversation_model(conversation_id, model.as_deref().unwrap()).await?;
+        history.model = model;
+    }
+    if collection != history.collection {
+        if let Some(id) = collection {
+            if !owns_collection(id, user.id).await? {
+                return Err(ServerFnError::ServerError("Collection not found".to_string()));
+            }
+        }
+        set_conversation_collection(conversation_id, collection).await?;
+        history.collection = collection;
+    }
+
+    // a replay runs exactly like the answer it replays: same sampling, model and format
+    let replayed = match &input {
+        ChatInput::Replay { message } => Some(
+            history
+                .messages
+                .iter()
+                .find(|stored| stored.id == Some(*message))
+                .and_then(Message::replay_settings)
+                .ok_or_else(|| ServerFnError::ServerError("This answer has no recorded seed, model and format".to_string()))?,
+        ),
+        _ => None,
+    };
+
+    // a model removed from the config falls back to the default one, but a replay needs its own
+    let model_config = match &replayed {
+        Some((_, model, _)) => state.config.model(model).map_err(|_| {
+            ServerFnError::ServerError(format!("The model of this answer, {model}, is no longer available"))
+        })?,
+        None => history
+            .model
+            .as_deref()
+            .and_then(|id| state.config.model(id).ok())
+            .unwrap_or_else(|| state.config.default_model()),
+    };
+    let template = state.config.template(&model_config.template)?;
+    let model = state.models.get(&model_config.id).await?;
+
+    if !matches!(input, ChatInput::Message(_)) {
+        if !history.branch_for(&input) {
+            return Err(ServerFnError::ServerError("Message not found".to_string()));
+        }
+        set_leaf(conversation_id, history.messages.last().and_then(|message| message.id)).await?;
+    }
+    if let Some(text) = input.text() {
+        let mut user_msg = Message::user(text);
+        let message_id = append_message(conversation_id, &user_msg).await?;
+        tokio::spawn(index_message(state.clone(), message_id, user_msg.text.clone()));
+        let source = AuditSource::Chat { conversation_id: Some(conversation_id), message_id: Some(message_id) };
+        tokio::spawn(record_decisions(user.id, source, Stage::Input, input_decisions));
+        user_msg.id = Some(message_id);
+        history.messages.push(user_msg);
+    }
+    let question = history.messages.last().map(|message| message.text.clone()).unwrap_or_default();
+    let sources = match history.collection {
+        Some(collection) => retrieve(&state, user.id, collection, &question).await?,
+        None => Vec::new(),
+    };
+
+    // a deleted persona leaves the conversation with the model's own system prompt
+    let persona = match history.persona {
+        Some(id) => load_persona(id, user.id).await?,
+        None => None,
+    };
+    let (base_prompt, sampling) = match persona {
+        Some(persona) if !persona.system_prompt.trim().is_empty() => (persona.system_prompt, persona.sampling),
+        Some(persona) => (model_config.system_prompt.clone(), persona.sampling),
+        None => (model_config.system_prompt.clone(), SamplingSettings::default()),
+    };
+    // the cache would hand back the replayed answer itself
+    let (sampling, format, cache) = match replayed {
+        Some((sampling, _, format)) => (sampling, format, CacheMode::Refresh),
+        None => (SamplingSettings { seed, ..sampling }, format, CacheMode::Read),
+    };
+    let mut system_prompt = grounded_system_prompt(&base_prompt, history.collection.is_some());
+    // no tools are offered for a JSON answer
+    if let Some(instructions) = format.instructions() {
+        system_prompt = format!("{system_prompt}\n\n{instructions}");
+    } else if !state.tools.is_empty() {
+        system_prompt = format!("{system_prompt}\n\n{}", state.tools.system_prompt());
     }
+    let turn = ChatTurn {
+        state: state.clone(),
+        user_id: user.id,
+        history,
+        model_config: model_config.clone(),
+        template,
+        model,
+        system_prompt,
+        sampling,
+        format,
+        sources: sources.clone(),
+        cache,
+    };
+    if !turn.fits(&turn.fit()) {
+        return Err(ServerFnError::ServerError(
+            "Your message is too long for Jippity's context window".to_string(),
+        ));
+    }
+
+    let generation = Arc::new(state.generations.start(user.id));
+    let (tx, rx) = mpsc::channel(16);
+    let _ = tx
+        .send(ChatEvent::Started { generation: generation.id, conversation: conversation_id })
+        .await;
+    if !sources.is_empty() {
+        let _ = tx.send(ChatEvent::Sources(sources)).await;
+    }
+
+    tokio::spawn(async move {
+        let event = turn.run(generation, tx.clone()).await;
+        let _ = tx.send(event).await;
+    });
+
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok(event.encode()), rx))
+    });
+    Ok(TextStream::new(events))
 }
 
-#[server(Jippity, "/jippity")]
-pub async fn converse(
-    Extension(model): Extension<Arc<Llama>>,  // Extract the model from state
-    prompt: Conversation,
-) -> Result<String, ServerFnError> {
-    let mut runtime = Runtime::new().expect("Failed to create runtime");
-
-    let jippity = "Jippity:";
-    let user_name = "User";
-    let mut chat = String::new();
-
-    for message in prompt.messages.into_iter() {
-        let msg = message.text;
-        let cur_ln = if message.from_llm {
-            format!("{user_name}: {msg}\n")
-        } else {
-            format!("{jippity}: {msg}\n")
-        };
-        chat.push_str(&cur_ln);
-    }
-
-    let mut res = String::new();
-    let mut rng = rand::thread_rng();
-    let mut buf = String::new();
-    let mut session = model.start_session(Default::default());
-
-    let (tx, mut rx) = mpsc::channel(1);
-
-    let stop_sequence = String::from(user_name);
-    session
-        .infer(
-            model.as_ref(),
-            &mut rng,
-            &InferenceRequest {
-                prompt: format!("{jippity}\n{chat}\n{user_name}").as_str().into(),
-                parameters: &llm::InferenceParameters::default(),
-                play_back_previous_tokens: false,
-                maximum_token_count: None,
-            },
-            &mut Default::default(),
-            inference_callback(
-                stop_sequence,
-                &mut buf,
-                tx, 
-                &mut runtime,
-            ),
-        )
-        .unwrap_or_else(|e| panic!("{e}"));
+// The stored conversation with its active path
+#[server(GetConversation, "/jippity")]
+pub async fn get_conversation(id: i64) -> Result<Conversation, ServerFnError> {
+    let Some(user) = current_user().await? else {
+        return Err(ServerFnError::ServerError("Please log in to chat with Jippity".to_string()));
+    };
+    load_conversation(id, user.id)
+        .await?
+        .ok_or_else(|| ServerFnError::ServerError("Conversation not found".to_string()))
+}
 
-    while let Some(message) = rx.recv().await {
-        res.push_str(&message);
+// Makes the branch of `message` the active path, down to its newest answer
+#[server(SwitchBranch, "/jippity")]
+pub async fn switch_branch(conversation: i64, message: i64) -> Result<Conversation, ServerFnError> {
+    get_conversation(conversation).await?;
+    let tree = message_tree(conversation).await?;
+    if !tree.iter().any(|(id, _)| *id == message) {
+        return Err(ServerFnError::ServerError("Message not found".to_string()));
     }
+    set_leaf(conversation, Some(newest_leaf(&tree, message))).await?;
+    get_conversation(conversation).await
+}
+
+// Rates an answer, None removes the rating
+#[server(name = RateMessage, prefix = "/jippity", input = Json)]
+pub async fn rate_message(conversation: i64, message: i64, feedback: Option<Feedback>) -> Result<(), ServerFnError> {
+    let Some(user) = current_user().await? else {
+        return Err(ServerFnError::ServerError("Please log in to rate answers".to_string()));
+    };
+    if !set_feedback(user.id, conversation, message, feedback.as_ref()).await? {
+        return Err(ServerFnError::ServerError("Answer not found".to_string()));
+    }
+    Ok(())
+}
 
-    Ok(res)
+// Stops a running answer at the next token, the text generated so far is kept
+#[server(CancelGeneration, "/jippity")]
+pub async fn cancel_generation(generation: u64) -> Result<bool, ServerFnError> {
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let Some(user) = current_user().await? else {
+        return Err(ServerFnError::ServerError("Please log in to stop an answer".to_string()));
+    };
+    Ok(state.generations.cancel(generation, user.id))
+}
+
+// Ranks the user's past messages by cosine similarity to `query`
+#[server(SearchHistory, "/jippity")]
+pub async fn search_history(query: String) -> Result<Vec<SearchHit>, ServerFnError> {
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let Some(user) = current_user().await? else {
+        return Err(ServerFnError::ServerError("Please log in to search your chats".to_string()));
+    };
+    if query.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let model_config = state.config.embedding_model().clone();
+    let query = embed(&state, Some(user.id), &model_config, &query).await?;
+    let mut hits: Vec<SearchHit> = embedded_messages(user.id, &model_config.id)
+        .await?
+        .into_iter()
+        .map(|stored| SearchHit {
+            score: cosine_similarity(&query.vector, &stored.embedding),
+            conversation_id: stored.conversation_id,
+            conversation_title: stored.title,
+            message: stored.message,
+        })
+        .collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(SEARCH_RESULTS);
+    Ok(hits)
 }
 
 // Client-side components
 
+// Feeds the streamed answer into the last (assistant) message of the conversation
+async fn stream_reply(
+    conversation: Option<i64>,
+    model: Option<String>,
+    collection: Option<i64>,
+    persona: Option<i64>,
+    format: ResponseFormat,
+    seed: Option<u64>,
+    input: ChatInput,
+    set_conversation: WriteSignal<Conversation>,
+    set_context: WriteSignal<ContextUsage>,
+    set_generation: WriteSignal<Option<u64>>,
+) -> Result<(), ServerFnError> {
+    let mut chunks = converse(conversation, model, collection, persona, format, seed, input).await?.into_inner();
+    let mut decoder = EventDecoder::default();
+    let mut placeholder = true;
+
+    while let Some(chunk) = chunks.next().await {
+        for event in decoder.push(&chunk?) {
+            match event {
+                ChatEvent::Started { generation, conversation } => {
+                    set_generation.set(Some(generation));
+                    set_conversation.update(move |conv| conv.id = Some(conversation));
+                }
+                ChatEvent::Queued { position } => {
+                    if placeholder {
+                        set_conversation.update(move |conv| {
+                            conv.messages.last_mut().unwrap().text = format!("waiting (position {position})");
+                        });
+                    }
+                }
+                ChatEvent::Sources(sources) => {
+                    set_conversation.update(move |conv| conv.messages.last_mut().unwrap().sources = sources);
+                }
+                ChatEvent::Token(token) => {
+                    let clear = std::mem::replace(&mut placeholder, false);
+                    set_conversation.update(move |conv| {
+                        let answer = conv.messages.last_mut().unwrap();
+                        if clear {
+                            answer.text.clear();
+                        }
+                        answer.text.push_str(&token);
+                    });
+                }
+                ChatEvent::Tool(invocation) => {
+                    // the tool gets its own bubble, the answer continues in a new one
+                    let replace = std::mem::replace(&mut placeholder, true);
+                    set_conversation.update(move |conv| {
+                        let last = conv.messages.pop().unwrap();
+                        let mut next = Message::llm("...");
+                        if replace {
+                            next.sources = last.sources;
+                        } else {
+                            conv.messages.push(last);
+                        }
+                        conv.messages.push(Message::tool(invocation));
+                        conv.messages.push(next);
+                    });
+                }
+                ChatEvent::Done { context, .. } => set_context.set(context),
+                ChatEvent::Error(err) => return Err(ServerFnError::ServerError(err)),
+            }
+        }
+    }
+    Ok(())
+}
+
 #[component]
 pub fn Jippity() -> impl IntoView {
     let (conversation, set_conversation) = create_signal(Conversation::new());
+    let (context, set_context) = create_signal(ContextUsage::default());
+    let (generation, set_generation) = create_signal(None::<u64>);
+    let (model, set_model) = create_signal(None::<String>);
+    let (collection, set_collection) = create_signal(None::<i64>);
+    let (persona, set_persona) = create_signal(None::<i64>);
+    let (format, set_format) = create_signal(ResponseFormat::Text);
+    let (seed, set_seed) = create_signal(None::<u64>);
 
-    let send = create_action(move |new_msg: &String| {
-        let user_msg = Message {
-            text: new_msg.clone(),
-            from_llm: false,
-        };
-        set_conversation.update(move |conv| 
-            conv.messages.push(user_msg));
-        // Call the server function here
-        converse(conversation.get())
-    });
+    let send = create_action(move |input: &ChatInput| {
+        let input = input.clone();
+        let conversation_id = conversation.get_untracked().id;
+        let model = model.get_untracked();
+        let collection = collection.get_untracked();
+        let persona = persona.get_untracked();
+        let format = format.get_untracked();
+        let seed = seed.get_untracked();
+        set_conversation.update({
+            let input = input.clone();
+            move |conv| {
+                conv.branch_for(&input);
+                if let Some(text) = input.text() {
+                    conv.messages.push(Message::user(text));
+                }
+                conv.messages.push(Message::llm("..."));
+            }
+        });
 
-    create_effect(move |_| {
-        if let Some(_) = send.input().get() {
-            let response_msg = Message {
-                text: String::from("..."),
-                from_llm: true,
-            };
-            set_conversation.update(move |conv| {
-                conv.messages.push(response_msg);
-            });
+        async move {
+            let result = stream_reply(
+                conversation_id, model, collection, persona, format, seed, input, set_conversation, set_context,
+                set_generation,
+            ).await;
+            set_generation.set(None);
+            match &result {
+                // the stored messages carry the ids and branches the bubbles need
+                Ok(()) => {
+                    if let Some(id) = conversation.get_untracked().id {
+                        if let Ok(stored) = get_conversation(id).await {
+                            set_conversation.set(stored);
+                        }
+                    }
+                }
+                Err(err) => {
+                    let text = format!("Error: {err}");
+                    set_conversation.update(move |conv| {
+                        conv.messages.last_mut().unwrap().text = text;
+                    });
+                }
+            }
+            result
         }
     });
 
+    // refetched after every answer, and every few seconds while a model loads
+    let (polls, set_polls) = create_signal(0);
+    let models = create_resource(move || (send.version().get(), polls.get()), |_| list_models());
     create_effect(move |_| {
-        if let Some(Ok(response)) = send.value().get() {
-            set_conversation.update(move |conv| {
-                conv.messages.last_mut().unwrap().text = response;
-            });
+        let loading = models
+            .get()
+            .and_then(Result::ok)
+            .is_some_and(|models| models.iter().any(|info| info.status == ModelStatus::Loading));
+        if loading {
+            set_timeout(move || set_polls.update(|polls| *polls += 1), std::time::Duration::from_secs(3));
         }
     });
 
     view! {
         <Nav />
         <h1>"The I in LLM stands for Intelligence"</h1>
-        <ChatArea conversation/>
-        <TypeArea send/>
+        <ModelPicker models model set_model/>
+        <CollectionPicker set_collection/>
+        <PersonaPicker conversation set_persona/>
+        <FormatPicker set_format/>
+        <SeedInput set_seed/>
+        <HistorySearch/>
+        <ShareButton conversation/>
+        <ModelStatusNotice models model/>
+        <ChatArea conversation set_conversation send generation/>
+        <ContextMeter context/>
+        <UsageMeter version=send.version()/>
+        <TypeArea send generation/>
+    }
+}
+
+type ModelsResource = Resource<(usize, usize), Result<Vec<ModelInfo>, ServerFnError>>;
+
+#[component]
+pub fn ModelPicker(
+    models: ModelsResource,
+    model: ReadSignal<Option<String>>,
+    set_model: WriteSignal<Option<String>>,
+) -> impl IntoView {
+    view! {
+        <Suspense fallback=|| ()>
+            <select
+                class="mb-3 p-2 rounded bg-zinc-700 border-zinc-700 text-white"
+                on:change=move |ev| {
+                    let id = event_target_value(&ev);
+                    set_model.set((!id.is_empty()).then_some(id));
+                }
+            >
+                {move || models.get().map(|models| {
+                    models.unwrap_or_default().into_iter().map(|info| {
+                        let state = match &info.status {
+                            ModelStatus::Unloaded => ", loads on first use",
+                            ModelStatus::Loading => ", loading",
+                            ModelStatus::Ready => "",
+                            ModelStatus::Failed(_) => ", unavailable",
+                        };
+                        let label = format!("{} ({} tokens{state})", info.id, info.context_size);
+                        let id = info.id.clone();
+                        let selected = move || match model.get() {
+                            Some(selected) => selected == id,
+                            None => info.default,
+                        };
+                        view! { <option value=info.id.clone() selected=selected>{label}</option> }
+                    }).collect_view()
+                })}
+            </select>
+        </Suspense>
+    }
+}
+
+#[component]
+pub fn CollectionPicker(set_collection: WriteSignal<Option<i64>>) -> impl IntoView {
+    let collections = create_resource(|| (), |_| list_collections());
+
+    view! {
+        <Suspense fallback=|| ()>
+            <select
+                class="mb-3 ml-2 p-2 rounded bg-zinc-700 border-zinc-700 text-white"
+                on:change=move |ev| set_collection.set(event_target_value(&ev).parse().ok())
+            >
+                <option value="">"No documents"</option>
+                {move || collections.get().map(|collections| {
+                    collections.unwrap_or_default().into_iter().map(|collection| {
+                        view! { <option value=collection.id>{format!("Documents: {}", collection.name)}</option> }
+                    }).collect_view()
+                })}
+            </select>
+        </Suspense>
+    }
+}
+
+// Empty for a random seed; the seed of every answer shows on its replay button
+#[component]
+pub fn SeedInput(set_seed: WriteSignal<Option<u64>>) -> impl IntoView {
+    view! {
+        <input
+            class="mb-3 ml-2 p-2 w-40 rounded bg-zinc-700 border-zinc-700 text-white"
+            type="number"
+            min="0"
+            placeholder="Seed (random)"
+            on:input=move |ev| set_seed.set(event_target_value(&ev).trim().parse().ok())
+        />
+    }
+}
+
+// Instead of a chat that hangs, say why Jippity can't answer right now
+#[component]
+pub fn ModelStatusNotice(models: ModelsResource, model: ReadSignal<Option<String>>) -> impl IntoView {
+    let notice = move || {
+        let selected = model.get();
+        let info = models.get()?.ok()?.into_iter().find(|info| match &selected {
+            Some(id) => &info.id == id,
+            None => info.default,
+        })?;
+        match info.status {
+            ModelStatus::Loading => Some(format!("Jippity is loading {}, it can answer once that is done", info.id)),
+            ModelStatus::Failed(reason) => Some(format!("Jippity is unavailable, {} failed to load: {reason}", info.id)),
+            ModelStatus::Unloaded | ModelStatus::Ready => None,
+        }
+    };
+
+    view! {
+        <Suspense fallback=|| ()>
+            {move || notice().map(|text| view! { <p class="mb-3 text-sm text-amber-400">{text}</p> })}
+        </Suspense>
+    }
+}
+
+// The persona is picked once, when the conversation starts
+#[component]
+pub fn PersonaPicker(conversation: ReadSignal<Conversation>, set_persona: WriteSignal<Option<i64>>) -> impl IntoView {
+    let personas = create_resource(|| (), |_| list_personas());
+
+    view! {
+        <Suspense fallback=|| ()>
+            <select
+                class="mb-3 ml-2 p-2 rounded bg-zinc-700 border-zinc-700 text-white"
+                disabled=move || conversation.get().id.is_some()
+                on:change=move |ev| set_persona.set(event_target_value(&ev).parse().ok())
+            >
+                <option value="">"No persona"</option>
+                {move || personas.get().map(|list| {
+                    list.map(|list| list.personas).unwrap_or_default().into_iter().map(|persona| {
+                        view! { <option value=persona.id>{format!("Persona: {}", persona.name)}</option> }
+                    }).collect_view()
+                })}
+            </select>
+        </Suspense>
     }
 }
 
+// Plain text, any JSON object, or JSON matching a schema typed in by the user
 #[component]
-pub fn ChatArea(conversation: ReadSignal<Conversation>) -> impl IntoView {
+pub fn FormatPicker(set_format: WriteSignal<ResponseFormat>) -> impl IntoView {
+    let (kind, set_kind) = create_signal(String::from("text"));
+    let (schema, set_schema) = create_signal(String::new());
+    let (invalid, set_invalid) = create_signal(false);
+
+    create_effect(move |_| {
+        let format = match kind.get().as_str() {
+            "json_object" => ResponseFormat::JsonObject,
+            "json_schema" => match serde_json::from_str(&schema.get()) {
+                Ok(schema) => ResponseFormat::JsonSchema {
+                    json_schema: JsonSchemaFormat { name: String::new(), schema },
+                },
+                Err(_) => {
+                    set_invalid.set(true);
+                    set_format.set(ResponseFormat::JsonObject);
+                    return;
+                }
+            },
+            _ => ResponseFormat::Text,
+        };
+        set_invalid.set(false);
+        set_format.set(format);
+    });
+
+    view! {
+        <select
+            class="mb-3 ml-2 p-2 rounded bg-zinc-700 border-zinc-700 text-white"
+            on:change=move |ev| set_kind.set(event_target_value(&ev))
+        >
+            <option value="text">"Answer as text"</option>
+            <option value="json_object">"Answer as JSON"</option>
+            <option value="json_schema">"Answer as JSON schema"</option>
+        </select>
+        <Show when=move || kind.get() == "json_schema">
+            <textarea
+                class="block w-full mb-3 p-2 rounded bg-zinc-700 border-zinc-700 text-white font-mono text-sm"
+                rows="4"
+                placeholder=r#"{"type": "object", "properties": {"answer": {"type": "string"}}, "required": ["answer"]}"#
+                prop:value=schema
+                on:input=move |ev| set_schema.set(event_target_value(&ev))
+            ></textarea>
+            <Show when=move || invalid.get()>
+                <p class="mb-3 text-sm text-red-400">"The schema is not valid JSON, any JSON object is allowed until it is"</p>
+            </Show>
+        </Show>
+    }
+}
+
+#[component]
+pub fn HistorySearch() -> impl IntoView {
+    let search = create_server_action::<SearchHistory>();
+
+    view! {
+        <ActionForm action=search class="mb-3">
+            <input class="p-2 rounded bg-zinc-700 border-zinc-700 text-white" type="search" name="query" placeholder="Search my chats"/>
+            <button class="p-2 ml-2 rounded bg-zinc-700 text-white" type="submit">"Search"</button>
+        </ActionForm>
+        {move || search.value().get().map(|hits| match hits {
+            Ok(hits) if hits.is_empty() => view! { <p class="text-sm text-zinc-400">"Nothing found"</p> }.into_view(),
+            Ok(hits) => view! {
+                <ul class="mb-3 text-sm text-zinc-300">
+                    {hits.into_iter().map(|hit| {
+                        let who = if hit.message.from_llm { "Jippity" } else { "You" };
+                        view! {
+                            <li class="mb-1">
+                                <b>{hit.conversation_title}</b>
+                                {format!(" ({:.0}%) {who}: {}", hit.score * 100.0, hit.message.text)}
+                            </li>
+                        }
+                    }).collect_view()}
+                </ul>
+            }.into_view(),
+            Err(err) => view! { <p class="text-sm text-red-400">{err.to_string()}</p> }.into_view(),
+        })}
+    }
+}
+
+#[component]
+pub fn ContextMeter(context: ReadSignal<ContextUsage>) -> impl IntoView {
+    view! {
+        <Show when=move || { context.get().size > 0 }>
+            <p class="text-sm text-zinc-400">
+                {move || {
+                    let usage = context.get();
+                    let percent = usage.used * 100 / usage.size;
+                    format!("Context: {} / {} tokens ({percent}%)", usage.used, usage.size)
+                }}
+                {move || {
+                    let dropped = context.get().dropped_messages;
+                    (dropped > 0).then(|| format!(", {dropped} older messages left out"))
+                }}
+            </p>
+        </Show>
+    }
+}
+
+#[component]
+pub fn ChatArea(
+    conversation: ReadSignal<Conversation>,
+    set_conversation: WriteSignal<Conversation>,
+    send: Action<ChatInput, Result<(), ServerFnError>>,
+    generation: ReadSignal<Option<u64>>,
+) -> impl IntoView {
     let chat_div_ref = create_node_ref::<Div>();
 
     create_effect(move |_| {
@@ -187,30 +935,227 @@ pub fn ChatArea(conversation: ReadSignal<Conversation>) -> impl IntoView {
 
     view! {
           <div class="b-screen pb-24 w-full flex flex-col overflow-y-auto border border-gray-300 rounded p-5 border-zinc-700 bg-zinc-900" node_ref=chat_div_ref>
-          {move || conversation.get().messages.iter().map(move |message| {
+          {move || {
+            let conversation = conversation.get();
+            let conversation_id = conversation.id;
+            conversation.messages.iter().map(move |message| {
+              if let Some(tool) = &message.tool {
+                  return view! { <ToolBubble tool=tool.clone()/> }.into_view();
+              }
               let class_str = if !message.from_llm { format!("max-w-md p-4 mb-5 rounded-lg self-end bg-blue-500 text-white") }
               else { format!("max-w-md p-4 mb-5 rounded-lg self-start bg-zinc-700 text-white") };
+              let text = if message.from_llm {
+                  view! { <Markdown text=message.text.clone()/> }.into_view()
+              } else {
+                  view! { <div class="whitespace-pre-wrap">{message.text.clone()}</div> }.into_view()
+              };
               view! {
                 <div class={class_str}>
-                  {message.text.clone()}
+                  {text}
+                  <SourceList sources=message.sources.clone()/>
+                  <MessageControls message=message.clone() conversation_id set_conversation send generation/>
+                  <FeedbackControls message=message.clone() conversation_id set_conversation/>
                 </div>
-              }
+              }.into_view()
             }).collect::<Vec<_>>()
-          }
+          }}
         </div>
     }
 }
 
+// Switching between branches (< 2/3 >), regenerating an answer and editing a message.
+// Only for stored messages, and not while an answer is being generated.
 #[component]
-pub fn TypeArea(send: Action<String, Result<String, ServerFnError>>) -> impl IntoView {
+pub fn MessageControls(
+    message: Message,
+    conversation_id: Option<i64>,
+    set_conversation: WriteSignal<Conversation>,
+    send: Action<ChatInput, Result<(), ServerFnError>>,
+    generation: ReadSignal<Option<u64>>,
+) -> impl IntoView {
+    let (Some(id), Some(conversation_id)) = (message.id, conversation_id) else {
+        return ().into_view();
+    };
+    let (editing, set_editing) = create_signal(false);
+    let edit_ref = create_node_ref::<Textarea>();
+    let busy = move || generation.get().is_some();
+
+    let position = message.siblings.iter().position(|&sibling| sibling == id).unwrap_or(0);
+    let count = message.siblings.len();
+    let switch_to = move |sibling: Option<i64>| {
+        if let Some(sibling) = sibling {
+            spawn_local(async move {
+                if let Ok(stored) = switch_branch(conversation_id, sibling).await {
+                    set_conversation.set(stored);
+                }
+            });
+        }
+    };
+    let previous = position.checked_sub(1).and_then(|i| message.siblings.get(i).copied());
+    let next = message.siblings.get(position + 1).copied();
+
+    let from_llm = message.from_llm;
+    let seed = message.sampling.as_ref().and_then(|sampling| sampling.seed);
+    let replayable = message.replay_settings().is_some();
+    let seed_title = seed.map(|seed| format!("seed {seed}")).unwrap_or_default();
+    let text = message.text.clone();
+    view! {
+        <div class="mt-2 flex gap-2 text-xs text-zinc-300">
+            <Show when=move || (count > 1)>
+                <button disabled=move || busy() || previous.is_none() on:click=move |_| switch_to(previous)>"<"</button>
+                <span>{format!("{}/{count}", position + 1)}</span>
+                <button disabled=move || busy() || next.is_none() on:click=move |_| switch_to(next)>">"</button>
+            </Show>
+            <Show when=move || from_llm>
+                <button disabled=busy on:click=move |_| send.dispatch(ChatInput::Regenerate { message: id })>"Regenerate"</button>
+            </Show>
+            <Show when=move || replayable>
+                <button
+                    disabled=busy
+                    title=seed_title.clone()
+                    on:click=move |_| send.dispatch(ChatInput::Replay { message: id })
+                >"Replay with same seed"</button>
+            </Show>
+            <Show when=move || !from_llm && !editing.get()>
+                <button disabled=busy on:click=move |_| set_editing.set(true)>"Edit"</button>
+            </Show>
+        </div>
+        <Show when=move || editing.get()>
+            <textarea class="w-full mt-2 p-2 rounded text-black" rows="3" node_ref=edit_ref>{text.clone()}</textarea>
+            <div class="flex gap-2 text-xs">
+                <button disabled=busy on:click=move |_| {
+                    let text = edit_ref.get().expect("editor exists").value();
+                    set_editing.set(false);
+                    send.dispatch(ChatInput::Edit { message: id, text });
+                }>"Save and send"</button>
+                <button on:click=move |_| set_editing.set(false)>"Cancel"</button>
+            </div>
+        </Show>
+    }
+    .into_view()
+}
+
+// Thumbs up or down on a stored answer, with a comment once rated.
+// Clicking the active thumb again removes the rating.
+#[component]
+pub fn FeedbackControls(
+    message: Message,
+    conversation_id: Option<i64>,
+    set_conversation: WriteSignal<Conversation>,
+) -> impl IntoView {
+    let (Some(id), Some(conversation_id), true) = (message.id, conversation_id, message.from_llm) else {
+        return ().into_view();
+    };
+    let (feedback, set_feedback) = create_signal(message.feedback);
+    let (error, set_error) = create_signal(None::<String>);
+
+    let save = move |new: Option<Feedback>| {
+        set_feedback.set(new.clone());
+        // without re-rendering the chat, later renders pick it up
+        set_conversation.update_untracked(|conversation| {
+            if let Some(message) = conversation.messages.iter_mut().find(|message| message.id == Some(id)) {
+                message.feedback = new.clone();
+            }
+        });
+        spawn_local(async move {
+            let result = rate_message(conversation_id, id, new).await;
+            set_error.set(result.err().map(|err| err.to_string()));
+        });
+    };
+    let rate = move |rating: Rating| {
+        let current = feedback.get_untracked();
+        match current {
+            Some(current) if current.rating == rating => save(None),
+            current => {
+                let comment = current.map(|current| current.comment).unwrap_or_default();
+                save(Some(Feedback { rating, comment }))
+            }
+        }
+    };
+    let rated = move |rating: Rating| feedback.with(|feedback| feedback.as_ref().is_some_and(|f| f.rating == rating));
+    let comment = move || feedback.with(|feedback| feedback.as_ref().map(|f| f.comment.clone()).unwrap_or_default());
+
+    view! {
+        <div class="mt-1 flex gap-2 text-xs text-zinc-300">
+            <button title="Good answer" class:opacity-40=move || !rated(Rating::Up) on:click=move |_| rate(Rating::Up)>
+                "👍"
+            </button>
+            <button title="Bad answer" class:opacity-40=move || !rated(Rating::Down) on:click=move |_| rate(Rating::Down)>
+                "👎"
+            </button>
+            <Show when=move || feedback.with(Option::is_some)>
+                <input
+                    class="px-1 rounded text-black"
+                    type="text"
+                    placeholder="Add a comment"
+                    prop:value=comment
+                    on:change=move |ev| {
+                        if let Some(current) = feedback.get_untracked() {
+                            save(Some(Feedback { comment: event_target_value(&ev), ..current }));
+                        }
+                    }
+                />
+            </Show>
+            {move || error.get()}
+        </div>
+    }
+    .into_view()
+}
+
+#[component]
+pub fn ToolBubble(tool: ToolInvocation) -> impl IntoView {
+    view! {
+        <div class="max-w-md p-3 mb-5 rounded-lg self-start border border-amber-700 bg-zinc-800 text-amber-200 font-mono text-xs">
+            <div>{format!("tool: {}({})", tool.name, tool.arguments)}</div>
+            <div class="mt-1 text-zinc-300 whitespace-pre-wrap">{format!("= {}", tool.result)}</div>
+        </div>
+    }
+}
+
+const EXCERPT_CHARS: usize = 160;
+
+// The document chunks an answer cites, with the start of each excerpt
+#[component]
+pub fn SourceList(sources: Vec<Source>) -> impl IntoView {
+    (!sources.is_empty()).then(|| view! {
+        <ul class="mt-3 pt-2 border-t border-zinc-500 text-xs text-zinc-300">
+            {sources.into_iter().map(|source| {
+                let mut excerpt: String = source.excerpt.chars().take(EXCERPT_CHARS).collect();
+                if excerpt.len() < source.excerpt.len() {
+                    excerpt.push_str("...");
+                }
+                view! {
+                    <li class="mb-1" title=source.excerpt>
+                        <b>{format!("[{}] {}", source.index, source.document)}</b>
+                        ": "{excerpt}
+                    </li>
+                }
+            }).collect_view()}
+        </ul>
+    })
+}
+
+#[component]
+pub fn TypeArea(
+    send: Action<ChatInput, Result<(), ServerFnError>>,
+    generation: ReadSignal<Option<u64>>,
+) -> impl IntoView {
     let input_ref = create_node_ref::<Input>();
 
+    let stop = move |_| {
+        if let Some(id) = generation.get_untracked() {
+            spawn_local(async move {
+                let _ = cancel_generation(id).await;
+            });
+        }
+    };
+
     view! {
         <div class = "h-24 w-full fixed bottom-0 flex justify-center items-center p-5 border-t bg-zinc-900 border-zinc-700">
             <form on:submit = move |ev| {
                 ev.prevent_default();
                 let input = input_ref.get().expect("Input doesn't exist");
-                send.dispatch(input.value());
+                send.dispatch(ChatInput::Message(input.value()));
                 input.set_value("");
             }>
             <input class="w-2/3 p-4 border rounded-full input-field bg-zinc-700 border-zinc-700 text-white" type="text" placeholder="Enter your prompt" node_ref=input_ref/>
@@ -219,7 +1164,87 @@ pub fn TypeArea(send: Action<String, Result<String, ServerFnError>>) -> impl Int
                 <path stroke-linecap="round" stroke-linejoin="round" d="M4.5 12h15m0 0l-6.75-6.75M19.5 12l-6.75 6.75" />
                 </svg>
             </button>
+            <Show when=move || generation.get().is_some()>
+                <button class="h-full p-4 ml-2 rounded-full cursor-pointer bg-red-700 text-white" type="button" title="Stop generating" on:click=stop>
+                    <svg xmlns="http://www.w3.org/2000/svg" fill="currentColor" viewBox="0 0 24 24" class="w-6 h-6">
+                    <rect x="6" y="6" width="12" height="12" rx="1.5" />
+                    </svg>
+                </button>
+            </Show>
         </form>
     </div>
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(id: i64, message: Message) -> Message {
+        Message { id: Some(id), ..message }
+    }
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new();
+        conversation.messages = vec![
+            stored(1, Message::user("What is 2+2?")),
+            stored(2, Message::llm("")),
+            stored(3, Message::tool(ToolInvocation { name: "calculator".into(), arguments: "{}".into(), result: "4".into() })),
+            stored(4, Message::llm("4")),
+            stored(5, Message::user("And 3+3?")),
+            stored(6, Message::llm("6")),
+        ];
+        conversation
+    }
+
+    #[test]
+    fn regenerating_keeps_the_question() {
+        let mut conv = conversation();
+        assert!(conv.branch_for(&ChatInput::Regenerate { message: 4 }));
+        assert_eq!(conv.messages.len(), 1);
+
+        let mut conv = conversation();
+        assert!(conv.branch_for(&ChatInput::Regenerate { message: 6 }));
+        assert_eq!(conv.messages.last().unwrap().id, Some(5));
+
+        let mut conv = conversation();
+        assert!(conv.branch_for(&ChatInput::Replay { message: 6 }));
+        assert_eq!(conv.messages.len(), 5);
+    }
+
+    #[test]
+    fn editing_drops_the_edited_message() {
+        let mut conv = conversation();
+        assert!(conv.branch_for(&ChatInput::Edit { message: 5, text: "And 4+4?".into() }));
+        assert_eq!(conv.messages.len(), 4);
+
+        let mut conv = conversation();
+        assert!(conv.branch_for(&ChatInput::Edit { message: 1, text: "What is 1+1?".into() }));
+        assert!(conv.messages.is_empty());
+    }
+
+    #[test]
+    fn branching_needs_a_matching_message_on_the_path() {
+        let mut conv = conversation();
+        assert!(!conv.branch_for(&ChatInput::Edit { message: 6, text: "x".into() }));
+        assert!(!conv.branch_for(&ChatInput::Regenerate { message: 5 }));
+        assert!(!conv.branch_for(&ChatInput::Regenerate { message: 42 }));
+        assert_eq!(conv.messages.len(), 6);
+        assert!(conv.branch_for(&ChatInput::Message("hi".into())));
+    }
+
+    #[test]
+    fn replays_need_the_seed_model_and_format() {
+        let mut answer = Message::llm("{}");
+        answer.sampling = Some(SamplingSettings { seed: Some(7), ..Default::default() });
+        answer.model = Some("llama".to_string());
+        assert_eq!(answer.replay_settings(), None);
+
+        answer.format = Some(ResponseFormat::JsonObject);
+        let (sampling, model, format) = answer.replay_settings().unwrap();
+        assert_eq!((sampling.seed, model.as_str(), format), (Some(7), "llama", ResponseFormat::JsonObject));
+
+        answer.sampling = Some(SamplingSettings::default());
+        assert_eq!(answer.replay_settings(), None);
+    }
+}
@@ -4,7 +4,7 @@ use leptos_router::*;
 use crate::components::nav::Nav;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "ssr")]
-use crate::app::ssr::create_db_conn;
+use crate::app::ssr::{create_db_conn, new_session_token, session_token, SESSION_COOKIE, SESSION_MAX_AGE_SECS};
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
@@ -14,32 +14,54 @@ pub struct Login {
 }
 
 #[cfg(feature = "ssr")]
-pub async fn check_user_credentials(login: Login) -> Result<bool, ServerFnError> {
+pub async fn check_user_credentials(login: Login) -> Result<Option<i32>, ServerFnError> {
     let pool = create_db_conn().await?;
 
     // query user & pwd
-    let result: Option<(String,)> = sqlx::query_as(
-        "SELECT username FROM user_table WHERE username = $1 AND pwd = $2"
+    let result: Option<(i32,)> = sqlx::query_as(
+        "SELECT id FROM user_table WHERE username = $1 AND pwd = $2"
     )
     .bind(&login.username)
     .bind(&login.pwd)
     .fetch_optional(&pool)
     .await?;
 
-    if let Some(_) = result {
+    if let Some((id,)) = result {
         println!("User found in db");
-        Ok(true)
+        Ok(Some(id))
     } else {
         println!("User not found in db");
-        Ok(false)
+        Ok(None)
     }
 }
 
+#[cfg(feature = "ssr")]
+pub async fn start_session(user_id: i32) -> Result<(), ServerFnError> {
+    use http::{header::SET_COOKIE, HeaderValue};
+
+    let pool = create_db_conn().await?;
+    let token = new_session_token();
+    sqlx::query(
+        "INSERT INTO user_session (token, user_id, expires_at) VALUES ($1, $2, now() + make_interval(secs => $3))"
+    )
+    .bind(&token)
+    .bind(user_id)
+    .bind(SESSION_MAX_AGE_SECS as f64)
+    .execute(&pool)
+    .await?;
+
+    let cookie = format!("{SESSION_COOKIE}={token}; Path=/; Max-Age={SESSION_MAX_AGE_SECS}; HttpOnly; SameSite=Lax");
+    let response = expect_context::<leptos_axum::ResponseOptions>();
+    response.insert_header(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+    Ok(())
+}
+
 #[server(LoginAction, "/login")]
 pub async fn pass_login_input(login: Login) -> Result<(), ServerFnError> {
-    let user_exists = check_user_credentials(login.clone()).await?;
+    let user_id = check_user_credentials(login.clone()).await?;
     
-    if user_exists {
+    if let Some(user_id) = user_id {
+        start_session(user_id).await?;
         println!("Login successful for user: {}", login.username);
         Ok(())
     } else {
@@ -48,6 +70,27 @@ pub async fn pass_login_input(login: Login) -> Result<(), ServerFnError> {
     }
 }
 
+// Deletes the session of the request and its cookie
+#[server(Logout, "/login")]
+pub async fn logout() -> Result<(), ServerFnError> {
+    use http::{header::SET_COOKIE, HeaderMap, HeaderValue};
+
+    let headers = leptos_axum::extract::<HeaderMap>().await?;
+    if let Some(token) = session_token(&headers) {
+        let pool = create_db_conn().await?;
+        sqlx::query("DELETE FROM user_session WHERE token = $1")
+            .bind(&token)
+            .execute(&pool)
+            .await?;
+    }
+
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax");
+    let response = expect_context::<leptos_axum::ResponseOptions>();
+    response.insert_header(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+    leptos_axum::redirect("/login");
+    Ok(())
+}
+
 #[component]
 pub fn Login() -> impl IntoView {
     let login_action = create_server_action::<LoginAction>();
@@ -0,0 +1,306 @@
+// Markdown rendering for Jippity's answers. This runs during SSR and after hydration alike,
+// so it is a pure function of the text. Raw HTML from the model is shown as text, links
+// with other schemes than http(s) and mailto lose their target, and images become links.
+use leptos::*;
+use pulldown_cmark::{html::push_html, CodeBlockKind, CowStr, Event, LinkType, Options, Parser, Tag, TagEnd};
+
+const SAFE_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+#[component]
+pub fn Markdown(text: String) -> impl IntoView {
+    view! { <div class="markdown" inner_html=render_markdown(&text)></div> }
+}
+
+// Streaming needs no special care: an unclosed code fence renders as a code block
+// up to the end of the text, unclosed emphasis stays literal until it is closed.
+pub fn render_markdown(text: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut events = Vec::new();
+    // (language, text) of the code block being collected
+    let mut code: Option<(String, String)> = None;
+    // whether each open link or image is kept
+    let mut links = Vec::new();
+
+    for event in Parser::new_ext(text, options) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or("").to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((language, String::new()));
+            }
+            Event::Text(text) if code.is_some() => {
+                if let Some((_, body)) = code.as_mut() {
+                    body.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((language, body)) = code.take() {
+                    events.push(Event::Html(code_block(&language, &body).into()));
+                }
+            }
+            Event::Html(html) | Event::InlineHtml(html) => events.push(Event::Text(html)),
+            Event::Start(Tag::Link { link_type, dest_url, title, id }) => {
+                let safe = is_safe_url(&dest_url);
+                links.push(safe);
+                if safe {
+                    events.push(Event::Start(Tag::Link { link_type, dest_url, title, id }));
+                }
+            }
+            // remote images would be loaded by every reader, they are linked instead
+            Event::Start(Tag::Image { dest_url, title, id, .. }) => {
+                let safe = is_safe_url(&dest_url);
+                links.push(safe);
+                if safe {
+                    events.push(Event::Start(Tag::Link { link_type: LinkType::Inline, dest_url, title, id }));
+                }
+            }
+            Event::End(TagEnd::Link) | Event::End(TagEnd::Image) => {
+                if links.pop().unwrap_or(false) {
+                    events.push(Event::End(TagEnd::Link));
+                }
+            }
+            event => events.push(event),
+        }
+    }
+    // a fence still open at the end of a streamed answer
+    if let Some((language, body)) = code.take() {
+        events.push(Event::Html(code_block(&language, &body).into()));
+    }
+
+    let mut html = String::new();
+    push_html(&mut html, events.into_iter());
+    html
+}
+
+// Relative URLs and the schemes in SAFE_SCHEMES. Browsers ignore whitespace and
+// control characters in a scheme, so they are dropped before looking at it.
+fn is_safe_url(url: &CowStr) -> bool {
+    let url: String = url.chars().filter(|c| !c.is_whitespace() && !c.is_control()).collect();
+    match url.find([':', '/', '?', '#']) {
+        Some(i) if url[i..].starts_with(':') => SAFE_SCHEMES.contains(&url[..i].to_ascii_lowercase().as_str()),
+        _ => true,
+    }
+}
+
+fn code_block(language: &str, code: &str) -> String {
+    let language: String = language
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '#' | '.'))
+        .collect();
+    let class = if language.is_empty() { String::new() } else { format!(" class=\"language-{language}\"") };
+    format!(
+        "<div class=\"code-block\"><div class=\"code-header\"><span>{language}</span>\
+         <button type=\"button\" class=\"copy-code\" \
+         onclick=\"navigator.clipboard.writeText(this.parentElement.nextElementSibling.innerText)\">Copy</button>\
+         </div><pre><code{class}>{}</code></pre></div>",
+        highlight(&language, code)
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+struct Syntax {
+    keywords: &'static [&'static str],
+    line_comment: &'static str,
+    // Rust's 'a, as opposed to the char literals 'a' and '\n'
+    lifetimes: bool,
+}
+
+fn syntax(language: &str) -> Option<Syntax> {
+    let syntax = match language.to_ascii_lowercase().as_str() {
+        "rust" | "rs" => Syntax {
+            keywords: &[
+                "as", "async", "await", "break", "const", "continue", "crate", "else", "enum", "false", "fn", "for",
+                "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self",
+                "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+            ],
+            line_comment: "//",
+            lifetimes: true,
+        },
+        "python" | "py" => Syntax {
+            keywords: &[
+                "and", "as", "async", "await", "break", "class", "continue", "def", "elif", "else", "except",
+                "False", "finally", "for", "from", "if", "import", "in", "is", "lambda", "None", "not", "or",
+                "pass", "raise", "return", "True", "try", "while", "with", "yield",
+            ],
+            line_comment: "#",
+            lifetimes: false,
+        },
+        "javascript" | "js" | "typescript" | "ts" | "jsx" | "tsx" => Syntax {
+            keywords: &[
+                "async", "await", "break", "case", "catch", "class", "const", "continue", "default", "else",
+                "export", "extends", "false", "for", "function", "if", "import", "in", "instanceof", "interface",
+                "let", "new", "null", "of", "return", "switch", "this", "throw", "true", "try", "type", "typeof",
+                "undefined", "var", "while",
+            ],
+            line_comment: "//",
+            lifetimes: false,
+        },
+        "c" | "cpp" | "c++" | "java" | "go" | "csharp" | "cs" | "c#" => Syntax {
+            keywords: &[
+                "break", "case", "char", "class", "const", "continue", "default", "double", "else", "enum",
+                "false", "float", "for", "func", "if", "import", "int", "interface", "long", "new", "null",
+                "package", "private", "public", "return", "static", "struct", "switch", "this", "true", "void",
+                "while",
+            ],
+            line_comment: "//",
+            lifetimes: false,
+        },
+        "sh" | "bash" | "shell" | "zsh" => Syntax {
+            keywords: &[
+                "case", "do", "done", "echo", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+                "in", "local", "return", "then", "while",
+            ],
+            line_comment: "#",
+            lifetimes: false,
+        },
+        "sql" => Syntax {
+            keywords: &[
+                "and", "as", "by", "create", "delete", "from", "group", "insert", "into", "join", "left", "not",
+                "null", "on", "or", "order", "select", "set", "table", "update", "values", "where",
+            ],
+            line_comment: "--",
+            lifetimes: false,
+        },
+        "toml" | "yaml" | "yml" => Syntax { keywords: &["true", "false"], line_comment: "#", lifetimes: false },
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+fn is_lifetime(syntax: &Syntax, rest: &[char]) -> bool {
+    syntax.lifetimes && rest.get(1) != Some(&'\\') && rest.get(2) != Some(&'\'')
+}
+
+// A small lexer for keywords, strings, numbers and line comments. Languages it
+// doesn't know are only escaped.
+fn highlight(language: &str, code: &str) -> String {
+    let Some(syntax) = syntax(language) else {
+        return escape_html(code);
+    };
+    // SQL keywords are case insensitive
+    let fold_case = syntax.line_comment == "--";
+    let chars: Vec<char> = code.chars().collect();
+    let mut html = String::with_capacity(code.len());
+    let mut i = 0;
+
+    let span = |html: &mut String, class: &str, text: &[char]| {
+        let text: String = text.iter().collect();
+        html.push_str(&format!("<span class=\"hl-{class}\">{}</span>", escape_html(&text)));
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().take(syntax.line_comment.len()).collect();
+        if rest == syntax.line_comment {
+            let end = chars[i..].iter().position(|&c| c == '\n').map_or(chars.len(), |n| i + n);
+            span(&mut html, "comment", &chars[i..end]);
+            i = end;
+        } else if matches!(c, '"' | '\'' | '`') && !(c == '\'' && is_lifetime(&syntax, &chars[i..])) {
+            let mut end = i + 1;
+            while end < chars.len() && chars[end] != c && chars[end] != '\n' {
+                end += if chars[end] == '\\' { 2 } else { 1 };
+            }
+            let end = (end + 1).min(chars.len());
+            span(&mut html, "string", &chars[i..end]);
+            i = end;
+        } else if c.is_ascii_digit() {
+            let end = chars[i..].iter().position(|c| !(c.is_ascii_alphanumeric() || *c == '.' || *c == '_')).map_or(chars.len(), |n| i + n);
+            span(&mut html, "number", &chars[i..end]);
+            i = end;
+        } else if c.is_alphabetic() || c == '_' {
+            let end = chars[i..].iter().position(|c| !(c.is_alphanumeric() || *c == '_')).map_or(chars.len(), |n| i + n);
+            let word: String = chars[i..end].iter().collect();
+            let keyword = if fold_case {
+                syntax.keywords.contains(&word.to_ascii_lowercase().as_str())
+            } else {
+                syntax.keywords.contains(&word.as_str())
+            };
+            if keyword {
+                span(&mut html, "keyword", &chars[i..end]);
+            } else {
+                html.push_str(&escape_html(&word));
+            }
+            i = end;
+        } else {
+            html.push_str(&escape_html(&c.to_string()));
+            i += 1;
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_html_is_escaped() {
+        let html = render_markdown("Hi <script>alert(1)</script>\n\n<img src=x onerror=alert(1)>");
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("<img"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn unsafe_links_keep_only_their_text() {
+        let html = render_markdown("[click](javascript:alert(1)) [docs](https://example.com)");
+        assert!(!html.contains("javascript"));
+        assert!(html.contains("click"));
+        assert!(html.contains("<a href=\"https://example.com\">docs</a>"));
+
+        assert!(!is_safe_url(&" Java\tScript:alert(1)".into()));
+        assert!(!is_safe_url(&"data:text/html,x".into()));
+        assert!(is_safe_url(&"mailto:me@example.com".into()));
+        assert!(is_safe_url(&"/docs?page=a:b".into()));
+    }
+
+    #[test]
+    fn images_become_links() {
+        let html = render_markdown("![a cat](https://example.com/cat.png)");
+        assert!(!html.contains("<img"));
+        assert!(html.contains("<a href=\"https://example.com/cat.png\">a cat</a>"));
+    }
+
+    #[test]
+    fn code_blocks_are_highlighted_and_escaped() {
+        let html = render_markdown("```rust\nfn main() { let s = \"<b>\"; } // done\n```");
+        assert!(html.contains("<code class=\"language-rust\">"));
+        assert!(html.contains("<span class=\"hl-keyword\">fn</span>"));
+        assert!(html.contains("<span class=\"hl-string\">&quot;&lt;b&gt;&quot;</span>"));
+        assert!(html.contains("<span class=\"hl-comment\">// done"));
+        assert!(html.contains("class=\"copy-code\""));
+    }
+
+    #[test]
+    fn unfinished_stream_renders_open_fence_as_code() {
+        let html = render_markdown("Here you go:\n\n```python\ndef f(x):\n    return x *");
+        assert!(html.contains("<p>Here you go:</p>"));
+        assert!(html.contains("<span class=\"hl-keyword\">def</span>"));
+        assert!(html.ends_with("</code></pre></div>"));
+        // a half written emphasis stays literal
+        assert_eq!(render_markdown("so **important"), "<p>so **important</p>\n");
+    }
+
+    #[test]
+    fn renders_tables_and_lists() {
+        let html = render_markdown("| a | b |\n|---|---|\n| 1 | 2 |\n\n- one\n- two");
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<li>one</li>"));
+    }
+}
@@ -3,4 +3,12 @@ pub mod home;
 pub mod register;
 pub mod about;
 pub mod login;
-pub mod jippity;
\ No newline at end of file
+pub mod jippity;
+pub mod api_tokens;
+pub mod documents;
+pub mod personas;
+pub mod markdown;
+pub mod usage;
+pub mod admin;
+pub mod archive;
+pub mod share;
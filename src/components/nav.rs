@@ -1,9 +1,12 @@
+use crate::components::login::Logout;
 use leptos::*;
+use leptos_router::ActionForm;
 
 #[component]
 pub fn Nav() -> impl IntoView {
     let _logo_svg = include_str!("../../style/icons/logo.svg");
     let person_circle_svg = include_str!("../../style/icons/person-circle.svg");
+    let logout = create_server_action::<Logout>();
 
     view! {
         
@@ -26,7 +29,21 @@ pub fn Nav() -> impl IntoView {
             |
             <a href="/login">Login</a>
             |
+            <ActionForm action=logout class="logout">
+                <button type="submit">"Logout"</button>
+            </ActionForm>
+            |
             <a href="/jippity">Jippity</a>
+            |
+            <a href="/personas">Personas</a>
+            |
+            <a href="/documents">Documents</a>
+            |
+            <a href="/tokens">API tokens</a>
+            |
+            <a href="/archive">Archive</a>
+            |
+            <a href="/admin">Admin</a>
             | 
             <a href="/about">About</a>
             |
@@ -0,0 +1,211 @@
+use leptos::*;
+use leptos::server_fn::codec::Json;
+use crate::components::jippity::list_models;
+use crate::components::nav::Nav;
+use crate::inference::sampling::SamplingSettings;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use crate::app::ssr::{require_user, AppState};
+
+// A saved system prompt with the sampling settings and model a new chat starts with
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Persona {
+    // None until saved
+    pub id: Option<i64>,
+    pub name: String,
+    pub system_prompt: String,
+    // None keeps the configured default model
+    pub model: Option<String>,
+    pub sampling: SamplingSettings,
+    // offered to every user, only admins may publish
+    pub global: bool,
+    // global personas of other users can be used but not edited
+    #[serde(default)]
+    pub own: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PersonaList {
+    pub personas: Vec<Persona>,
+    pub can_publish: bool,
+}
+
+#[server(ListPersonas, "/personas")]
+pub async fn list_personas() -> Result<PersonaList, ServerFnError> {
+    let user = require_user().await?;
+    Ok(PersonaList {
+        personas: crate::personas::list_personas(user.id).await?,
+        can_publish: user.is_admin,
+    })
+}
+
+#[server(name = SavePersona, prefix = "/personas", input = Json)]
+pub async fn save_persona(persona: Persona) -> Result<i64, ServerFnError> {
+    use axum::Extension;
+
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let user = require_user().await?;
+    let invalid = |message: &str| Err(ServerFnError::ServerError(message.to_string()));
+
+    if persona.name.trim().is_empty() {
+        return invalid("The persona needs a name");
+    }
+    if persona.global && !user.is_admin {
+        return invalid("Only admins can publish global personas");
+    }
+    if let Some(model) = &persona.model {
+        state.config.model(model)?;
+    }
+    let sampling = &persona.sampling;
+    if [sampling.temperature, sampling.top_p, sampling.repeat_penalty].iter().flatten().any(|value| *value < 0.0) {
+        return invalid("Sampling settings can't be negative");
+    }
+
+    crate::personas::save_persona(user.id, &persona)
+        .await?
+        .ok_or_else(|| ServerFnError::ServerError("Persona not found".to_string()))
+}
+
+#[server(DeletePersona, "/personas")]
+pub async fn delete_persona(id: i64) -> Result<(), ServerFnError> {
+    let user = require_user().await?;
+    crate::personas::delete_persona(id, user.id).await
+}
+
+fn parse_optional<T: std::str::FromStr>(value: String) -> Option<T> {
+    value.trim().parse().ok()
+}
+
+fn show_optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+#[component]
+pub fn Personas() -> impl IntoView {
+    let editing = create_rw_signal(Persona::default());
+    let save = create_action(|persona: &Persona| {
+        let persona = persona.clone();
+        async move { save_persona(persona).await }
+    });
+    let delete_action = create_server_action::<DeletePersona>();
+    let personas = create_resource(
+        move || (save.version().get(), delete_action.version().get()),
+        |_| list_personas(),
+    );
+    let models = create_resource(|| (), |_| list_models());
+    let can_publish = move || personas.get().and_then(Result::ok).map_or(false, |list| list.can_publish);
+
+    create_effect(move |_| {
+        if let Some(Ok(_)) = save.value().get() {
+            editing.set(Persona::default());
+        }
+    });
+
+    view! {
+        <Nav />
+        <h2>"Personas"</h2>
+        <p>"A persona sets the system prompt, the sampling settings and the model a new Jippity chat starts with."</p>
+
+        <Transition fallback=move || view! { <p>"Loading..."</p> }>
+            {move || personas.get().map(|list| match list {
+                Ok(list) => view! {
+                    <ul>
+                        {list.personas.into_iter().map(|persona| {
+                            let label = if persona.global { format!("{} (global)", persona.name) } else { persona.name.clone() };
+                            let id = persona.id;
+                            let own = persona.own;
+                            view! {
+                                <li>
+                                    {label}" "
+                                    <Show when=move || own>
+                                        <button on:click={
+                                            let persona = persona.clone();
+                                            move |_| editing.set(persona.clone())
+                                        }>"Edit"</button>
+                                        <button on:click=move |_| {
+                                            if let Some(id) = id {
+                                                delete_action.dispatch(DeletePersona { id });
+                                            }
+                                        }>"Delete"</button>
+                                    </Show>
+                                </li>
+                            }
+                        }).collect_view()}
+                    </ul>
+                }.into_view(),
+                Err(err) => view! { <p>{err.to_string()}</p> }.into_view(),
+            })}
+        </Transition>
+
+        <h3>{move || if editing.get().id.is_some() { "Edit persona" } else { "New persona" }}</h3>
+        <form on:submit=move |ev| {
+            ev.prevent_default();
+            save.dispatch(editing.get_untracked());
+        }>
+            <label for="persona-name"><b>"Name"</b></label>
+            <input type="text" id="persona-name" required
+                prop:value=move || editing.get().name
+                on:input=move |ev| editing.update(|p| p.name = event_target_value(&ev))
+            />
+
+            <label for="persona-prompt"><b>"System prompt"</b></label>
+            <textarea id="persona-prompt" rows="5"
+                prop:value=move || editing.get().system_prompt
+                on:input=move |ev| editing.update(|p| p.system_prompt = event_target_value(&ev))
+            ></textarea>
+
+            <label for="persona-model"><b>"Model"</b></label>
+            <select id="persona-model" on:change=move |ev| {
+                let id = event_target_value(&ev);
+                editing.update(|p| p.model = (!id.is_empty()).then_some(id));
+            }>
+                <option value="" selected=move || editing.get().model.is_none()>"Default model"</option>
+                <Suspense fallback=|| ()>
+                    {move || models.get().map(|models| models.unwrap_or_default().into_iter().map(|info| {
+                        let id = info.id.clone();
+                        let selected = move || editing.get().model.as_deref() == Some(id.as_str());
+                        view! { <option value=info.id.clone() selected=selected>{info.id}</option> }
+                    }).collect_view())}
+                </Suspense>
+            </select>
+
+            <label for="persona-temperature"><b>"Temperature"</b></label>
+            <input type="number" step="0.05" min="0" id="persona-temperature" placeholder="default"
+                prop:value=move || show_optional(editing.get().sampling.temperature)
+                on:input=move |ev| editing.update(|p| p.sampling.temperature = parse_optional(event_target_value(&ev)))
+            />
+            <label for="persona-top-p"><b>"Top p"</b></label>
+            <input type="number" step="0.05" min="0" max="1" id="persona-top-p" placeholder="default"
+                prop:value=move || show_optional(editing.get().sampling.top_p)
+                on:input=move |ev| editing.update(|p| p.sampling.top_p = parse_optional(event_target_value(&ev)))
+            />
+            <label for="persona-top-k"><b>"Top k"</b></label>
+            <input type="number" step="1" min="1" id="persona-top-k" placeholder="default"
+                prop:value=move || show_optional(editing.get().sampling.top_k)
+                on:input=move |ev| editing.update(|p| p.sampling.top_k = parse_optional(event_target_value(&ev)))
+            />
+            <label for="persona-repeat-penalty"><b>"Repeat penalty"</b></label>
+            <input type="number" step="0.05" min="0" id="persona-repeat-penalty" placeholder="default"
+                prop:value=move || show_optional(editing.get().sampling.repeat_penalty)
+                on:input=move |ev| editing.update(|p| p.sampling.repeat_penalty = parse_optional(event_target_value(&ev)))
+            />
+
+            <Show when=can_publish>
+                <label>
+                    <input type="checkbox"
+                        prop:checked=move || editing.get().global
+                        on:change=move |ev| editing.update(|p| p.global = event_target_checked(&ev))
+                    />
+                    " Publish for everybody"
+                </label>
+            </Show>
+
+            <button type="submit">"Save"</button>
+            <button type="button" on:click=move |_| editing.set(Persona::default())>"New"</button>
+        </form>
+        {move || match save.value().get() {
+            Some(Err(err)) => err.to_string(),
+            _ => String::new(),
+        }}
+    }
+}
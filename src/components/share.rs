@@ -0,0 +1,231 @@
+use leptos::*;
+use leptos_meta::{Meta, Title};
+use leptos_router::use_params_map;
+use crate::components::jippity::{Conversation, Message, SourceList, ToolBubble};
+use crate::components::markdown::Markdown;
+use crate::components::nav::Nav;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "ssr")]
+use crate::app::ssr::require_user;
+
+// A link to a snapshot of a conversation, opened at /share/<token>
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub token: String,
+    pub conversation_id: i64,
+    pub title: String,
+    pub created_at: String,
+}
+
+impl ShareLink {
+    pub fn path(&self) -> String {
+        share_path(&self.token)
+    }
+}
+
+pub fn share_path(token: &str) -> String {
+    format!("/share/{token}")
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SharedConversation {
+    pub title: String,
+    pub shared_at: String,
+    pub messages: Vec<Message>,
+}
+
+const DESCRIPTION_CHARS: usize = 160;
+
+impl SharedConversation {
+    // the first question, for the description meta tag
+    pub fn description(&self) -> String {
+        let question = self.messages.iter().find(|message| !message.from_llm).map_or("", |message| message.text.trim());
+        let mut description: String = question.chars().take(DESCRIPTION_CHARS).collect();
+        if description.len() < question.len() {
+            description.push_str("...");
+        }
+        description
+    }
+}
+
+// What a snapshot keeps of a message: ids, branches and sampling settings stay private
+pub fn snapshot(messages: &[Message]) -> Vec<Message> {
+    messages
+        .iter()
+        .map(|message| Message {
+            text: message.text.clone(),
+            from_llm: message.from_llm,
+            sources: message.sources.clone(),
+            tool: message.tool.clone(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+// Shares the active path as it is now. Returns the token of the new link.
+#[server(ShareConversation, "/share")]
+pub async fn share_conversation(conversation: i64) -> Result<String, ServerFnError> {
+    use crate::history::load_conversation;
+    use crate::shares::create_share;
+
+    let user = require_user().await?;
+    let not_found = || ServerFnError::ServerError("Conversation not found".to_string());
+    let stored = load_conversation(conversation, user.id).await?.ok_or_else(not_found)?;
+    create_share(user.id, conversation, &snapshot(&stored.messages)).await?.ok_or_else(not_found)
+}
+
+#[server(ListShareLinks, "/share")]
+pub async fn list_share_links() -> Result<Vec<ShareLink>, ServerFnError> {
+    let user = require_user().await?;
+    crate::shares::list_shares(user.id).await
+}
+
+#[server(RevokeShareLink, "/share")]
+pub async fn revoke_share_link(token: String) -> Result<(), ServerFnError> {
+    let user = require_user().await?;
+    crate::shares::delete_share(&token, user.id).await
+}
+
+// No login needed, the token is the permission
+#[server(GetSharedConversation, "/share")]
+pub async fn get_shared_conversation(token: String) -> Result<SharedConversation, ServerFnError> {
+    crate::shares::load_share(&token)
+        .await?
+        .ok_or_else(|| ServerFnError::ServerError("This link doesn't exist or was revoked".to_string()))
+}
+
+// The read-only page behind a share link. The snapshot is loaded before the page is
+// sent, so the title and description tags are in the server rendered HTML.
+#[component]
+pub fn SharedChat() -> impl IntoView {
+    let params = use_params_map();
+    let token = move || params.with(|params| params.get("token").cloned().unwrap_or_default());
+    let shared = create_blocking_resource(token, get_shared_conversation);
+
+    view! {
+        <Nav />
+        <Suspense fallback=move || view! { <p>"Loading..."</p> }>
+            {move || shared.get().map(|shared| match shared {
+                Ok(shared) => {
+                    let description = shared.description();
+                    let SharedConversation { title, shared_at, messages } = shared;
+                    view! {
+                        <Title text=format!("{title} - Jippity")/>
+                        <Meta name="description" content=description.clone()/>
+                        <Meta property="og:title" content=title.clone()/>
+                        <Meta property="og:description" content=description/>
+                        <h2>{title}</h2>
+                        <p class="text-xs">{format!("Shared from Jippity on {shared_at}")}</p>
+                        <div class="w-full flex flex-col border border-gray-300 rounded p-5 border-zinc-700 bg-zinc-900">
+                            {messages.into_iter().map(|message| match message.tool {
+                                Some(tool) => view! { <ToolBubble tool/> }.into_view(),
+                                None if message.from_llm => view! {
+                                    <div class="max-w-md p-4 mb-5 rounded-lg self-start bg-zinc-700 text-white">
+                                        <Markdown text=message.text/>
+                                        <SourceList sources=message.sources/>
+                                    </div>
+                                }.into_view(),
+                                None => view! {
+                                    <div class="max-w-md p-4 mb-5 rounded-lg self-end bg-blue-500 text-white">
+                                        <div class="whitespace-pre-wrap">{message.text}</div>
+                                    </div>
+                                }.into_view(),
+                            }).collect_view()}
+                        </div>
+                    }.into_view()
+                }
+                Err(err) => view! {
+                    <Title text="Jippity"/>
+                    <p>{err.to_string()}</p>
+                }.into_view(),
+            })}
+        </Suspense>
+    }
+}
+
+// Shares the open conversation and shows the new link
+#[component]
+pub fn ShareButton(conversation: ReadSignal<Conversation>) -> impl IntoView {
+    let share = create_server_action::<ShareConversation>();
+    let id = move || conversation.with(|conversation| conversation.id);
+
+    view! {
+        <Show when=move || id().is_some()>
+            <button on:click=move |_| {
+                if let Some(conversation) = id() {
+                    share.dispatch(ShareConversation { conversation });
+                }
+            }>"Share"</button>
+        </Show>
+        {move || match share.value().get() {
+            Some(Ok(token)) => {
+                let path = share_path(&token);
+                view! { <span>" Read-only link: "<a href=path.clone()>{path}</a></span> }.into_view()
+            }
+            Some(Err(err)) => view! { <span>{err.to_string()}</span> }.into_view(),
+            None => ().into_view(),
+        }}
+    }
+}
+
+// The user's share links, `refresh` reloads the list after a new one was created
+#[component]
+pub fn ShareLinks(#[prop(into)] refresh: Signal<usize>) -> impl IntoView {
+    let revoke = create_server_action::<RevokeShareLink>();
+    let links = create_resource(move || (refresh.get(), revoke.version().get()), |_| list_share_links());
+
+    view! {
+        <h3>"Share links"</h3>
+        <Transition fallback=move || view! { <p>"Loading..."</p> }>
+            {move || links.get().map(|links| match links {
+                Ok(links) if links.is_empty() => view! { <p>"Nothing shared yet."</p> }.into_view(),
+                Ok(links) => view! {
+                    <ul>
+                        {links.into_iter().map(|link| {
+                            let path = link.path();
+                            let token = link.token.clone();
+                            view! {
+                                <li>
+                                    {link.title}" ("{link.created_at}") "<a href=path.clone()>{path}</a>" "
+                                    <button on:click=move |_| revoke.dispatch(RevokeShareLink { token: token.clone() })>
+                                        "Revoke"
+                                    </button>
+                                </li>
+                            }
+                        }).collect_view()}
+                    </ul>
+                }.into_view(),
+                Err(err) => view! { <p>{err.to_string()}</p> }.into_view(),
+            })}
+        </Transition>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_keep_only_what_is_shown() {
+        let mut answer = Message::llm("Hello");
+        answer.id = Some(2);
+        answer.siblings = vec![2, 5];
+        answer.sampling = Some(Default::default());
+        let shared = snapshot(&[Message::user("Hi"), answer]);
+        assert_eq!(shared, vec![Message::user("Hi"), Message::llm("Hello")]);
+    }
+
+    #[test]
+    fn descriptions_start_with_the_first_question() {
+        let question = "word ".repeat(50);
+        let shared = SharedConversation {
+            title: "Words".to_string(),
+            shared_at: "2024-01-01".to_string(),
+            messages: vec![Message::user(question.clone()), Message::llm("ok")],
+        };
+        let description = shared.description();
+        assert!(description.ends_with("..."));
+        assert_eq!(description.chars().count(), DESCRIPTION_CHARS + 3);
+        assert!(question.starts_with(description.trim_end_matches("...")));
+    }
+}
@@ -0,0 +1,97 @@
+use leptos::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Tokens (prompt plus completion) used in the current day or month
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Budget {
+    pub used: u64,
+    // None is unlimited
+    pub limit: Option<u64>,
+}
+
+impl Budget {
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|limit| limit.saturating_sub(self.used))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageInfo {
+    pub daily: Budget,
+    pub monthly: Budget,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("You have used up your {period} budget of {limit} tokens, it renews {renews}")]
+pub struct QuotaExceeded {
+    pub period: &'static str,
+    pub limit: u64,
+    pub renews: &'static str,
+}
+
+impl UsageInfo {
+    pub fn check(&self) -> Result<(), QuotaExceeded> {
+        for (budget, period, renews) in [(self.daily, "daily", "tomorrow"), (self.monthly, "monthly", "next month")] {
+            if let Some(limit) = budget.limit.filter(|&limit| budget.used >= limit) {
+                return Err(QuotaExceeded { period, limit, renews });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[server(GetUsage, "/usage")]
+pub async fn get_usage() -> Result<UsageInfo, ServerFnError> {
+    use crate::app::ssr::{require_user, AppState};
+    use axum::Extension;
+
+    let Extension(state) = leptos_axum::extract::<Extension<AppState>>().await?;
+    let user = require_user().await?;
+    crate::usage::usage_info(&state, user.id).await
+}
+
+fn describe(budget: Budget, period: &str) -> String {
+    match budget.remaining() {
+        Some(remaining) => format!("{remaining} of {} tokens left {period}", budget.limit.unwrap_or_default()),
+        None => format!("{} tokens used {period}", budget.used),
+    }
+}
+
+// The user's remaining budget, refetched whenever `version` changes
+#[component]
+pub fn UsageMeter(#[prop(into)] version: Signal<usize>) -> impl IntoView {
+    let usage = create_resource(move || version.get(), |_| get_usage());
+
+    view! {
+        <Suspense fallback=|| ()>
+            {move || usage.get().and_then(Result::ok).map(|usage| view! {
+                <p class="text-xs text-zinc-400">
+                    {describe(usage.daily, "today")}", "{describe(usage.monthly, "this month")}
+                </p>
+            })}
+        </Suspense>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exceeded_budgets_name_their_period() {
+        let mut usage = UsageInfo {
+            daily: Budget { used: 100, limit: Some(100) },
+            monthly: Budget { used: 100, limit: None },
+        };
+        assert_eq!(usage.check().unwrap_err().period, "daily");
+
+        usage.daily.limit = Some(1000);
+        usage.monthly.limit = Some(50);
+        assert_eq!(usage.check().unwrap_err().renews, "next month");
+
+        usage.monthly.limit = None;
+        assert!(usage.check().is_ok());
+        assert_eq!(usage.daily.remaining(), Some(900));
+    }
+}
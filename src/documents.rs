@@ -0,0 +1,153 @@
+// Uploaded documents, stored per user in collections. A document is split into chunks,
+// every chunk is embedded so the ones relevant to a question can be retrieved.
+use crate::app::ssr::create_db_conn;
+use crate::components::documents::{CollectionInfo, DocumentInfo};
+use crate::components::jippity::Source;
+use leptos::ServerFnError;
+
+pub struct StoredChunk {
+    pub id: i64,
+    pub document: String,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+pub async fn create_collection(user_id: i32, name: &str) -> Result<i64, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let (id,): (i64,) = sqlx::query_as("INSERT INTO collection (user_id, name) VALUES ($1, $2) RETURNING id")
+        .bind(user_id)
+        .bind(name)
+        .fetch_one(&pool)
+        .await?;
+    Ok(id)
+}
+
+pub async fn owns_collection(id: i64, user_id: i32) -> Result<bool, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let owned: Option<(i64,)> = sqlx::query_as("SELECT id FROM collection WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&pool)
+        .await?;
+    Ok(owned.is_some())
+}
+
+// The user's collections with their documents and how many chunks are embedded so far
+pub async fn list_collections(user_id: i32) -> Result<Vec<CollectionInfo>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let collections: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, name FROM collection WHERE user_id = $1 ORDER BY id")
+            .bind(user_id)
+            .fetch_all(&pool)
+            .await?;
+    let documents: Vec<(i64, i64, String, i32, i64)> = sqlx::query_as(
+        "SELECT d.collection_id, d.id, d.name, d.chunks, COUNT(c.id)
+         FROM document d
+         JOIN collection col ON col.id = d.collection_id
+         LEFT JOIN document_chunk c ON c.document_id = d.id
+         WHERE col.user_id = $1
+         GROUP BY d.id
+         ORDER BY d.id"
+    )
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(collections
+        .into_iter()
+        .map(|(id, name)| CollectionInfo {
+            id,
+            name,
+            documents: documents
+                .iter()
+                .filter(|(collection_id, ..)| *collection_id == id)
+                .map(|(_, id, name, chunks, indexed)| DocumentInfo {
+                    id: *id,
+                    name: name.clone(),
+                    chunks: *chunks as usize,
+                    indexed: *indexed as usize,
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+pub async fn create_document(collection_id: i64, name: &str, chunks: usize) -> Result<i64, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let (id,): (i64,) = sqlx::query_as(
+        "INSERT INTO document (collection_id, name, chunks) VALUES ($1, $2, $3) RETURNING id"
+    )
+    .bind(collection_id)
+    .bind(name)
+    .bind(chunks as i32)
+    .fetch_one(&pool)
+    .await?;
+    Ok(id)
+}
+
+pub async fn store_chunk(
+    document_id: i64,
+    position: usize,
+    text: &str,
+    model_id: &str,
+    embedding: &[f32],
+) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query(
+        "INSERT INTO document_chunk (document_id, position, text, model_id, embedding) VALUES ($1, $2, $3, $4, $5)"
+    )
+    .bind(document_id)
+    .bind(position as i32)
+    .bind(text)
+    .bind(model_id)
+    .bind(embedding)
+    .execute(&pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_document(id: i64, user_id: i32) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query(
+        "DELETE FROM document d USING collection c
+         WHERE d.id = $1 AND c.id = d.collection_id AND c.user_id = $2"
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(&pool)
+    .await?;
+    Ok(())
+}
+
+// Chunks of a collection embedded by `model_id`
+pub async fn collection_chunks(collection_id: i64, model_id: &str) -> Result<Vec<StoredChunk>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let rows: Vec<(i64, String, String, Vec<f32>)> = sqlx::query_as(
+        "SELECT c.id, d.name, c.text, c.embedding
+         FROM document_chunk c
+         JOIN document d ON d.id = c.document_id
+         WHERE d.collection_id = $1 AND c.model_id = $2"
+    )
+    .bind(collection_id)
+    .bind(model_id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, document, text, embedding)| StoredChunk { id, document, text, embedding })
+        .collect())
+}
+
+pub async fn store_sources(message_id: i64, sources: &[Source]) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    for source in sources {
+        sqlx::query("INSERT INTO message_source (message_id, chunk_id, rank) VALUES ($1, $2, $3)")
+            .bind(message_id)
+            .bind(source.chunk_id)
+            .bind(source.index as i32)
+            .execute(&pool)
+            .await?;
+    }
+    Ok(())
+}
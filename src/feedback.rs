@@ -0,0 +1,223 @@
+// Rated answers as an evaluation and fine-tuning dataset, one JSON line per record:
+// the conversation up to the question as `prompt`, a thumbs up answer as `chosen`
+// and a thumbs down answer to the same question as `rejected`.
+use crate::app::ssr::create_db_conn;
+use crate::components::jippity::Rating;
+use crate::history::{path_to, TreeNode};
+use chrono::NaiveDate;
+use leptos::ServerFnError;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PromptMessage {
+    pub role: &'static str,
+    pub content: String,
+    // the tool of a tool result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RatedAnswer {
+    pub conversation_id: i64,
+    // the message it answers
+    pub parent: Option<i64>,
+    pub model: String,
+    pub rating: Rating,
+    pub comment: Option<String>,
+    pub text: String,
+    pub prompt: Vec<PromptMessage>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DatasetRecord {
+    pub prompt: Vec<PromptMessage>,
+    pub chosen: Option<String>,
+    pub rejected: Option<String>,
+    pub model: String,
+    pub conversation_id: i64,
+    pub comments: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DatasetFilter {
+    pub model: Option<String>,
+    // both days included
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+// A day from the export form, None if the field was left empty
+pub fn parse_day(field: &str, value: &str) -> Result<Option<NaiveDate>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(Some)
+        .map_err(|_| format!("The {field} date \"{value}\" isn't a date like 2024-05-31"))
+}
+
+// Answers to the same question are paired up in the order they were given. An answer
+// without a counterpart gets a record of its own, with the other side null.
+pub fn dataset(answers: Vec<RatedAnswer>) -> Vec<DatasetRecord> {
+    let mut groups: Vec<(Vec<RatedAnswer>, Vec<RatedAnswer>)> = Vec::new();
+    let mut group_of = HashMap::new();
+    for answer in answers {
+        let group = *group_of.entry((answer.conversation_id, answer.parent)).or_insert_with(|| {
+            groups.push((Vec::new(), Vec::new()));
+            groups.len() - 1
+        });
+        match answer.rating {
+            Rating::Up => groups[group].0.push(answer),
+            Rating::Down => groups[group].1.push(answer),
+        }
+    }
+
+    let mut records = Vec::new();
+    for (chosen, rejected) in groups {
+        let (mut chosen, mut rejected) = (chosen.into_iter(), rejected.into_iter());
+        loop {
+            let (chosen, rejected) = match (chosen.next(), rejected.next()) {
+                (None, None) => break,
+                pair => pair,
+            };
+            let first = chosen.as_ref().or(rejected.as_ref()).expect("one side is set");
+            records.push(DatasetRecord {
+                prompt: first.prompt.clone(),
+                model: first.model.clone(),
+                conversation_id: first.conversation_id,
+                comments: chosen.iter().chain(&rejected).filter_map(|answer| answer.comment.clone()).collect(),
+                chosen: chosen.map(|answer| answer.text),
+                rejected: rejected.map(|answer| answer.text),
+            });
+        }
+    }
+    records
+}
+
+pub fn to_jsonl(records: &[DatasetRecord]) -> Result<String, serde_json::Error> {
+    let mut jsonl = String::new();
+    for record in records {
+        jsonl.push_str(&serde_json::to_string(record)?);
+        jsonl.push('\n');
+    }
+    Ok(jsonl)
+}
+
+// Every rated answer matching `filter`; conversations without a model use `default_model`
+pub async fn rated_answers(filter: &DatasetFilter, default_model: &str) -> Result<Vec<RatedAnswer>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let rated: Vec<(Option<i64>, i64, String, i16, Option<String>, String)> = sqlx::query_as(
+        "SELECT m.parent_id, m.conversation_id, COALESCE(c.model_id, $1), m.rating, m.feedback, m.text
+         FROM message m JOIN conversation c ON c.id = m.conversation_id
+         WHERE m.rating IS NOT NULL
+           AND ($2::VARCHAR IS NULL OR COALESCE(c.model_id, $1) = $2)
+           AND ($3::DATE IS NULL OR m.rated_at >= $3::DATE)
+           AND ($4::DATE IS NULL OR m.rated_at < $4::DATE + 1)
+         ORDER BY m.id"
+    )
+    .bind(default_model)
+    .bind(&filter.model)
+    .bind(filter.from.map(|day| day.to_string()))
+    .bind(filter.to.map(|day| day.to_string()))
+    .fetch_all(&pool)
+    .await?;
+
+    // the conversations around them, for the prompts
+    let mut conversation_ids: Vec<i64> = rated.iter().map(|row| row.1).collect();
+    conversation_ids.sort();
+    conversation_ids.dedup();
+    let messages: Vec<(i64, Option<i64>, String, bool, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT id, parent_id, text, from_llm, tool_name, tool_result
+         FROM message WHERE conversation_id = ANY($1) ORDER BY id"
+    )
+    .bind(&conversation_ids)
+    .fetch_all(&pool)
+    .await?;
+    let tree: Vec<TreeNode> = messages.iter().map(|message| (message.0, message.1)).collect();
+    let by_id: HashMap<i64, _> = messages.iter().map(|message| (message.0, message)).collect();
+    let prompt_message = |id: &i64| {
+        let (_, _, text, from_llm, tool_name, tool_result) = by_id[id];
+        match tool_name {
+            Some(name) => PromptMessage {
+                role: "tool",
+                content: tool_result.clone().unwrap_or_default(),
+                name: Some(name.clone()),
+            },
+            None => PromptMessage {
+                role: if *from_llm { "assistant" } else { "user" },
+                content: text.clone(),
+                name: None,
+            },
+        }
+    };
+
+    Ok(rated
+        .into_iter()
+        .filter_map(|(parent, conversation_id, model, rating, comment, text)| {
+            Some(RatedAnswer {
+                conversation_id,
+                parent,
+                model,
+                rating: Rating::from_score(rating)?,
+                comment,
+                text,
+                prompt: path_to(&tree, parent).iter().map(prompt_message).collect(),
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(parent: i64, rating: Rating, text: &str) -> RatedAnswer {
+        RatedAnswer {
+            conversation_id: 1,
+            parent: Some(parent),
+            model: "default".to_string(),
+            rating,
+            comment: None,
+            text: text.to_string(),
+            prompt: vec![PromptMessage { role: "user", content: format!("question {parent}"), name: None }],
+        }
+    }
+
+    #[test]
+    fn answers_to_the_same_question_are_paired() {
+        let mut good = answer(1, Rating::Up, "good");
+        good.comment = Some("clear".to_string());
+        let records = dataset(vec![answer(1, Rating::Down, "bad"), good, answer(3, Rating::Down, "worse")]);
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].chosen.as_deref(), Some("good"));
+        assert_eq!(records[0].rejected.as_deref(), Some("bad"));
+        assert_eq!(records[0].comments, vec!["clear"]);
+        assert_eq!(records[0].prompt[0].content, "question 1");
+        // nothing to compare with, the other side stays empty
+        assert_eq!(records[1].chosen, None);
+        assert_eq!(records[1].rejected.as_deref(), Some("worse"));
+    }
+
+    #[test]
+    fn export_days_are_checked() {
+        assert_eq!(parse_day("from", " "), Ok(None));
+        assert_eq!(parse_day("from", "2024-05-31"), Ok(NaiveDate::from_ymd_opt(2024, 5, 31)));
+        assert!(parse_day("to", "2024-02-30").is_err());
+        assert_eq!(parse_day("to", "31.05.2024").unwrap_err(), "The to date \"31.05.2024\" isn't a date like 2024-05-31");
+    }
+
+    #[test]
+    fn every_record_is_one_line() {
+        let records = dataset(vec![answer(1, Rating::Up, "two\nlines")]);
+        let jsonl = to_jsonl(&records).unwrap();
+        assert_eq!(jsonl.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(jsonl.trim_end()).unwrap();
+        assert_eq!(line["prompt"][0], serde_json::json!({"role": "user", "content": "question 1"}));
+        assert_eq!(line["chosen"], "two\nlines");
+        assert!(line["rejected"].is_null());
+    }
+}
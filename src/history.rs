@@ -0,0 +1,552 @@
+// Stored Jippity conversations. Every turn is written here, so the prompt
+// (and a dropped inference session) can always be rebuilt from the database.
+use crate::app::ssr::create_db_conn;
+use crate::components::archive::{ConversationSummary, ExportedConversation, ExportedMessage, Role};
+use crate::components::jippity::{Conversation, Feedback, Message, Rating, Source, ToolInvocation};
+use crate::inference::sampling::SamplingSettings;
+use leptos::ServerFnError;
+use std::collections::HashMap;
+
+const TITLE_LEN: usize = 60;
+
+// id, parent id, text, from_llm, the tool columns, the sampling columns, the feedback columns,
+// the model and the response format
+type MessageRow = (
+    i64,
+    Option<i64>,
+    String,
+    bool,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<f32>,
+    Option<f32>,
+    Option<i32>,
+    Option<f32>,
+    Option<i64>,
+    Option<i16>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+// the conversation id and the creation time in front of a MessageRow
+type ExportRow = (
+    i64,
+    String,
+    i64,
+    Option<i64>,
+    String,
+    bool,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<f32>,
+    Option<f32>,
+    Option<i32>,
+    Option<f32>,
+    Option<i64>,
+);
+
+// (id, parent id) of a message, the tree of a conversation is a list of these
+pub type TreeNode = (i64, Option<i64>);
+
+// The ids from the first message down to `leaf`
+pub fn path_to(tree: &[TreeNode], leaf: Option<i64>) -> Vec<i64> {
+    let mut path = Vec::new();
+    let mut next = leaf;
+    while let Some(id) = next {
+        let Some(&(_, parent)) = tree.iter().find(|(node, _)| *node == id) else {
+            break;
+        };
+        path.push(id);
+        next = parent;
+    }
+    path.reverse();
+    path
+}
+
+// The messages with the same parent as `id`, oldest first
+pub fn siblings(tree: &[TreeNode], id: i64) -> Vec<i64> {
+    let Some(&(_, parent)) = tree.iter().find(|(node, _)| *node == id) else {
+        return Vec::new();
+    };
+    tree.iter().filter(|(_, other)| *other == parent).map(|(node, _)| *node).collect()
+}
+
+// The end of the branch below `id`, following the newest reply at each step
+pub fn newest_leaf(tree: &[TreeNode], id: i64) -> i64 {
+    let mut leaf = id;
+    while let Some(&(child, _)) = tree.iter().rev().find(|(_, parent)| *parent == Some(leaf)) {
+        leaf = child;
+    }
+    leaf
+}
+
+pub async fn create_conversation(
+    user_id: i32,
+    first_message: &str,
+    model_id: &str,
+    persona_id: Option<i64>,
+) -> Result<i64, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let title: String = first_message.chars().take(TITLE_LEN).collect();
+
+    let (id,): (i64,) = sqlx::query_as(
+        "INSERT INTO conversation (user_id, title, model_id, persona_id) VALUES ($1, $2, $3, $4) RETURNING id"
+    )
+    .bind(user_id)
+    .bind(title.trim())
+    .bind(model_id)
+    .bind(persona_id)
+    .fetch_one(&pool)
+    .await?;
+
+    Ok(id)
+}
+
+// None if the conversation doesn't exist or belongs to somebody else
+pub async fn load_conversation(id: i64, user_id: i32) -> Result<Option<Conversation>, ServerFnError> {
+    let pool = create_db_conn().await?;
+
+    let owned: Option<(Option<String>, Option<i64>, Option<i64>, Option<i64>)> = sqlx::query_as(
+        "SELECT model_id, collection_id, persona_id, leaf_id FROM conversation WHERE id = $1 AND user_id = $2"
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&pool)
+    .await?;
+    let Some((model, collection, persona, leaf)) = owned else {
+        return Ok(None);
+    };
+
+    let mut rows: Vec<MessageRow> = sqlx::query_as(
+        "SELECT id, parent_id, text, from_llm, tool_name, tool_arguments, tool_result,
+                temperature, top_p, top_k, repeat_penalty, seed, rating, feedback, model_id, response_format
+         FROM message WHERE conversation_id = $1 ORDER BY id"
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await?;
+    let tree: Vec<TreeNode> = rows.iter().map(|row| (row.0, row.1)).collect();
+    let path = path_to(&tree, leaf);
+    rows.retain(|row| path.contains(&row.0));
+
+    let sources: Vec<(i64, i32, i64, String, String)> = sqlx::query_as(
+        "SELECT s.message_id, s.rank, c.id, d.name, c.text
+         FROM message_source s
+         JOIN message m ON m.id = s.message_id
+         JOIN document_chunk c ON c.id = s.chunk_id
+         JOIN document d ON d.id = c.document_id
+         WHERE m.conversation_id = $1
+         ORDER BY s.rank"
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Some(Conversation {
+        id: Some(id),
+        model,
+        collection,
+        persona,
+        messages: rows
+            .into_iter()
+            .map(|row| {
+                let (message_id, _, text, from_llm, tool_name, tool_arguments, tool_result) =
+                    (row.0, row.1, row.2, row.3, row.4, row.5, row.6);
+                // only answers have a seed, the settings are stored with it
+                let sampling = row.11.map(|seed| SamplingSettings {
+                    temperature: row.7,
+                    top_p: row.8,
+                    top_k: row.9.map(|k| k as usize),
+                    repeat_penalty: row.10,
+                    seed: Some(seed as u64),
+                });
+                Message {
+                    id: Some(message_id),
+                    text,
+                    from_llm,
+                    tool: tool_name.map(|name| ToolInvocation {
+                        name,
+                        arguments: tool_arguments.unwrap_or_default(),
+                        result: tool_result.unwrap_or_default(),
+                    }),
+                    sources: sources
+                        .iter()
+                        .filter(|(source_message, ..)| *source_message == message_id)
+                        .map(|(_, rank, chunk_id, document, excerpt)| Source {
+                            index: *rank as usize,
+                            chunk_id: *chunk_id,
+                            document: document.clone(),
+                            excerpt: excerpt.clone(),
+                        })
+                        .collect(),
+                    siblings: siblings(&tree, message_id),
+                    sampling,
+                    model: row.14,
+                    format: row.15.and_then(|format| serde_json::from_str(&format).ok()),
+                    feedback: row.12.and_then(Rating::from_score).map(|rating| Feedback {
+                        rating,
+                        comment: row.13.unwrap_or_default(),
+                    }),
+                }
+            })
+            .collect(),
+    }))
+}
+
+pub async fn set_conversation_model(id: i64, model_id: &str) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query("UPDATE conversation SET model_id = $2 WHERE id = $1")
+        .bind(id)
+        .bind(model_id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn set_conversation_collection(id: i64, collection_id: Option<i64>) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query("UPDATE conversation SET collection_id = $2 WHERE id = $1")
+        .bind(id)
+        .bind(collection_id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+// Moves the end of the active path, the next message is appended after `leaf`.
+// None starts a new first message.
+pub async fn set_leaf(conversation_id: i64, leaf: Option<i64>) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query("UPDATE conversation SET leaf_id = $2 WHERE id = $1")
+        .bind(conversation_id)
+        .bind(leaf)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+// (id, parent id) of every message of the conversation, oldest first
+pub async fn message_tree(conversation_id: i64) -> Result<Vec<TreeNode>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    Ok(sqlx::query_as("SELECT id, parent_id FROM message WHERE conversation_id = $1 ORDER BY id")
+        .bind(conversation_id)
+        .fetch_all(&pool)
+        .await?)
+}
+
+// Appends the message to the end of the active path and makes it the new end.
+// Returns the id of the stored message.
+pub async fn append_message(conversation_id: i64, message: &Message) -> Result<i64, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let tool = message.tool.as_ref();
+    let sampling = message.sampling.as_ref();
+    let (id,): (i64,) = sqlx::query_as(
+        "WITH inserted AS (
+             INSERT INTO message (conversation_id, parent_id, from_llm, text, tool_name, tool_arguments, tool_result,
+                                  temperature, top_p, top_k, repeat_penalty, seed, model_id, response_format)
+             SELECT id, leaf_id, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13 FROM conversation WHERE id = $1
+             RETURNING id
+         )
+         UPDATE conversation SET leaf_id = inserted.id FROM inserted WHERE conversation.id = $1
+         RETURNING inserted.id"
+    )
+    .bind(conversation_id)
+    .bind(message.from_llm)
+    .bind(&message.text)
+    .bind(tool.map(|tool| &tool.name))
+    .bind(tool.map(|tool| &tool.arguments))
+    .bind(tool.map(|tool| &tool.result))
+    .bind(sampling.and_then(|sampling| sampling.temperature))
+    .bind(sampling.and_then(|sampling| sampling.top_p))
+    .bind(sampling.and_then(|sampling| sampling.top_k).map(|k| k as i32))
+    .bind(sampling.and_then(|sampling| sampling.repeat_penalty))
+    .bind(sampling.and_then(|sampling| sampling.seed).map(|seed| seed as i64))
+    .bind(&message.model)
+    .bind(message.format.as_ref().map(serde_json::to_string).transpose()?)
+    .fetch_one(&pool)
+    .await?;
+    Ok(id)
+}
+
+// The user's conversations, newest first
+pub async fn list_conversations(user_id: i32) -> Result<Vec<ConversationSummary>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let rows: Vec<(i64, String, String, i64)> = sqlx::query_as(
+        "SELECT c.id, c.title, to_char(c.created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI'),
+                (SELECT count(*) FROM message m WHERE m.conversation_id = c.id)
+         FROM conversation c WHERE c.user_id = $1 ORDER BY c.id DESC"
+    )
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, title, created_at, messages)| ConversationSummary { id, title, created_at, messages: messages as usize })
+        .collect())
+}
+
+// The whole message tree of `conversation`, or of every conversation of the user with None.
+// Sources, the collection and the persona are left out, they only exist on this instance.
+pub async fn export_conversations(
+    user_id: i32,
+    conversation: Option<i64>,
+) -> Result<Vec<ExportedConversation>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let conversations: Vec<(i64, String, Option<String>, Option<i64>, String)> = sqlx::query_as(
+        "SELECT id, title, model_id, leaf_id, to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')
+         FROM conversation WHERE user_id = $1 AND ($2::BIGINT IS NULL OR id = $2) ORDER BY id"
+    )
+    .bind(user_id)
+    .bind(conversation)
+    .fetch_all(&pool)
+    .await?;
+    let rows: Vec<ExportRow> = sqlx::query_as(
+        "SELECT m.conversation_id, to_char(m.created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'),
+                m.id, m.parent_id, m.text, m.from_llm, m.tool_name, m.tool_arguments, m.tool_result,
+                m.temperature, m.top_p, m.top_k, m.repeat_penalty, m.seed
+         FROM message m JOIN conversation c ON c.id = m.conversation_id
+         WHERE c.user_id = $1 AND ($2::BIGINT IS NULL OR c.id = $2)
+         ORDER BY m.id"
+    )
+    .bind(user_id)
+    .bind(conversation)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(conversations
+        .into_iter()
+        .map(|(id, title, model, leaf, created_at)| {
+            // parents become positions in the exported list
+            let mut positions = HashMap::new();
+            let mut messages = Vec::new();
+            for row in rows.iter().filter(|row| row.0 == id) {
+                positions.insert(row.2, messages.len());
+                let tool = row.6.clone().map(|name| ToolInvocation {
+                    name,
+                    arguments: row.7.clone().unwrap_or_default(),
+                    result: row.8.clone().unwrap_or_default(),
+                });
+                messages.push(ExportedMessage {
+                    parent: row.3.and_then(|parent| positions.get(&parent).copied()),
+                    role: Role::new(row.5, tool.is_some()),
+                    text: row.4.clone(),
+                    created_at: Some(row.1.clone()),
+                    tool,
+                    sampling: row.13.map(|seed| SamplingSettings {
+                        temperature: row.9,
+                        top_p: row.10,
+                        top_k: row.11.map(|k| k as usize),
+                        repeat_penalty: row.12,
+                        seed: Some(seed as u64),
+                    }),
+                });
+            }
+            ExportedConversation {
+                title,
+                model,
+                created_at: Some(created_at),
+                leaf: leaf.and_then(|leaf| positions.get(&leaf).copied()),
+                messages,
+            }
+        })
+        .collect())
+}
+
+// Stores validated exports as new conversations of the user, each with the model to use.
+// Everything is imported in one transaction. Returns the new message ids, in the order of
+// the exported messages, per conversation.
+pub async fn import_conversations(
+    user_id: i32,
+    conversations: &[(&ExportedConversation, Option<&str>)],
+) -> Result<Vec<Vec<i64>>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let mut tx = pool.begin().await?;
+    let mut imported = Vec::new();
+
+    for (conversation, model_id) in conversations {
+        let title = match conversation.title.trim() {
+            "" => conversation.messages[0].text.chars().take(TITLE_LEN).collect(),
+            title => title.to_string(),
+        };
+        let (conversation_id,): (i64,) = sqlx::query_as(
+            "INSERT INTO conversation (user_id, title, model_id, created_at)
+             VALUES ($1, $2, $3, COALESCE($4::TIMESTAMPTZ, now())) RETURNING id"
+        )
+        .bind(user_id)
+        .bind(title.trim())
+        .bind(model_id)
+        .bind(&conversation.created_at)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let mut ids: Vec<i64> = Vec::new();
+        for message in &conversation.messages {
+            let tool = message.tool.as_ref();
+            let sampling = message.sampling.as_ref();
+            let (id,): (i64,) = sqlx::query_as(
+                "INSERT INTO message (conversation_id, parent_id, from_llm, text, tool_name, tool_arguments, tool_result,
+                                      temperature, top_p, top_k, repeat_penalty, seed, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, COALESCE($13::TIMESTAMPTZ, now()))
+                 RETURNING id"
+            )
+            .bind(conversation_id)
+            .bind(message.parent.map(|parent| ids[parent]))
+            .bind(message.role != Role::User)
+            .bind(&message.text)
+            .bind(tool.map(|tool| &tool.name))
+            .bind(tool.map(|tool| &tool.arguments))
+            .bind(tool.map(|tool| &tool.result))
+            .bind(sampling.and_then(|sampling| sampling.temperature))
+            .bind(sampling.and_then(|sampling| sampling.top_p))
+            .bind(sampling.and_then(|sampling| sampling.top_k).map(|k| k as i32))
+            .bind(sampling.and_then(|sampling| sampling.repeat_penalty))
+            .bind(sampling.and_then(|sampling| sampling.seed).map(|seed| seed as i64))
+            .bind(&message.created_at)
+            .fetch_one(&mut *tx)
+            .await?;
+            ids.push(id);
+        }
+
+        let leaf = conversation.leaf.map_or(ids.last(), |leaf| ids.get(leaf)).copied();
+        sqlx::query("UPDATE conversation SET leaf_id = $2 WHERE id = $1")
+            .bind(conversation_id)
+            .bind(leaf)
+            .execute(&mut *tx)
+            .await?;
+        imported.push(ids);
+    }
+
+    tx.commit().await?;
+    Ok(imported)
+}
+
+// Rates an answer in a conversation of the user, None removes the rating.
+// False if there is no such answer.
+pub async fn set_feedback(
+    user_id: i32,
+    conversation_id: i64,
+    message_id: i64,
+    feedback: Option<&Feedback>,
+) -> Result<bool, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let comment = feedback.map(|feedback| feedback.comment.trim()).filter(|comment| !comment.is_empty());
+    let updated = sqlx::query(
+        "UPDATE message m SET rating = $4, feedback = $5, rated_at = CASE WHEN $4 IS NULL THEN NULL ELSE now() END
+         FROM conversation c
+         WHERE m.id = $3 AND m.conversation_id = c.id AND c.id = $2 AND c.user_id = $1
+           AND m.from_llm AND m.tool_name IS NULL"
+    )
+    .bind(user_id)
+    .bind(conversation_id)
+    .bind(message_id)
+    .bind(feedback.map(|feedback| feedback.rating.score()))
+    .bind(comment)
+    .execute(&pool)
+    .await?
+    .rows_affected();
+    Ok(updated > 0)
+}
+
+pub async fn store_embedding(message_id: i64, model_id: &str, embedding: &[f32]) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query(
+        "INSERT INTO message_embedding (message_id, model_id, embedding) VALUES ($1, $2, $3)
+         ON CONFLICT (message_id) DO UPDATE SET model_id = $2, embedding = $3"
+    )
+    .bind(message_id)
+    .bind(model_id)
+    .bind(embedding)
+    .execute(&pool)
+    .await?;
+    Ok(())
+}
+
+pub struct EmbeddedMessage {
+    pub conversation_id: i64,
+    pub title: String,
+    pub message: Message,
+    pub embedding: Vec<f32>,
+}
+
+// Every message of the user embedded by `model_id`, embeddings of other models don't compare
+pub async fn embedded_messages(user_id: i32, model_id: &str) -> Result<Vec<EmbeddedMessage>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let rows: Vec<(i64, String, String, bool, Vec<f32>)> = sqlx::query_as(
+        "SELECT c.id, c.title, m.text, m.from_llm, e.embedding
+         FROM message_embedding e
+         JOIN message m ON m.id = e.message_id
+         JOIN conversation c ON c.id = m.conversation_id
+         WHERE c.user_id = $1 AND e.model_id = $2"
+    )
+    .bind(user_id)
+    .bind(model_id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(conversation_id, title, text, from_llm, embedding)| EmbeddedMessage {
+            conversation_id,
+            title,
+            message: if from_llm { Message::llm(text) } else { Message::user(text) },
+            embedding,
+        })
+        .collect())
+}
+
+// Messages of the user's conversations containing `text`, newest first, with the conversation title
+pub async fn find_messages(user_id: i32, text: &str, limit: usize) -> Result<Vec<(String, Message)>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+    let rows: Vec<(String, String, bool)> = sqlx::query_as(
+        "SELECT c.title, m.text, m.from_llm
+         FROM message m
+         JOIN conversation c ON c.id = m.conversation_id
+         WHERE c.user_id = $1 AND m.tool_name IS NULL AND m.text ILIKE $2
+         ORDER BY m.id DESC
+         LIMIT $3"
+    )
+    .bind(user_id)
+    .bind(pattern)
+    .bind(limit as i64)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(title, text, from_llm)| (title, if from_llm { Message::llm(text) } else { Message::user(text) }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 -> 2 -> 3, 2 regenerated as 4 -> 5, 1 edited as 6
+    const TREE: [TreeNode; 6] = [(1, None), (2, Some(1)), (3, Some(2)), (4, Some(1)), (5, Some(4)), (6, None)];
+
+    #[test]
+    fn path_follows_parents_from_the_leaf() {
+        assert_eq!(path_to(&TREE, Some(5)), vec![1, 4, 5]);
+        assert_eq!(path_to(&TREE, Some(3)), vec![1, 2, 3]);
+        assert!(path_to(&TREE, None).is_empty());
+    }
+
+    #[test]
+    fn siblings_share_a_parent() {
+        assert_eq!(siblings(&TREE, 4), vec![2, 4]);
+        assert_eq!(siblings(&TREE, 1), vec![1, 6]);
+        assert_eq!(siblings(&TREE, 5), vec![5]);
+    }
+
+    #[test]
+    fn switching_branches_ends_at_the_newest_leaf() {
+        assert_eq!(newest_leaf(&TREE, 1), 5);
+        assert_eq!(newest_leaf(&TREE, 2), 3);
+        assert_eq!(newest_leaf(&TREE, 6), 6);
+    }
+}
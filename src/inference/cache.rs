@@ -0,0 +1,248 @@
+// Answers to deterministic requests (temperature 0 or a fixed seed), so a repeated prompt
+// doesn't run the model again. The key is a SHA-256 over everything that decides the
+// output. Memory is the first tier, the response_cache table the optional second one.
+use crate::app::ssr::create_db_conn;
+use crate::inference::config::CacheConfig;
+use crate::inference::engine::{FinishReason, GenerationJob, GenerationOutput};
+use crate::inference::grammar::ResponseFormat;
+use crate::inference::sampling::SamplingSettings;
+use leptos::ServerFnError;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How a generation uses the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    // a stored answer is returned instead of running the model
+    Read,
+    // the model runs anyway and its answer replaces the stored one, for replays
+    // that are meant to reproduce the generation
+    Refresh,
+}
+
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    tick: u64,
+    answers: HashMap<String, CacheEntry>,
+}
+
+struct CacheEntry {
+    last_used: u64,
+    stored: Instant,
+    model_id: String,
+    output: GenerationOutput,
+}
+
+// what goes into the key, serialized as JSON before hashing
+#[derive(Serialize)]
+struct KeyParts<'a> {
+    model_id: &'a str,
+    prompt: &'a str,
+    sampling: &'a SamplingSettings,
+    stop_sequences: &'a [String],
+    max_tokens: usize,
+    tool_calls: bool,
+    format: &'a ResponseFormat,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> ResponseCache {
+        ResponseCache { config, entries: Mutex::new(CacheEntries::default()) }
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.config.ttl_secs)
+    }
+
+    // None if the answer to `job` is random, it isn't cached then
+    pub fn key(&self, job: &GenerationJob) -> Option<String> {
+        let sampling = &job.sampling;
+        let deterministic = sampling.seed.is_some() || sampling.temperature.is_some_and(|temperature| temperature <= 0.0);
+        if !self.config.enabled || !deterministic {
+            return None;
+        }
+        let parts = KeyParts {
+            model_id: &job.model_id,
+            prompt: &job.prompt,
+            sampling,
+            stop_sequences: &job.stop_sequences,
+            max_tokens: job.max_tokens,
+            tool_calls: job.tool_calls,
+            format: &job.format,
+        };
+        let json = serde_json::to_vec(&parts).ok()?;
+        Some(format!("{:x}", Sha256::digest(json)))
+    }
+
+    pub async fn get(&self, key: &str, mode: CacheMode) -> Option<GenerationOutput> {
+        if mode == CacheMode::Refresh {
+            return None;
+        }
+        if let Some(output) = self.get_memory(key) {
+            return Some(output);
+        }
+        if !self.config.postgres {
+            return None;
+        }
+        match self.load(key).await {
+            Ok(Some((model_id, age, output))) => {
+                // the entry keeps the age it has in the table
+                let stored = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                self.insert(key.to_string(), model_id, stored, output.clone());
+                Some(output)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                eprintln!("Could not read the response cache: {err}");
+                None
+            }
+        }
+    }
+
+    // Cancelled generations are not stored, their text is cut off
+    pub async fn put(&self, key: String, model_id: String, output: GenerationOutput) {
+        if output.finish_reason == FinishReason::Cancelled {
+            return;
+        }
+        self.insert(key.clone(), model_id.clone(), Instant::now(), output.clone());
+        if self.config.postgres {
+            if let Err(err) = store(&key, &model_id, &output, self.config.ttl_secs).await {
+                eprintln!("Could not store a response in the cache: {err}");
+            }
+        }
+    }
+
+    // Drops the answers of `model_id`, or all of them. Returns how many were dropped.
+    pub async fn clear(&self, model_id: Option<&str>) -> Result<u64, ServerFnError> {
+        let dropped = {
+            let mut entries = self.entries.lock().unwrap();
+            let before = entries.answers.len();
+            entries.answers.retain(|_, entry| model_id.is_some_and(|id| entry.model_id != id));
+            (before - entries.answers.len()) as u64
+        };
+        if !self.config.postgres {
+            return Ok(dropped);
+        }
+        let pool = create_db_conn().await?;
+        let deleted = sqlx::query("DELETE FROM response_cache WHERE $1::VARCHAR IS NULL OR model_id = $1")
+            .bind(model_id)
+            .execute(&pool)
+            .await?
+            .rows_affected();
+        // the memory tier mostly holds copies of rows
+        Ok(deleted.max(dropped))
+    }
+
+    pub fn memory_entries(&self) -> usize {
+        self.entries.lock().unwrap().answers.len()
+    }
+
+    fn get_memory(&self, key: &str) -> Option<GenerationOutput> {
+        let ttl = self.ttl();
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        match entries.answers.get_mut(key) {
+            Some(entry) if entry.stored.elapsed() < ttl => {
+                entry.last_used = tick;
+                Some(entry.output.clone())
+            }
+            Some(_) => {
+                entries.answers.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: String, model_id: String, stored: Instant, output: GenerationOutput) {
+        if self.config.memory_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let last_used = entries.tick;
+        entries.answers.insert(key, CacheEntry { last_used, stored, model_id, output });
+
+        while entries.answers.len() > self.config.memory_entries {
+            let oldest = entries
+                .answers
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            entries.answers.remove(&oldest);
+        }
+    }
+
+    async fn load(&self, key: &str) -> Result<Option<(String, Duration, GenerationOutput)>, ServerFnError> {
+        let pool = create_db_conn().await?;
+        let row: Option<(String, f64, String)> = sqlx::query_as(
+            "SELECT model_id, EXTRACT(EPOCH FROM now() - created_at)::FLOAT8, response
+             FROM response_cache WHERE key = $1 AND created_at > now() - make_interval(secs => $2)"
+        )
+        .bind(key)
+        .bind(self.config.ttl_secs as f64)
+        .fetch_optional(&pool)
+        .await?;
+        let Some((model_id, age, response)) = row else {
+            return Ok(None);
+        };
+        let output = serde_json::from_str(&response)?;
+        Ok(Some((model_id, Duration::from_secs_f64(age.max(0.0)), output)))
+    }
+}
+
+// Expired rows are swept out on the way
+async fn store(key: &str, model_id: &str, output: &GenerationOutput, ttl_secs: u64) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query("DELETE FROM response_cache WHERE created_at < now() - make_interval(secs => $1)")
+        .bind(ttl_secs as f64)
+        .execute(&pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO response_cache (key, model_id, response) VALUES ($1, $2, $3)
+         ON CONFLICT (key) DO UPDATE SET response = $3, created_at = now()"
+    )
+    .bind(key)
+    .bind(model_id)
+    .bind(serde_json::to_string(output)?)
+    .execute(&pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(text: &str) -> GenerationOutput {
+        GenerationOutput {
+            text: text.to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 2,
+            finish_reason: FinishReason::Stop,
+            tool_call: None,
+            seed: 42,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_run_the_model_and_refresh_the_answer() {
+        let cache = ResponseCache::new(CacheConfig::default());
+        cache.put("key".to_string(), "default".to_string(), output("first")).await;
+        assert_eq!(cache.get("key", CacheMode::Read).await.unwrap().text, "first");
+        assert!(cache.get("key", CacheMode::Refresh).await.is_none());
+
+        cache.put("key".to_string(), "default".to_string(), output("replayed")).await;
+        assert_eq!(cache.get("key", CacheMode::Read).await.unwrap().text, "replayed");
+    }
+}
@@ -0,0 +1,471 @@
+use crate::inference::moderation::{ModerationAction, Moderator, PiiKind, Stage};
+use crate::inference::prompt::PromptTemplate;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use thiserror::Error;
+
+const DEFAULT_CONFIG_PATH: &str = "jippity.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Could not read config file {path:?}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("Could not parse config file {path:?}: {source}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("Unknown prompt template: {0}")]
+    UnknownTemplate(String),
+    #[error("Model {0}: the context size leaves no room for the prompt")]
+    ContextTooSmall(String),
+    #[error("Model {id}: {reason}")]
+    InvalidModel { id: String, reason: String },
+    #[error("Model {0} is declared more than once")]
+    DuplicateModel(String),
+    #[error("Unknown model: {0}")]
+    UnknownModel(String),
+    #[error("Queue limits must be at least 1")]
+    InvalidQueueLimits,
+    #[error("Retrieval needs top_k >= 1 and a chunk_overlap smaller than chunk_chars")]
+    InvalidRetrieval,
+    #[error("Moderation: {0}")]
+    InvalidModeration(String),
+}
+
+// Everything Jippity reads from jippity.toml (or the file named by JIPPITY_CONFIG).
+// A missing default file is fine, every section falls back to its defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct JippityConfig {
+    // id of the model new conversations use, the first declared one if unset
+    pub default_model: Option<String>,
+    pub models: Vec<ModelConfig>,
+    pub registry: RegistryConfig,
+    pub queue: QueueConfig,
+    pub sessions: SessionConfig,
+    pub embeddings: EmbeddingConfig,
+    pub retrieval: RetrievalConfig,
+    pub tools: ToolConfig,
+    pub quotas: QuotaConfig,
+    pub cache: CacheConfig,
+    pub moderation: ModerationConfig,
+    // user defined templates, looked up before the builtin ones
+    pub templates: HashMap<String, PromptTemplate>,
+}
+
+// One [[models]] entry. The default and embedding models are loaded at startup, the others on first use.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ModelConfig {
+    pub id: String,
+    // anything llm::ModelArchitecture parses: llama, gptneox, gpt2, gptj, bloom, mpt, falcon
+    pub architecture: String,
+    pub path: PathBuf,
+    pub template: String,
+    pub system_prompt: String,
+    pub context_size: usize,
+    // tokens kept free for the answer, the prompt gets the rest of the context
+    pub max_response_tokens: usize,
+    // KV cache size of one context slot, 2 * layers * embedding size * 2 bytes for f16
+    pub kv_bytes_per_token: usize,
+    // memory the loaded model takes, the file size if unset
+    pub memory_mb: Option<usize>,
+    // off by default, so the server also runs on machines without a GPU
+    pub use_gpu: bool,
+    // layers offloaded to the GPU, all of them if unset
+    pub gpu_layers: Option<usize>,
+    pub prefer_mmap: bool,
+    // LoRA adapters applied on top of the model, in order
+    pub lora_adapters: Vec<PathBuf>,
+    // RoPE scaling for models with an extended context, llm's defaults if unset
+    pub rope_frequency_base: Option<usize>,
+    pub rope_frequency_scale: Option<f32>,
+    // grouped-query attention factor, 8 for Llama 2 70B
+    pub n_gqa: Option<usize>,
+    // threads per inference session, llm's default if unset
+    pub threads: Option<usize>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            id: "default".to_string(),
+            architecture: "llama".to_string(),
+            path: PathBuf::new(),
+            template: "plain".to_string(),
+            system_prompt: "You are Jippity, a helpful assistant.".to_string(),
+            context_size: 2048,
+            max_response_tokens: 512,
+            // 7B Llama: 2 * 32 * 4096 * 2
+            kv_bytes_per_token: 524_288,
+            memory_mb: None,
+            use_gpu: false,
+            gpu_layers: None,
+            prefer_mmap: true,
+            lora_adapters: Vec::new(),
+            rope_frequency_base: None,
+            rope_frequency_scale: None,
+            n_gqa: None,
+            threads: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RegistryConfig {
+    // least recently used models are unloaded to stay below this
+    pub memory_limit_mb: usize,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig { memory_limit_mb: 16_384 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    // generations running on the model at the same time
+    pub max_concurrent: usize,
+    pub max_per_user: usize,
+    // further messages of a user are rejected instead of queued
+    pub max_waiting_per_user: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_concurrent: 2,
+            max_per_user: 1,
+            max_waiting_per_user: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    // memory for inference sessions kept alive between the turns of a conversation
+    pub memory_budget_mb: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig { memory_budget_mb: 2048 }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    // model used to embed messages for the history search, the default model if unset
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetrievalConfig {
+    // chunks of the attached collection inserted into the prompt per answer
+    pub top_k: usize,
+    // uploaded documents are split into chunks of about this many characters,
+    // neighbouring chunks share `chunk_overlap` characters
+    pub chunk_chars: usize,
+    pub chunk_overlap: usize,
+    pub max_upload_mb: usize,
+}
+
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        RetrievalConfig {
+            top_k: 4,
+            chunk_chars: 1200,
+            chunk_overlap: 200,
+            max_upload_mb: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ToolConfig {
+    // let Jippity call the builtin tools (calculator, current time, conversation search)
+    pub enabled: bool,
+    // tool calls within one answer, after that the answer has to do without
+    pub max_calls_per_answer: usize,
+}
+
+impl Default for ToolConfig {
+    fn default() -> Self {
+        ToolConfig { enabled: true, max_calls_per_answer: 3 }
+    }
+}
+
+// Answers to deterministic requests (temperature 0 or a fixed seed), keyed on everything
+// that goes into the generation
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    // answers kept in memory, least recently used ones are dropped
+    pub memory_entries: usize,
+    // also keep answers in the response_cache table, so they survive a restart
+    pub postgres: bool,
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { enabled: true, memory_entries: 1000, postgres: false, ttl_secs: 86_400 }
+    }
+}
+
+// Checks on the user's message before inference and on the answer after it, off by default.
+// The blocklist runs first, then the regex rules and the PII detectors in their order.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    pub enabled: bool,
+    pub blocklist: BlocklistConfig,
+    pub rules: Vec<RegexRuleConfig>,
+    pub pii: Vec<PiiRuleConfig>,
+    pub classifier: ClassifierConfig,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        let redact = |kind| PiiRuleConfig { kind, action: ModerationAction::Redact, stages: Stage::both() };
+        ModerationConfig {
+            enabled: false,
+            blocklist: BlocklistConfig::default(),
+            rules: Vec::new(),
+            pii: vec![redact(PiiKind::Email), redact(PiiKind::Iban), redact(PiiKind::Phone)],
+            classifier: ClassifierConfig::default(),
+        }
+    }
+}
+
+// Words and phrases matched case-insensitively as whole words
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BlocklistConfig {
+    pub words: Vec<String>,
+    pub action: ModerationAction,
+    pub stages: Vec<Stage>,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig { words: Vec::new(), action: ModerationAction::Block, stages: Stage::both() }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegexRuleConfig {
+    // shown in the audit log and in redactions, "[<name> removed]"
+    pub name: String,
+    // regex crate syntax, (?i) for case-insensitive
+    pub pattern: String,
+    pub action: ModerationAction,
+    #[serde(default = "Stage::both")]
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PiiRuleConfig {
+    pub kind: PiiKind,
+    pub action: ModerationAction,
+    #[serde(default = "Stage::both")]
+    pub stages: Vec<Stage>,
+}
+
+// Asks a local model whether the text is unsafe; `prompt` gets the text in place of {text}
+// and has to be answered with yes or no
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ClassifierConfig {
+    pub enabled: bool,
+    // the default model if unset
+    pub model: Option<String>,
+    // block or flag
+    pub action: ModerationAction,
+    pub stages: Vec<Stage>,
+    pub prompt: String,
+}
+
+impl Default for ClassifierConfig {
+    fn default() -> Self {
+        ClassifierConfig {
+            enabled: false,
+            model: None,
+            action: ModerationAction::Flag,
+            stages: vec![Stage::Input],
+            prompt: "Does the following text contain harassment, hate speech, threats, sexual content involving \
+                     minors or instructions for causing serious harm? Answer only yes or no.\n\nText: {text}"
+                .to_string(),
+        }
+    }
+}
+
+// Token budgets (prompt plus completion tokens) per role. A user_quota row overrides
+// them for a single user.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub user: Quota,
+    pub admin: Quota,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Quota {
+    // None is unlimited
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+}
+
+impl QuotaConfig {
+    pub fn for_role(&self, is_admin: bool) -> Quota {
+        if is_admin {
+            self.admin
+        } else {
+            self.user
+        }
+    }
+}
+
+impl JippityConfig {
+    pub fn load() -> Result<JippityConfig, ConfigError> {
+        dotenv::dotenv().ok();
+        let (path, explicit) = match env::var("JIPPITY_CONFIG") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let mut config = if !explicit && !path.exists() {
+            JippityConfig::default()
+        } else {
+            let raw = std::fs::read_to_string(&path)
+                .map_err(|source| ConfigError::Io { path: path.clone(), source })?;
+            toml::from_str(&raw).map_err(|source| ConfigError::Parse { path, source })?
+        };
+
+        // the single model setup from before [[models]] existed; without LLM_PATH the
+        // server still starts and the model shows up as failed
+        if config.models.is_empty() {
+            let path = env::var("LLM_PATH").unwrap_or_default();
+            config.models.push(ModelConfig { path: PathBuf::from(path), ..ModelConfig::default() });
+        }
+
+        // fail at startup rather than on the first chat message
+        for (i, model) in config.models.iter().enumerate() {
+            if config.models[..i].iter().any(|other| other.id == model.id) {
+                return Err(ConfigError::DuplicateModel(model.id.clone()));
+            }
+            config.template(&model.template)?;
+            if model.prompt_budget() == 0 {
+                return Err(ConfigError::ContextTooSmall(model.id.clone()));
+            }
+            model.check_parameters()?;
+        }
+        if let Some(id) = &config.default_model {
+            config.model(id)?;
+        }
+        if let Some(id) = &config.embeddings.model {
+            config.model(id)?;
+        }
+        let queue = &config.queue;
+        if queue.max_concurrent == 0 || queue.max_per_user == 0 || queue.max_waiting_per_user == 0 {
+            return Err(ConfigError::InvalidQueueLimits);
+        }
+        let retrieval = &config.retrieval;
+        if retrieval.top_k == 0 || retrieval.chunk_overlap >= retrieval.chunk_chars {
+            return Err(ConfigError::InvalidRetrieval);
+        }
+        Moderator::new(&config.moderation)?;
+        if let Some(id) = &config.moderation.classifier.model {
+            config.model(id)?;
+        }
+        Ok(config)
+    }
+
+    pub fn template(&self, name: &str) -> Result<PromptTemplate, ConfigError> {
+        self.templates
+            .get(name)
+            .cloned()
+            .or_else(|| PromptTemplate::builtin(name))
+            .ok_or_else(|| ConfigError::UnknownTemplate(name.to_string()))
+    }
+
+    pub fn model(&self, id: &str) -> Result<&ModelConfig, ConfigError> {
+        self.models
+            .iter()
+            .find(|model| model.id == id)
+            .ok_or_else(|| ConfigError::UnknownModel(id.to_string()))
+    }
+
+    pub fn default_model(&self) -> &ModelConfig {
+        self.default_model
+            .as_deref()
+            .and_then(|id| self.model(id).ok())
+            .unwrap_or(&self.models[0])
+    }
+
+    pub fn embedding_model(&self) -> &ModelConfig {
+        self.embeddings
+            .model
+            .as_deref()
+            .and_then(|id| self.model(id).ok())
+            .unwrap_or_else(|| self.default_model())
+    }
+}
+
+impl ModelConfig {
+    pub fn prompt_budget(&self) -> usize {
+        self.context_size.saturating_sub(self.max_response_tokens)
+    }
+
+    pub fn session_bytes(&self) -> usize {
+        self.context_size * self.kv_bytes_per_token
+    }
+
+    pub fn parameters(&self) -> llm::ModelParameters {
+        let rope_overrides = (self.rope_frequency_base.is_some() || self.rope_frequency_scale.is_some()).then(|| {
+            let defaults = llm::RoPEOverrides::default();
+            llm::RoPEOverrides {
+                frequency_base: self.rope_frequency_base.unwrap_or(defaults.frequency_base),
+                frequency_scale: self.rope_frequency_scale.unwrap_or(defaults.frequency_scale),
+            }
+        });
+        llm::ModelParameters {
+            prefer_mmap: self.prefer_mmap,
+            context_size: self.context_size,
+            lora_adapters: (!self.lora_adapters.is_empty()).then(|| self.lora_adapters.clone()),
+            use_gpu: self.use_gpu,
+            gpu_layers: self.gpu_layers,
+            rope_overrides,
+            n_gqa: self.n_gqa,
+        }
+    }
+
+    pub fn session_config(&self) -> llm::InferenceSessionConfig {
+        let defaults = llm::InferenceSessionConfig::default();
+        llm::InferenceSessionConfig { n_threads: self.threads.unwrap_or(defaults.n_threads), ..defaults }
+    }
+
+    // The files themselves are only checked when the model is loaded
+    fn check_parameters(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidModel { id: self.id.clone(), reason: reason.to_string() };
+        if self.gpu_layers.is_some() && !self.use_gpu {
+            return Err(invalid("gpu_layers needs use_gpu = true"));
+        }
+        if self.threads == Some(0) || self.n_gqa == Some(0) {
+            return Err(invalid("threads and n_gqa must be at least 1"));
+        }
+        if self.rope_frequency_base == Some(0) || self.rope_frequency_scale.is_some_and(|scale| scale <= 0.0) {
+            return Err(invalid("the RoPE frequency base and scale must be positive"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use crate::components::jippity::Conversation;
+use crate::inference::prompt::PromptTemplate;
+use serde::{Deserialize, Serialize};
+
+// How much of the model's context window a reply took, shown below the chat
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContextUsage {
+    pub used: usize,
+    pub size: usize,
+    pub dropped_messages: usize,
+}
+
+// Result of fitting a conversation into the prompt budget
+#[derive(Clone, Debug)]
+pub struct FittedPrompt {
+    pub prompt: String,
+    pub prompt_tokens: usize,
+    pub dropped_messages: usize,
+}
+
+// Drops the oldest messages until the rendered prompt fits into `budget` tokens.
+// The system prompt and the latest message are always kept, even if they alone overflow;
+// the caller decides what to do then.
+pub fn fit_prompt(
+    template: &PromptTemplate,
+    system_prompt: Option<&str>,
+    conversation: &Conversation,
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> FittedPrompt {
+    let mut window = conversation.clone();
+    let mut dropped_messages = 0;
+
+    loop {
+        let prompt = template.render(system_prompt, &window);
+        let prompt_tokens = count_tokens(&prompt);
+        if prompt_tokens <= budget || window.messages.len() <= 1 {
+            return FittedPrompt { prompt, prompt_tokens, dropped_messages };
+        }
+
+        window.messages.remove(0);
+        dropped_messages += 1;
+        // never start the window with an orphaned answer
+        while window.messages.len() > 1 && window.messages[0].from_llm {
+            window.messages.remove(0);
+            dropped_messages += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::jippity::Message;
+
+    // one "token" per whitespace separated word is plenty for testing the window
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    fn conversation(texts: &[&str]) -> Conversation {
+        Conversation {
+            id: None,
+            model: None,
+            collection: None,
+            persona: None,
+            messages: texts
+                .iter()
+                .enumerate()
+                .map(|(i, text)| if i % 2 == 1 { Message::llm(*text) } else { Message::user(*text) })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn keeps_everything_that_fits() {
+        let conv = conversation(&["one two", "three", "four"]);
+        let fitted = fit_prompt(&PromptTemplate::plain(), None, &conv, 100, words);
+        assert_eq!(fitted.dropped_messages, 0);
+        assert_eq!(fitted.prompt, PromptTemplate::plain().render(None, &conv));
+    }
+
+    #[test]
+    fn drops_oldest_exchange_first() {
+        let conv = conversation(&["a b c d e f", "g h i j k l", "latest question"]);
+        let fitted = fit_prompt(&PromptTemplate::plain(), Some("sys"), &conv, 8, words);
+        assert_eq!(fitted.dropped_messages, 2);
+        assert!(fitted.prompt.starts_with("sys\n"));
+        assert!(fitted.prompt.contains("latest question"));
+        assert!(!fitted.prompt.contains("g h i"));
+        assert!(fitted.prompt_tokens <= 8);
+    }
+
+    #[test]
+    fn latest_message_survives_even_when_too_long() {
+        let conv = conversation(&["old", "reply", "a very long latest message"]);
+        let fitted = fit_prompt(&PromptTemplate::plain(), Some("sys"), &conv, 2, words);
+        assert_eq!(fitted.dropped_messages, 2);
+        assert!(fitted.prompt.contains("a very long latest message"));
+        assert!(fitted.prompt_tokens > 2);
+    }
+}
@@ -0,0 +1,132 @@
+// Embeddings from the hidden state the model produces for a text, used by the
+// history search and /v1/embeddings. They run through the inference queue like any answer.
+use crate::app::ssr::AppState;
+use crate::history::store_embedding;
+use crate::inference::config::ModelConfig;
+use crate::inference::queue::{QueueError, QueuePermit};
+use crate::inference::registry::RegistryError;
+use crate::usage::record_usage;
+use llm::Model;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum EmbeddingError {
+    #[error(transparent)]
+    Registry(#[from] RegistryError),
+    #[error(transparent)]
+    Queue(#[from] QueueError),
+    #[error("Could not tokenize the text: {0}")]
+    Tokenize(String),
+    #[error("Embedding task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+pub struct Embedding {
+    pub vector: Vec<f32>,
+    // tokens of the text that went into the embedding
+    pub tokens: usize,
+}
+
+// Texts longer than the model's context are cut off, the embedding covers their beginning
+pub async fn embed(
+    state: &AppState,
+    user_id: Option<i32>,
+    model_config: &ModelConfig,
+    text: &str,
+) -> Result<Embedding, EmbeddingError> {
+    let model = state.models.get(&model_config.id).await?;
+    let permit = state.queue.acquire(user_id, |_| {}).await?;
+    let started = Instant::now();
+    let embedding = evaluate(model, model_config, text, permit).await?;
+    if let Some(user_id) = user_id {
+        tokio::spawn(record_usage(user_id, model_config.id.clone(), embedding.tokens, 0, started.elapsed(), false));
+    }
+    Ok(embedding)
+}
+
+async fn evaluate(
+    model: Arc<dyn Model>,
+    model_config: &ModelConfig,
+    text: &str,
+    permit: QueuePermit,
+) -> Result<Embedding, EmbeddingError> {
+    let text = text.to_string();
+    let context_size = model_config.context_size;
+    let session_config = model_config.session_config();
+    let embedding = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let mut tokens: Vec<llm::TokenId> = model
+            .tokenizer()
+            .tokenize(&text, true)
+            .map_err(|err| EmbeddingError::Tokenize(err.to_string()))?
+            .into_iter()
+            .map(|(_, token)| token)
+            .collect();
+        tokens.truncate(context_size);
+
+        let mut session = model.start_session(session_config);
+        let mut output = llm::OutputRequest { all_logits: None, embeddings: Some(Vec::new()) };
+        model.evaluate(&mut session, &tokens, &mut output);
+
+        Ok::<_, EmbeddingError>(Embedding { vector: output.embeddings.unwrap_or_default(), tokens: tokens.len() })
+    })
+    .await??;
+    Ok(embedding)
+}
+
+// Embeds a stored message for the history search. Runs in the background after a turn
+// or an import, a failure only means the message can't be found by the search.
+// Nobody asked for it, so it waits behind every chat answer, takes none of the user's
+// queue slots and doesn't count against their quota.
+pub async fn index_message(state: AppState, message_id: i64, text: String) {
+    let model_config = state.config.embedding_model().clone();
+    let embedding = async {
+        let model = state.models.get(&model_config.id).await?;
+        let permit = state.queue.acquire_background().await;
+        evaluate(model, &model_config, &text, permit).await
+    };
+    let embedding = match embedding.await {
+        Ok(embedding) => embedding,
+        Err(err) => {
+            eprintln!("Could not embed message {message_id}: {err}");
+            return;
+        }
+    };
+    if let Err(err) = store_embedding(message_id, &model_config.id, &embedding.vector).await {
+        eprintln!("Could not store the embedding of message {message_id}: {err}");
+    }
+}
+
+// 0 for vectors of different length or without direction
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_similarity_of_parallel_orthogonal_and_opposite_vectors() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_similarity_of_mismatched_or_zero_vectors_is_zero() {
+        assert_eq!(cosine_similarity(&[1.0, 2.0], &[1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+}
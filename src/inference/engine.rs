@@ -0,0 +1,381 @@
+// The inference path shared by the Jippity chat and the OpenAI compatible API:
+// wait for a queue slot, continue or rebuild the inference session, stream tokens.
+use crate::app::ssr::AppState;
+use crate::inference::cache::CacheMode;
+use crate::inference::events::ChatEvent;
+use crate::inference::generation::Generation;
+use crate::inference::grammar::{ConstrainedSampler, JsonConstraint, ResponseFormat};
+use crate::inference::queue::QueueError;
+use crate::inference::sampling::SamplingSettings;
+use crate::inference::sessions::CachedSession;
+use crate::inference::stop::{Scan, StopMatcher};
+use crate::inference::tools::{TOOL_CALL_CLOSE, TOOL_CALL_OPEN};
+use crate::usage::record_usage;
+use llm::{InferenceRequest, Model};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Error)]
+pub enum GenerationError {
+    #[error(transparent)]
+    Queue(#[from] QueueError),
+    #[error("{0}")]
+    Sampling(String),
+    #[error("Inference failed: {0}")]
+    Inference(String),
+    #[error("Inference task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+pub struct GenerationJob {
+    pub user_id: i32,
+    pub model_id: String,
+    pub model: Arc<dyn Model>,
+    // fully rendered prompt, the model continues right after it
+    pub prompt: String,
+    pub stop_sequences: Vec<String>,
+    pub max_tokens: usize,
+    pub sampling: SamplingSettings,
+    // conversation whose cached session may be continued, None for one-off prompts
+    pub session_key: Option<i64>,
+    pub session_bytes: usize,
+    // threads etc. of a new session
+    pub session_config: llm::InferenceSessionConfig,
+    // watch for <tool_call> blocks, a complete one ends the generation
+    pub tool_calls: bool,
+    // a JSON format constrains the sampling, stop sequences and tool calls are off then
+    pub format: ResponseFormat,
+    pub cache: CacheMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinishReason {
+    Stop,
+    Length,
+    Cancelled,
+    // the model asked for a tool, see GenerationOutput::tool_call
+    ToolCall,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GenerationOutput {
+    pub text: String,
+    // tokens fed to the model, without those a continued session already had
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+    // body of the <tool_call> block the generation stopped at
+    pub tool_call: Option<String>,
+    // the sampling seed, replaying with it gives the same text
+    pub seed: u64,
+}
+
+pub fn count_tokens(model: &dyn Model, text: &str) -> usize {
+    model
+        .tokenizer()
+        .tokenize(text, true)
+        .map(|tokens| tokens.len())
+        .unwrap_or(text.len())
+}
+
+// Runs inside spawn_blocking. Generation stops at a stop sequence, at the end of text,
+// when the user pressed stop, or when the receiver of `tx` went away.
+// `generated` gets every inferred token, including a swallowed stop sequence,
+// since all of them end up in the session's KV state.
+// With `tool_calls`, the matcher also watches for TOOL_CALL_OPEN: the <tool_call> block is
+// not streamed but collected into `tool_call`, and its closing tag halts the generation;
+// `tool_call_done` tells it was complete.
+// With a `constraint`, the generation halts as soon as the JSON value is complete.
+// `disconnected` tells the receiver went away, the answer is cut off then.
+#[allow(clippy::too_many_arguments)]
+fn inference_callback<'a>(
+    matcher: &'a mut StopMatcher,
+    answer: &'a mut String,
+    generated: &'a mut String,
+    tool_call: &'a mut Option<String>,
+    tool_call_done: &'a mut bool,
+    completion_tokens: &'a mut usize,
+    constraint: Option<&'a Mutex<JsonConstraint>>,
+    tx: mpsc::Sender<ChatEvent>,
+    generation: &'a Generation,
+    disconnected: &'a mut bool,
+) -> impl FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, std::convert::Infallible> + 'a {
+    use llm::InferenceFeedback::{Halt, Continue};
+
+    move |resp| -> Result<llm::InferenceFeedback, std::convert::Infallible> {
+        if generation.is_cancelled() {
+            return Ok(Halt);
+        }
+        match resp {
+            llm::InferenceResponse::InferredToken(t) => {
+                *completion_tokens += 1;
+                generated.push_str(&t);
+
+                if let Some(call) = tool_call.as_mut() {
+                    call.push_str(&t);
+                    if let Some(end) = call.find(TOOL_CALL_CLOSE) {
+                        call.truncate(end);
+                        *tool_call_done = true;
+                        return Ok(Halt);
+                    }
+                    return Ok(Continue);
+                }
+
+                let (text, feedback) = match matcher.push(&t) {
+                    Scan::Text(text) => (text, Continue),
+                    Scan::Matched { before, index, after } if matcher.sequence(index) == TOOL_CALL_OPEN => {
+                        let mut call = after;
+                        let feedback = match call.find(TOOL_CALL_CLOSE) {
+                            Some(end) => {
+                                call.truncate(end);
+                                *tool_call_done = true;
+                                Halt
+                            }
+                            None => Continue,
+                        };
+                        *tool_call = Some(call);
+                        (before, feedback)
+                    }
+                    Scan::Matched { before, .. } => (before, Halt),
+                };
+                let complete = constraint.is_some_and(|constraint| constraint.lock().unwrap().is_complete());
+                let feedback = if complete { Halt } else { feedback };
+
+                if !text.is_empty() {
+                    answer.push_str(&text);
+                    if tx.blocking_send(ChatEvent::Token(text)).is_err() {
+                        *disconnected = true;
+                        return Ok(Halt);
+                    }
+                }
+                Ok(feedback)
+            }
+            llm::InferenceResponse::EotToken => Ok(Halt),
+            _ => Ok(Continue),
+        }
+    }
+}
+
+// A stopped or disconnected generation is cut off, whatever else happened.
+// `truncated` means the token limit ended it.
+fn finish_reason(cut_off: bool, tool_call: bool, truncated: bool) -> FinishReason {
+    if cut_off {
+        FinishReason::Cancelled
+    } else if tool_call {
+        FinishReason::ToolCall
+    } else if truncated {
+        FinishReason::Length
+    } else {
+        FinishReason::Stop
+    }
+}
+
+// Queues `job` and streams Queued and Token events to `tx` while it runs.
+// A cancelled generation or a dropped receiver ends with FinishReason::Cancelled,
+// the text generated until then is kept. A cached answer is streamed right away,
+// without waiting for a queue slot.
+pub async fn run_generation(
+    state: &AppState,
+    job: GenerationJob,
+    generation: &Arc<Generation>,
+    tx: mpsc::Sender<ChatEvent>,
+) -> Result<GenerationOutput, GenerationError> {
+    let cache_key = state.cache.key(&job);
+    if let Some(key) = &cache_key {
+        if let Some(output) = state.cache.get(key, job.cache).await {
+            if !output.text.is_empty() {
+                let _ = tx.send(ChatEvent::Token(output.text.clone())).await;
+            }
+            let usage = (output.prompt_tokens, output.completion_tokens);
+            tokio::spawn(record_usage(job.user_id, job.model_id, usage.0, usage.1, Duration::ZERO, true));
+            return Ok(output);
+        }
+    }
+
+    let seed = job.sampling.seed.unwrap_or_else(rand::random);
+    let cancelled = GenerationOutput {
+        text: String::new(),
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        finish_reason: FinishReason::Cancelled,
+        tool_call: None,
+        seed,
+    };
+
+    let position_tx = tx.clone();
+    let permit = tokio::select! {
+        permit = state.queue.acquire(Some(job.user_id), |position| {
+            let _ = position_tx.try_send(ChatEvent::Queued { position });
+        }) => permit?,
+        // nobody is listening anymore, leave the queue
+        _ = tx.closed() => return Ok(cancelled),
+        _ = generation.cancelled() => return Ok(cancelled),
+    };
+
+    let sessions = state.sessions.clone();
+    let generation = generation.clone();
+    let (user_id, usage_model) = (job.user_id, job.model_id.clone());
+    let started = Instant::now();
+    let output = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let GenerationJob {
+            model_id, model, prompt, stop_sequences, max_tokens, sampling, session_key, session_bytes, session_config,
+            tool_calls, format, ..
+        } = job;
+        let mut parameters = sampling.to_parameters(model.as_ref()).map_err(GenerationError::Sampling)?;
+        let constraint = format.constraint().map_err(|err| GenerationError::Sampling(err.to_string()))?;
+        let constraint = constraint.map(|constraint| Arc::new(Mutex::new(constraint)));
+        if let Some(constraint) = &constraint {
+            let sampler = ConstrainedSampler::new(model.as_ref(), constraint.clone(), parameters.sampler);
+            parameters.sampler = Arc::new(Mutex::new(sampler));
+        }
+        let (stop_sequences, tool_calls) = match constraint {
+            Some(_) => (Vec::new(), false),
+            None => (stop_sequences, tool_calls),
+        };
+        let mut answer = String::new();
+        let mut generated = String::new();
+        let mut completion_tokens = 0;
+        let tool_call_open = tool_calls.then_some(TOOL_CALL_OPEN);
+        let mut matcher = StopMatcher::new(stop_sequences.iter().map(String::as_str).chain(tool_call_open));
+        let mut tool_call = None;
+        let mut tool_call_done = false;
+        let mut disconnected = false;
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // continue the conversation's session if it still matches the prompt,
+        // otherwise (evicted, restarted, history truncated) rebuild it from scratch
+        let cached = session_key.and_then(|key| sessions.take(key));
+        let (mut session, fed) = match cached {
+            Some(cached) if cached.unfed(&model_id, &prompt).is_some() => (cached.session, cached.fed),
+            _ => (model.start_session(session_config), String::new()),
+        };
+        let unfed = &prompt[fed.len()..];
+        let prompt_tokens = count_tokens(model.as_ref(), unfed);
+
+        session
+            .infer(
+                model.as_ref(),
+                &mut rng,
+                &InferenceRequest {
+                    prompt: unfed.into(),
+                    parameters: &parameters,
+                    play_back_previous_tokens: false,
+                    maximum_token_count: Some(max_tokens),
+                },
+                &mut Default::default(),
+                inference_callback(
+                    &mut matcher,
+                    &mut answer,
+                    &mut generated,
+                    &mut tool_call,
+                    &mut tool_call_done,
+                    &mut completion_tokens,
+                    constraint.as_deref(),
+                    tx.clone(),
+                    &generation,
+                    &mut disconnected,
+                ),
+            )
+            // the session is in an unknown state now, it's not cached again
+            .map_err(|err| GenerationError::Inference(err.to_string()))?;
+
+        // the start of a stop sequence that never completed is part of the answer
+        let tail = matcher.flush();
+        if !tail.is_empty() {
+            answer.push_str(&tail);
+            disconnected |= tx.blocking_send(ChatEvent::Token(tail)).is_err();
+        }
+
+        if let Some(key) = session_key {
+            let fed = format!("{prompt}{generated}");
+            sessions.put(key, CachedSession { model_id, session, fed }, session_bytes);
+        }
+
+        // an unfinished block (length limit, end of text) is just dropped
+        let tool_call = tool_call.filter(|_| tool_call_done);
+        let cut_off = generation.is_cancelled() || disconnected;
+        // JSON that still parses wasn't cut off, even if it used the last token.
+        // Length with a JSON format means the answer doesn't parse.
+        let truncated = match &constraint {
+            Some(constraint) => !constraint.lock().unwrap().can_end(),
+            None => completion_tokens >= max_tokens,
+        };
+        let finish_reason = finish_reason(cut_off, tool_call.is_some(), truncated);
+        Ok::<_, GenerationError>(GenerationOutput {
+            text: answer,
+            prompt_tokens,
+            completion_tokens,
+            finish_reason,
+            tool_call,
+            seed,
+        })
+    })
+    .await??;
+
+    let (prompt_tokens, completion_tokens) = (output.prompt_tokens, output.completion_tokens);
+    if let Some(key) = cache_key {
+        let (cache, model_id, output) = (state.cache.clone(), usage_model.clone(), output.clone());
+        tokio::spawn(async move { cache.put(key, model_id, output).await });
+    }
+    tokio::spawn(record_usage(user_id, usage_model, prompt_tokens, completion_tokens, started.elapsed(), false));
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::cache::{CacheMode, ResponseCache};
+    use crate::inference::config::CacheConfig;
+    use crate::inference::generation::GenerationRegistry;
+
+    #[test]
+    fn a_dropped_receiver_cancels_and_is_not_cached() {
+        let registry = Arc::new(GenerationRegistry::default());
+        let generation = registry.start(1);
+        let (tx, rx) = mpsc::channel(16);
+        drop(rx);
+
+        let mut matcher = StopMatcher::new(Vec::<String>::new());
+        let (mut answer, mut generated, mut tool_call) = (String::new(), String::new(), None);
+        let (mut tool_call_done, mut completion_tokens, mut disconnected) = (false, 0, false);
+        let mut callback = inference_callback(
+            &mut matcher,
+            &mut answer,
+            &mut generated,
+            &mut tool_call,
+            &mut tool_call_done,
+            &mut completion_tokens,
+            None,
+            tx,
+            &generation,
+            &mut disconnected,
+        );
+        let feedback = callback(llm::InferenceResponse::InferredToken("Hel".to_string())).unwrap();
+        assert!(matches!(feedback, llm::InferenceFeedback::Halt));
+        drop(callback);
+        assert!(disconnected);
+
+        let finish_reason = finish_reason(disconnected, false, completion_tokens >= 100);
+        assert_eq!(finish_reason, FinishReason::Cancelled);
+        let output = GenerationOutput {
+            text: answer,
+            prompt_tokens: 3,
+            completion_tokens,
+            finish_reason,
+            tool_call: None,
+            seed: 7,
+        };
+        let cache = ResponseCache::new(CacheConfig::default());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            cache.put("key".to_string(), "default".to_string(), output).await;
+            assert!(cache.get("key", CacheMode::Read).await.is_none());
+        });
+    }
+}
@@ -0,0 +1,81 @@
+use crate::components::jippity::{Source, ToolInvocation};
+use crate::inference::context::ContextUsage;
+use serde::{Deserialize, Serialize};
+
+// What `converse` streams back to the browser, one JSON object per line
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatEvent {
+    // `generation` is the id to pass to `cancel_generation`,
+    // `conversation` the stored conversation the answer belongs to
+    Started { generation: u64, conversation: i64 },
+    // 1-based position in the inference queue, sent while waiting for a free slot
+    Queued { position: usize },
+    // document chunks the answer is grounded on, sent before the first token
+    Sources(Vec<Source>),
+    Token(String),
+    // a tool Jippity called, the answer continues with new Token events
+    Tool(ToolInvocation),
+    Done { context: ContextUsage, cancelled: bool },
+    Error(String),
+}
+
+impl ChatEvent {
+    pub fn encode(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("ChatEvent is always serializable");
+        line.push('\n');
+        line
+    }
+}
+
+// The browser may split or merge the chunks of a streamed response,
+// so lines are buffered until their newline arrives.
+#[derive(Default)]
+pub struct EventDecoder {
+    buf: String,
+}
+
+impl EventDecoder {
+    pub fn push(&mut self, chunk: &str) -> Vec<ChatEvent> {
+        self.buf.push_str(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buf.find('\n') {
+            let line: String = self.buf.drain(..=end).collect();
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(event) => events.push(event),
+                Err(err) => events.push(ChatEvent::Error(format!("Malformed server event: {err}"))),
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_events_split_across_chunks() {
+        let stream = [
+            ChatEvent::Started { generation: 7, conversation: 1 },
+            ChatEvent::Token("Hel".to_string()),
+            ChatEvent::Token("lo\n".to_string()),
+            ChatEvent::Done { context: ContextUsage::default(), cancelled: true },
+        ]
+        .iter()
+        .map(ChatEvent::encode)
+        .collect::<String>();
+
+        let mut decoder = EventDecoder::default();
+        let (head, tail) = stream.split_at(stream.len() / 2 + 3);
+        let mut events = decoder.push(head);
+        events.extend(decoder.push(tail));
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[2], ChatEvent::Token("lo\n".to_string()));
+        assert!(matches!(events[3], ChatEvent::Done { cancelled: true, .. }));
+    }
+}
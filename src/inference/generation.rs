@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Keeps a cancel flag for every running generation so the Stop button
+// can reach the inference callback of another request.
+#[derive(Default)]
+pub struct GenerationRegistry {
+    // the flag with the id of the user the generation runs for
+    running: Mutex<HashMap<u64, (i32, Arc<CancelFlag>)>>,
+}
+
+#[derive(Default)]
+struct CancelFlag {
+    cancelled: AtomicBool,
+    // wakes up generations that are still waiting in the queue
+    notify: Notify,
+}
+
+// Unregisters the generation again when the inference task is done
+pub struct Generation {
+    pub id: u64,
+    flag: Arc<CancelFlag>,
+    registry: Arc<GenerationRegistry>,
+}
+
+impl GenerationRegistry {
+    pub fn start(self: &Arc<Self>, user_id: i32) -> Generation {
+        let flag = Arc::new(CancelFlag::default());
+        let mut running = self.running.lock().unwrap();
+        // random ids, so nobody can stop someone else's answer by counting up
+        let id = loop {
+            let id = rand::random::<u64>();
+            if !running.contains_key(&id) {
+                break id;
+            }
+        };
+        running.insert(id, (user_id, flag.clone()));
+
+        Generation { id, flag, registry: self.clone() }
+    }
+
+    // Only the user a generation runs for may stop it
+    pub fn cancel(&self, id: u64, user_id: i32) -> bool {
+        match self.running.lock().unwrap().get(&id) {
+            Some((owner, flag)) if *owner == user_id => {
+                flag.cancelled.store(true, Ordering::Relaxed);
+                flag.notify.notify_waiters();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Generation {
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.cancelled.load(Ordering::Relaxed)
+    }
+
+    // resolves once the generation got cancelled
+    pub async fn cancelled(&self) {
+        let notified = self.flag.notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        self.registry.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_owner_cancels() {
+        let registry = Arc::new(GenerationRegistry::default());
+        let generation = registry.start(1);
+        assert!(!registry.cancel(generation.id, 2));
+        assert!(!generation.is_cancelled());
+        assert!(registry.cancel(generation.id, 1));
+        assert!(generation.is_cancelled());
+
+        let id = generation.id;
+        drop(generation);
+        assert!(!registry.cancel(id, 1));
+    }
+}
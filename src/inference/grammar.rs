@@ -0,0 +1,882 @@
+// Constrained generation: the answer has to be JSON, optionally matching a JSON schema.
+// The schema becomes a byte-level pushdown automaton (JsonConstraint). Before each token
+// is sampled, the tokens it would reject are masked out, so the output always parses
+// unless it hits the token limit first.
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use thiserror::Error;
+
+// The `response_format` of the OpenAI API
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JsonSchemaFormat {
+    #[serde(default)]
+    pub name: String,
+    pub schema: Value,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum GrammarError {
+    #[error("Unsupported JSON schema keyword: {0}")]
+    Unsupported(String),
+    #[error("Invalid JSON schema: {0}")]
+    Invalid(String),
+}
+
+impl ResponseFormat {
+    // None for plain text
+    pub fn constraint(&self) -> Result<Option<JsonConstraint>, GrammarError> {
+        let schema = match self {
+            ResponseFormat::Text => return Ok(None),
+            ResponseFormat::JsonObject => Schema::any_object(),
+            ResponseFormat::JsonSchema { json_schema } => Schema::compile(&json_schema.schema)?,
+        };
+        Ok(Some(JsonConstraint::new(schema)))
+    }
+
+    // Appended to the system prompt, the constraint alone gives valid but rarely useful JSON
+    pub fn instructions(&self) -> Option<String> {
+        match self {
+            ResponseFormat::Text => None,
+            ResponseFormat::JsonObject => Some("Answer with a single JSON object and nothing else.".to_string()),
+            ResponseFormat::JsonSchema { json_schema } => Some(format!(
+                "Answer with a single JSON value matching this JSON schema and nothing else:\n{}",
+                json_schema.schema
+            )),
+        }
+    }
+}
+
+// The supported part of JSON schema: type, properties, required, additionalProperties,
+// items, enum, const, anyOf and oneOf. Other keywords are ignored.
+#[derive(Debug, PartialEq)]
+pub enum Schema {
+    Any,
+    Object {
+        properties: Vec<(String, Arc<Schema>)>,
+        required: Vec<String>,
+        // None allows no other properties
+        additional: Option<Arc<Schema>>,
+    },
+    Array(Arc<Schema>),
+    String,
+    Number { integer: bool },
+    // the JSON texts of the allowed values, for enum, const, booleans and null
+    Literal(Arc<Vec<String>>),
+    // the value follows the first alternative that accepts its first character
+    OneOf(Vec<Arc<Schema>>),
+}
+
+impl Schema {
+    pub fn compile(schema: &Value) -> Result<Schema, GrammarError> {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(true) => return Ok(Schema::Any),
+            _ => return Err(GrammarError::Invalid("a schema must be an object".to_string())),
+        };
+        if schema.contains_key("$ref") {
+            return Err(GrammarError::Unsupported("$ref".to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            return match values {
+                Value::Array(values) if !values.is_empty() => Ok(literal(values.iter().map(Value::to_string))),
+                _ => Err(GrammarError::Invalid("enum must be a non-empty array".to_string())),
+            };
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal([value.to_string()]));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(alternatives) = schema.get(keyword) {
+                let Value::Array(alternatives) = alternatives else {
+                    return Err(GrammarError::Invalid(format!("{keyword} must be an array")));
+                };
+                let alternatives = alternatives.iter().map(|alternative| Schema::compile(alternative).map(Arc::new));
+                return Ok(Schema::OneOf(alternatives.collect::<Result<_, _>>()?));
+            }
+        }
+        match schema.get("type") {
+            None if schema.contains_key("properties") => Schema::object(schema),
+            None => Ok(Schema::Any),
+            Some(Value::String(kind)) => Schema::typed(kind, schema),
+            Some(Value::Array(kinds)) => {
+                let alternatives = kinds.iter().map(|kind| match kind {
+                    Value::String(kind) => Schema::typed(kind, schema).map(Arc::new),
+                    _ => Err(GrammarError::Invalid("type must name types".to_string())),
+                });
+                Ok(Schema::OneOf(alternatives.collect::<Result<_, _>>()?))
+            }
+            Some(_) => Err(GrammarError::Invalid("type must be a string or an array".to_string())),
+        }
+    }
+
+    fn typed(kind: &str, schema: &Map<String, Value>) -> Result<Schema, GrammarError> {
+        Ok(match kind {
+            "object" => return Schema::object(schema),
+            "array" => Schema::Array(Arc::new(match schema.get("items") {
+                Some(items) => Schema::compile(items)?,
+                None => Schema::Any,
+            })),
+            "string" => Schema::String,
+            "integer" => Schema::Number { integer: true },
+            "number" => Schema::Number { integer: false },
+            "boolean" => literal(["true", "false"]),
+            "null" => literal(["null"]),
+            other => return Err(GrammarError::Invalid(format!("unknown type {other}"))),
+        })
+    }
+
+    fn object(schema: &Map<String, Value>) -> Result<Schema, GrammarError> {
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) => properties
+                .iter()
+                .map(|(name, property)| Ok((name.clone(), Arc::new(Schema::compile(property)?))))
+                .collect::<Result<Vec<_>, GrammarError>>()?,
+            Some(_) => return Err(GrammarError::Invalid("properties must be an object".to_string())),
+            None => Vec::new(),
+        };
+        let required: Vec<String> = match schema.get("required") {
+            Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            _ => Vec::new(),
+        };
+        let additional = match schema.get("additionalProperties") {
+            Some(Value::Bool(false)) => None,
+            Some(Value::Bool(true)) | None => Some(Arc::new(Schema::Any)),
+            Some(additional) => Some(Arc::new(Schema::compile(additional)?)),
+        };
+        if additional.is_none() {
+            if let Some(missing) = required.iter().find(|name| !properties.iter().any(|(property, _)| property == *name)) {
+                return Err(GrammarError::Invalid(format!("required property {missing} is not allowed")));
+            }
+        }
+        Ok(Schema::Object { properties, required, additional })
+    }
+
+    fn any_object() -> Schema {
+        Schema::Object { properties: Vec::new(), required: Vec::new(), additional: Some(Arc::new(Schema::Any)) }
+    }
+}
+
+fn literal<S: Into<String>>(values: impl IntoIterator<Item = S>) -> Schema {
+    Schema::Literal(Arc::new(values.into_iter().map(Into::into).collect()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Expect {
+    Open,
+    FirstKey,
+    Key,
+    KeyName,
+    Colon,
+    Value,
+    InValue,
+    CommaOrEnd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Escape {
+    None,
+    Backslash,
+    // hex digits still expected after \u
+    Hex(u8),
+}
+
+#[derive(Clone, Debug)]
+enum Frame {
+    // a value of the schema, after optional whitespace
+    Value(Arc<Schema>),
+    Object { schema: Arc<Schema>, seen: Vec<String>, key: Option<String>, expect: Expect },
+    Array { item: Arc<Schema>, expect: Expect },
+    // `allowed` restricts object keys, `text` is only kept for keys
+    Str { open: bool, escape: Escape, key: bool, text: Vec<u8>, allowed: Option<Arc<Vec<String>>> },
+    // state of the number automaton, see number_step
+    Number { integer: bool, state: u8 },
+    Literal { options: Arc<Vec<String>>, text: Vec<u8> },
+}
+
+enum Step {
+    Consumed,
+    Reject,
+    // push the frame and hand it the same byte
+    Push(Frame),
+    // replace this frame and hand the new one the same byte
+    Replace(Frame),
+    // the frame is complete with the byte (a key string returns its text)
+    Done(Option<String>),
+    // the frame was complete before the byte, the parent gets the byte
+    Ended,
+}
+
+// Whitespace bytes in a row outside of strings, so a model can't pad until the token limit
+const MAX_WHITESPACE: usize = 64;
+
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+// -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)? as a state machine, None rejects the byte
+fn number_step(state: u8, b: u8, integer: bool) -> Option<u8> {
+    match (state, b) {
+        (0, b'-') => Some(1),
+        (0 | 1, b'0') => Some(2),
+        (0 | 1, b'1'..=b'9') => Some(3),
+        (3, b'0'..=b'9') => Some(3),
+        (2 | 3, b'.') if !integer => Some(4),
+        (4 | 5, b'0'..=b'9') => Some(5),
+        (2 | 3 | 5, b'e' | b'E') if !integer => Some(6),
+        (6, b'+' | b'-') => Some(7),
+        (6..=8, b'0'..=b'9') => Some(8),
+        _ => None,
+    }
+}
+
+fn number_is_whole(state: u8) -> bool {
+    matches!(state, 2 | 3 | 5 | 8)
+}
+
+impl Frame {
+    // The frame for a value of `schema` starting with `b`, None if it can't start so
+    fn start(schema: &Arc<Schema>, b: u8) -> Option<Frame> {
+        let frame = match (schema.as_ref(), b) {
+            (Schema::Any, b'{') => Frame::object(Arc::new(Schema::any_object())),
+            (Schema::Any, b'[') => Frame::Array { item: schema.clone(), expect: Expect::Open },
+            (Schema::Any | Schema::String, b'"') => Frame::string(false, None),
+            (Schema::Any, b'-' | b'0'..=b'9') => Frame::Number { integer: false, state: 0 },
+            (Schema::Any, _) => Frame::Literal { options: Arc::new(vec!["true".into(), "false".into(), "null".into()]), text: Vec::new() },
+            (Schema::Object { .. }, b'{') => Frame::object(schema.clone()),
+            (Schema::Array(item), b'[') => Frame::Array { item: item.clone(), expect: Expect::Open },
+            (Schema::Number { integer }, b'-' | b'0'..=b'9') => Frame::Number { integer: *integer, state: 0 },
+            (Schema::Literal(options), _) => Frame::Literal { options: options.clone(), text: Vec::new() },
+            (Schema::OneOf(alternatives), _) => {
+                return alternatives.iter().find_map(|alternative| {
+                    let frame = Frame::start(alternative, b)?;
+                    (!matches!(frame.clone().push(b), Step::Reject)).then_some(frame)
+                })
+            }
+            _ => return None,
+        };
+        Some(frame)
+    }
+
+    fn object(schema: Arc<Schema>) -> Frame {
+        Frame::Object { schema, seen: Vec::new(), key: None, expect: Expect::Open }
+    }
+
+    fn string(key: bool, allowed: Option<Arc<Vec<String>>>) -> Frame {
+        Frame::Str { open: false, escape: Escape::None, key, text: Vec::new(), allowed }
+    }
+
+    fn push(&mut self, b: u8) -> Step {
+        match self {
+            Frame::Value(schema) => {
+                if is_whitespace(b) {
+                    return Step::Consumed;
+                }
+                match Frame::start(schema, b) {
+                    Some(frame) => Step::Replace(frame),
+                    None => Step::Reject,
+                }
+            }
+            Frame::Object { schema, seen, key, expect } => {
+                let Schema::Object { properties, required, additional } = schema.as_ref() else {
+                    return Step::Reject;
+                };
+                if *expect == Expect::Open {
+                    if b != b'{' {
+                        return Step::Reject;
+                    }
+                    *expect = Expect::FirstKey;
+                    return Step::Consumed;
+                }
+                if is_whitespace(b) {
+                    return Step::Consumed;
+                }
+                let complete = || required.iter().all(|name| seen.contains(name));
+                // property names still allowed, None for any name
+                let remaining = || -> Option<Vec<String>> {
+                    additional.is_none().then(|| {
+                        properties.iter().map(|(name, _)| name).filter(|name| !seen.contains(name)).cloned().collect()
+                    })
+                };
+                let more_keys = || remaining().is_none_or(|names| !names.is_empty());
+                match (*expect, b) {
+                    (Expect::FirstKey | Expect::CommaOrEnd, b'}') if complete() => Step::Done(None),
+                    (Expect::FirstKey | Expect::Key, b'"') if more_keys() => {
+                        *expect = Expect::KeyName;
+                        Step::Push(Frame::string(true, remaining().map(Arc::new)))
+                    }
+                    (Expect::Colon, b':') => {
+                        *expect = Expect::Value;
+                        Step::Consumed
+                    }
+                    (Expect::Value, _) => {
+                        let name = key.as_deref().unwrap_or_default();
+                        let value = properties
+                            .iter()
+                            .find(|(property, _)| property == name)
+                            .map(|(_, schema)| schema.clone())
+                            .or_else(|| additional.clone())
+                            .unwrap_or_else(|| Arc::new(Schema::Any));
+                        *expect = Expect::InValue;
+                        Step::Push(Frame::Value(value))
+                    }
+                    (Expect::CommaOrEnd, b',') if more_keys() => {
+                        *expect = Expect::Key;
+                        Step::Consumed
+                    }
+                    _ => Step::Reject,
+                }
+            }
+            Frame::Array { item, expect } => match (*expect, b) {
+                (Expect::Open, b'[') => {
+                    *expect = Expect::FirstKey;
+                    Step::Consumed
+                }
+                (Expect::Open, _) => Step::Reject,
+                (_, b) if is_whitespace(b) => Step::Consumed,
+                (Expect::FirstKey | Expect::CommaOrEnd, b']') => Step::Done(None),
+                (Expect::CommaOrEnd, b',') => {
+                    *expect = Expect::Value;
+                    Step::Consumed
+                }
+                (Expect::FirstKey | Expect::Value, _) => {
+                    *expect = Expect::InValue;
+                    Step::Push(Frame::Value(item.clone()))
+                }
+                _ => Step::Reject,
+            },
+            Frame::Str { open, escape, key, text, allowed } => {
+                if !*open {
+                    *open = b == b'"';
+                    return if *open { Step::Consumed } else { Step::Reject };
+                }
+                match *escape {
+                    Escape::Backslash => {
+                        *escape = match b {
+                            b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => Escape::None,
+                            b'u' => Escape::Hex(4),
+                            _ => return Step::Reject,
+                        };
+                        return Step::Consumed;
+                    }
+                    Escape::Hex(left) => {
+                        if !b.is_ascii_hexdigit() {
+                            return Step::Reject;
+                        }
+                        *escape = if left > 1 { Escape::Hex(left - 1) } else { Escape::None };
+                        return Step::Consumed;
+                    }
+                    Escape::None => {}
+                }
+                match b {
+                    b'"' => {
+                        let known = |names: &Arc<Vec<String>>| names.iter().any(|name| name.as_bytes() == text.as_slice());
+                        if !allowed.as_ref().is_none_or(known) {
+                            return Step::Reject;
+                        }
+                        Step::Done(key.then(|| String::from_utf8_lossy(text).into_owned()))
+                    }
+                    // restricted names are matched as written
+                    b'\\' if allowed.is_some() => Step::Reject,
+                    b'\\' => {
+                        *escape = Escape::Backslash;
+                        Step::Consumed
+                    }
+                    0..=0x1f => Step::Reject,
+                    _ => {
+                        if *key {
+                            text.push(b);
+                        }
+                        let fits = |names: &Arc<Vec<String>>| names.iter().any(|name| name.as_bytes().starts_with(text));
+                        if allowed.as_ref().is_none_or(fits) { Step::Consumed } else { Step::Reject }
+                    }
+                }
+            }
+            Frame::Number { integer, state } => match number_step(*state, b, *integer) {
+                Some(next) => {
+                    *state = next;
+                    Step::Consumed
+                }
+                None if number_is_whole(*state) => Step::Ended,
+                None => Step::Reject,
+            },
+            Frame::Literal { options, text } => {
+                let mut next = text.clone();
+                next.push(b);
+                if options.iter().any(|option| option.as_bytes().starts_with(&next)) {
+                    *text = next;
+                    // nothing longer can follow, so the literal ends with this byte
+                    let longer = options.iter().any(|option| option.len() > text.len() && option.as_bytes().starts_with(text));
+                    if !longer {
+                        return Step::Done(None);
+                    }
+                    Step::Consumed
+                } else if options.iter().any(|option| option.as_bytes() == text.as_slice()) {
+                    Step::Ended
+                } else {
+                    Step::Reject
+                }
+            }
+        }
+    }
+
+    // A child frame completed, `key` is the text of a key string
+    fn child_done(&mut self, name: Option<String>) {
+        match self {
+            Frame::Object { seen, key, expect, .. } => {
+                if *expect == Expect::KeyName {
+                    *key = name;
+                    *expect = Expect::Colon;
+                } else {
+                    seen.extend(key.take());
+                    *expect = Expect::CommaOrEnd;
+                }
+            }
+            Frame::Array { expect, .. } => *expect = Expect::CommaOrEnd,
+            _ => {}
+        }
+    }
+
+    // could the value end right here
+    fn is_whole(&self) -> bool {
+        match self {
+            Frame::Number { state, .. } => number_is_whole(*state),
+            Frame::Literal { options, text } => options.iter().any(|option| option.as_bytes() == text.as_slice()),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JsonConstraint {
+    stack: Vec<Frame>,
+    complete: bool,
+    whitespace: usize,
+}
+
+impl JsonConstraint {
+    pub fn new(schema: Schema) -> JsonConstraint {
+        JsonConstraint { stack: vec![Frame::Value(Arc::new(schema))], complete: false, whitespace: 0 }
+    }
+
+    // Feeds the bytes of a token. False if one of them is rejected, the state is unchanged then.
+    pub fn push(&mut self, bytes: &[u8]) -> bool {
+        let mut cursor = self.cursor();
+        if !bytes.iter().all(|&b| cursor.push_byte(b)) {
+            return false;
+        }
+        let Cursor { base, top, complete, whitespace } = cursor;
+        let kept = base.len();
+        self.stack.truncate(kept);
+        self.stack.extend(top);
+        self.complete = complete;
+        self.whitespace = whitespace;
+        true
+    }
+
+    // Whether a token with these bytes may come next. Empty (special) tokens may not.
+    pub fn allows(&self, bytes: &[u8]) -> bool {
+        let mut cursor = self.cursor();
+        !bytes.is_empty() && bytes.iter().all(|&b| cursor.push_byte(b))
+    }
+
+    // The root value is finished, generation can stop
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    // The output parses if it ends here, like a number at the root that could still go on
+    pub fn can_end(&self) -> bool {
+        self.complete || (self.stack.len() == 1 && self.stack[0].is_whole())
+    }
+
+    fn cursor(&self) -> Cursor<'_> {
+        Cursor { base: &self.stack, top: Vec::new(), complete: self.complete, whitespace: self.whitespace }
+    }
+}
+
+// A state on top of a borrowed JsonConstraint. Only the frames a byte changes are copied,
+// so trying a token doesn't clone the whole stack.
+#[derive(Clone)]
+struct Cursor<'a> {
+    // the unchanged bottom of the stack, `top` goes on it
+    base: &'a [Frame],
+    top: Vec<Frame>,
+    complete: bool,
+    whitespace: usize,
+}
+
+impl Cursor<'_> {
+    fn push_byte(&mut self, b: u8) -> bool {
+        let in_string = matches!(self.top.last().or(self.base.last()), Some(Frame::Str { open: true, .. }));
+        let padding = is_whitespace(b) && !in_string;
+        if padding && self.whitespace >= MAX_WHITESPACE {
+            return false;
+        }
+        if !self.step(b) {
+            return false;
+        }
+        self.whitespace = if padding { self.whitespace + 1 } else { 0 };
+        true
+    }
+
+    fn step(&mut self, b: u8) -> bool {
+        loop {
+            let Some(frame) = self.last_mut() else {
+                // only whitespace after the root value
+                return is_whitespace(b);
+            };
+            match frame.push(b) {
+                Step::Consumed => return true,
+                Step::Reject => return false,
+                Step::Push(child) => self.top.push(child),
+                Step::Replace(next) => *frame = next,
+                Step::Done(key) => {
+                    self.top.pop();
+                    self.child_done(key);
+                    return true;
+                }
+                Step::Ended => {
+                    self.top.pop();
+                    self.child_done(None);
+                }
+            }
+        }
+    }
+
+    // The top frame, copied from `base` first if needed
+    fn last_mut(&mut self) -> Option<&mut Frame> {
+        if self.top.is_empty() {
+            let (last, rest) = self.base.split_last()?;
+            self.top.push(last.clone());
+            self.base = rest;
+        }
+        self.top.last_mut()
+    }
+
+    fn child_done(&mut self, key: Option<String>) {
+        match self.last_mut() {
+            Some(parent) => parent.child_done(key),
+            None => self.complete = true,
+        }
+    }
+}
+
+// The vocabulary as a byte trie, so tokens with a common prefix are checked together
+// and a rejected prefix rules out all of its tokens at once
+#[derive(Debug)]
+pub struct TokenTrie {
+    // the root is the first node
+    nodes: Vec<TrieNode>,
+    tokens: usize,
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    // the ids of the tokens that end here
+    tokens: Vec<usize>,
+}
+
+impl TokenTrie {
+    // `vocabulary` holds the bytes of every token by id
+    pub fn new(vocabulary: &[Vec<u8>]) -> TokenTrie {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in vocabulary.iter().enumerate().filter(|(_, bytes)| !bytes.is_empty()) {
+            let mut node = 0;
+            for &b in bytes {
+                node = match nodes[node].children.iter().find(|(byte, _)| *byte == b) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((b, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id);
+        }
+        TokenTrie { nodes, tokens: vocabulary.len() }
+    }
+
+    // By token id, whether `constraint` allows it next, like JsonConstraint::allows
+    pub fn allowed(&self, constraint: &JsonConstraint) -> Vec<bool> {
+        let mut allowed = vec![false; self.tokens];
+        self.walk(0, &constraint.cursor(), &mut allowed);
+        allowed
+    }
+
+    fn walk(&self, node: usize, cursor: &Cursor, allowed: &mut [bool]) {
+        for &(b, child) in &self.nodes[node].children {
+            let mut next = cursor.clone();
+            if next.push_byte(b) {
+                for &token in &self.nodes[child].tokens {
+                    allowed[token] = true;
+                }
+                self.walk(child, &next, allowed);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+pub use sampler::ConstrainedSampler;
+
+#[cfg(feature = "ssr")]
+mod sampler {
+    use super::{JsonConstraint, TokenTrie};
+    use llm::TokenId;
+    use llm_samplers::prelude::{HasSamplerResources, Logits, Sampler, SamplerError};
+    use std::sync::{Arc, Mutex};
+
+    // Masks the tokens the constraint rejects and lets `inner` pick from the others.
+    // The picked token advances the constraint.
+    #[derive(Debug)]
+    pub struct ConstrainedSampler {
+        constraint: Arc<Mutex<JsonConstraint>>,
+        // the bytes of every token of the model
+        vocabulary: Vec<Vec<u8>>,
+        trie: TokenTrie,
+        eot: TokenId,
+        inner: Arc<Mutex<dyn Sampler<TokenId, f32>>>,
+    }
+
+    impl ConstrainedSampler {
+        pub fn new(
+            model: &dyn llm::Model,
+            constraint: Arc<Mutex<JsonConstraint>>,
+            inner: Arc<Mutex<dyn Sampler<TokenId, f32>>>,
+        ) -> ConstrainedSampler {
+            let tokenizer = model.tokenizer();
+            let vocabulary: Vec<Vec<u8>> = (0..tokenizer.len()).map(|id| tokenizer.token(id)).collect();
+            let trie = TokenTrie::new(&vocabulary);
+            ConstrainedSampler { constraint, vocabulary, trie, eot: model.eot_token_id(), inner }
+        }
+
+        fn mask(&self, logits: &mut Logits<TokenId, f32>) {
+            let constraint = self.constraint.lock().unwrap();
+            let tokens = self.trie.allowed(&constraint);
+            for logit in logits.iter_mut() {
+                let allowed = if logit.token_id == self.eot {
+                    constraint.can_end()
+                } else {
+                    tokens.get(logit.token_id as usize).copied().unwrap_or(false)
+                };
+                if !allowed {
+                    logit.logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+
+    impl Sampler<TokenId, f32> for ConstrainedSampler {
+        fn sample<'a>(
+            &mut self,
+            res: &mut dyn HasSamplerResources<TokenId = TokenId>,
+            logits: &'a mut Logits<TokenId, f32>,
+        ) -> Result<&'a mut Logits<TokenId, f32>, SamplerError> {
+            self.mask(logits);
+            self.inner.lock().unwrap().sample(res, logits)
+        }
+
+        fn sampled_token_id(&self) -> Option<TokenId> {
+            self.inner.lock().unwrap().sampled_token_id()
+        }
+
+        fn sample_token(
+            &mut self,
+            res: &mut dyn HasSamplerResources<TokenId = TokenId>,
+            logits: &mut Logits<TokenId, f32>,
+        ) -> Result<Option<TokenId>, SamplerError> {
+            self.mask(logits);
+            let token = self.inner.lock().unwrap().sample_token(res, logits)?;
+            if let Some(token) = token.filter(|&token| token != self.eot) {
+                if let Some(bytes) = self.vocabulary.get(token as usize) {
+                    self.constraint.lock().unwrap().push(bytes);
+                }
+            }
+            Ok(token)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn constraint(schema: Value) -> JsonConstraint {
+        JsonConstraint::new(Schema::compile(&schema).unwrap())
+    }
+
+    // Feeds `tokens` one by one, the index of the first rejected one or None
+    fn feed(constraint: &mut JsonConstraint, tokens: &[&str]) -> Option<usize> {
+        tokens.iter().position(|token| !constraint.push(token.as_bytes()))
+    }
+
+    #[test]
+    fn any_json_object() {
+        let mut json = JsonConstraint::new(Schema::any_object());
+        assert!(!json.allows(b"[1]"));
+        assert!(!json.allows(b"hello"));
+        assert_eq!(feed(&mut json, &[" {", "\"a\"", ": [1, ", "-2.5e3, tr", "ue, null], \"b\"", ":{}", "}"]), None);
+        assert!(json.is_complete());
+        assert!(!json.allows(b","));
+        assert!(json.allows(b"\n"));
+    }
+
+    #[test]
+    fn rejected_tokens_leave_the_state_alone() {
+        let mut json = JsonConstraint::new(Schema::any_object());
+        assert!(json.push(b"{\"a\":"));
+        assert!(!json.push(b" 01"));
+        assert!(!json.push(b"}"));
+        assert!(json.push(b"\"x\"}"));
+        assert!(json.is_complete());
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        let mut json = constraint(json!({ "type": "string" }));
+        assert!(!json.allows(b"\"a\nb\""));
+        assert!(!json.allows(b"\"\\x\""));
+        assert!(!json.allows(b"\"\\u12g4\""));
+        assert_eq!(feed(&mut json, &["\"quote \\\" ", "\\u00e9 ", "grün", "\""]), None);
+        assert!(json.is_complete());
+    }
+
+    #[test]
+    fn numbers_follow_the_json_grammar() {
+        let mut integer = constraint(json!({ "type": "integer" }));
+        assert!(!integer.allows(b"1.5"));
+        assert!(!integer.allows(b"+1"));
+        assert!(integer.push(b"-12"));
+        assert!(integer.can_end());
+        assert!(!integer.is_complete());
+
+        let number = constraint(json!({ "type": "number" }));
+        for valid in ["0", "0.5", "-3e-7", "12.25E+2"] {
+            assert!(number.allows(valid.as_bytes()), "{valid}");
+        }
+        for invalid in ["01", "1.", "-", ".5", "1e"] {
+            let mut json = number.clone();
+            assert!(!(json.push(invalid.as_bytes()) && json.can_end()), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn object_keys_come_from_the_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+            "required": ["name"],
+            "additionalProperties": false
+        });
+        let mut json = constraint(schema);
+        assert!(json.push(b"{\"na"));
+        assert!(!json.allows(b"x"));
+        assert!(json.push(b"me\": \"Ada\""));
+        // age is optional, but it can't come twice and nothing else can come
+        assert!(json.allows(b"}"));
+        assert!(!json.allows(b", \"nickname\""));
+        assert!(json.push(b", \"age\": 36"));
+        assert!(!json.allows(b","));
+        assert!(!json.allows(b".5"));
+        assert!(json.push(b"}"));
+        assert!(json.is_complete());
+    }
+
+    #[test]
+    fn required_properties_keep_the_object_open() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "ok": { "type": "boolean" } },
+            "required": ["ok"]
+        });
+        let mut json = constraint(schema);
+        assert!(!json.allows(b"{}"));
+        assert!(json.push(b"{\"extra\": [1, {\"x\": null}], "));
+        assert!(!json.allows(b"}"));
+        assert!(!json.allows(b"\"ok\": yes"));
+        assert_eq!(feed(&mut json, &["\"ok\"", ":", " fal", "se", "}"]), None);
+        assert!(json.is_complete());
+    }
+
+    #[test]
+    fn arrays_enums_and_alternatives() {
+        let schema = json!({
+            "type": "array",
+            "items": { "anyOf": [{ "enum": ["red", "green", 1, 10] }, { "type": "null" }] }
+        });
+        let mut json = constraint(schema);
+        assert!(!json.allows(b"[\"blue\""));
+        assert!(!json.allows(b"[2"));
+        assert_eq!(feed(&mut json, &["[", "\"red\"", ", 1", "0, 1", ", null", " ]"]), None);
+        assert!(json.is_complete());
+    }
+
+    #[test]
+    fn whitespace_runs_are_bounded() {
+        let mut json = JsonConstraint::new(Schema::any_object());
+        let padding = " ".repeat(MAX_WHITESPACE);
+        assert!(json.push(format!("{{{padding}").as_bytes()));
+        assert!(!json.allows(b" "));
+        assert!(!json.allows(b"\n\"a\""));
+        // spaces inside strings are text, and anything else starts a new run
+        assert!(json.push(b"\"a          b\":"));
+        assert!(json.push(padding.as_bytes()));
+        assert!(json.push(b"1}"));
+        assert!(json.is_complete());
+    }
+
+    #[test]
+    fn the_trie_agrees_with_single_tokens() {
+        let vocabulary: Vec<Vec<u8>> = ["", "{", "{\"", "\"", "a", "ab", "\":", " 1", "1}", "}", "tr", "true", "  ", "x"]
+            .iter()
+            .map(|token| token.as_bytes().to_vec())
+            .collect();
+        let trie = TokenTrie::new(&vocabulary);
+        let mut json = JsonConstraint::new(Schema::any_object());
+        for token in ["{\"", "ab", "\":", " 1", "}"] {
+            let expected: Vec<bool> = vocabulary.iter().map(|bytes| json.allows(bytes)).collect();
+            assert_eq!(trie.allowed(&json), expected, "before {token}");
+            assert!(json.push(token.as_bytes()));
+        }
+        assert!(json.is_complete());
+    }
+
+    #[test]
+    fn unsupported_and_invalid_schemas() {
+        assert_eq!(
+            Schema::compile(&json!({ "$ref": "#/definitions/a" })),
+            Err(GrammarError::Unsupported("$ref".to_string()))
+        );
+        assert!(Schema::compile(&json!({ "type": "date" })).is_err());
+        assert!(Schema::compile(&json!({ "enum": [] })).is_err());
+        let impossible = json!({ "type": "object", "required": ["a"], "additionalProperties": false });
+        assert!(Schema::compile(&impossible).is_err());
+    }
+
+    #[test]
+    fn response_formats_deserialize_like_openai() {
+        let format: ResponseFormat = serde_json::from_value(json!({ "type": "json_object" })).unwrap();
+        assert_eq!(format, ResponseFormat::JsonObject);
+        let format: ResponseFormat = serde_json::from_value(json!({
+            "type": "json_schema",
+            "json_schema": { "name": "answer", "schema": { "type": "string" } }
+        }))
+        .unwrap();
+        assert!(format.constraint().unwrap().is_some());
+        assert!(ResponseFormat::Text.constraint().unwrap().is_none());
+    }
+}
@@ -0,0 +1,455 @@
+// Checks the user's message before inference and Jippity's answer after it. Every rule
+// blocks the text, redacts what it matched or flags the message for review; the
+// decisions go to the moderation_audit table.
+use crate::app::ssr::AppState;
+use crate::components::jippity::{Conversation, Message};
+use crate::inference::cache::CacheMode;
+use crate::inference::config::{ClassifierConfig, ConfigError, ModerationConfig};
+use crate::inference::engine::{run_generation, GenerationError, GenerationJob};
+use crate::inference::events::ChatEvent;
+use crate::inference::grammar::ResponseFormat;
+use crate::inference::registry::RegistryError;
+use crate::inference::sampling::SamplingSettings;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+// the classifier only has to say yes or no
+const CLASSIFIER_MAX_TOKENS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    // the user's message, before inference
+    Input,
+    // Jippity's answer, after inference
+    Output,
+}
+
+impl Stage {
+    pub fn both() -> Vec<Stage> {
+        vec![Stage::Input, Stage::Output]
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Input => "input",
+            Stage::Output => "output",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Block,
+    Redact,
+    Flag,
+}
+
+impl ModerationAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModerationAction::Block => "block",
+            ModerationAction::Redact => "redact",
+            ModerationAction::Flag => "flag",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PiiKind {
+    Email,
+    Phone,
+    Iban,
+}
+
+impl PiiKind {
+    fn name(self) -> &'static str {
+        match self {
+            PiiKind::Email => "email",
+            PiiKind::Phone => "phone",
+            PiiKind::Iban => "iban",
+        }
+    }
+
+    fn pattern(self) -> &'static str {
+        match self {
+            PiiKind::Email => r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+            PiiKind::Phone => r"(?:\+\d{1,3}[ .-]?\(?|\(|\b)\d{1,5}\)?(?:[ .-]?\d{2,5}){1,5}\b",
+            PiiKind::Iban => r"\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]{4}){2,7}(?: ?[A-Z0-9]{1,3})?\b",
+        }
+    }
+
+    // weeds out the numbers the patterns match too
+    fn is_match(self, found: &str) -> bool {
+        match self {
+            PiiKind::Email => true,
+            PiiKind::Phone => is_phone_number(found),
+            PiiKind::Iban => is_iban(found),
+        }
+    }
+}
+
+// 9 to 15 digits, 7 are enough with a country or area code in front. Dates
+// (8 digits) and short numbers are left alone.
+fn is_phone_number(found: &str) -> bool {
+    let digits = found.chars().filter(char::is_ascii_digit).count();
+    let min = if found.starts_with(['+', '(']) { 7 } else { 9 };
+    (min..=15).contains(&digits)
+}
+
+// ISO 13616: the country code and check digits moved to the end, letters as numbers
+// from A = 10, the whole number modulo 97 is 1
+fn is_iban(found: &str) -> bool {
+    let compact: String = found.chars().filter(|c| !c.is_whitespace()).collect();
+    if !(15..=34).contains(&compact.len()) {
+        return false;
+    }
+    let (head, tail) = compact.split_at(4);
+    let mut remainder = 0;
+    for c in tail.chars().chain(head.chars()) {
+        let Some(value) = c.to_digit(36) else {
+            return false;
+        };
+        let shift = if value < 10 { 10 } else { 100 };
+        remainder = (remainder * shift + value) % 97;
+    }
+    remainder == 1
+}
+
+// What one rule decided about a text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub rule: String,
+    pub action: ModerationAction,
+    pub matches: usize,
+}
+
+// The text with the redactions applied, and every rule that matched
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checked {
+    pub text: String,
+    pub decisions: Vec<Decision>,
+}
+
+impl Checked {
+    pub fn blocked(&self) -> bool {
+        self.decisions.iter().any(|decision| decision.action == ModerationAction::Block)
+    }
+
+    // the rules that blocked the text, for the error message
+    pub fn blocked_by(&self) -> String {
+        let rules: Vec<&str> = self
+            .decisions
+            .iter()
+            .filter(|decision| decision.action == ModerationAction::Block)
+            .map(|decision| decision.rule.as_str())
+            .collect();
+        rules.join(", ")
+    }
+}
+
+struct Rule {
+    name: String,
+    pattern: Regex,
+    // a second look at every match, for checksums and digit counts
+    is_match: Box<dyn Fn(&str) -> bool + Send + Sync>,
+    action: ModerationAction,
+    stages: Vec<Stage>,
+}
+
+// The compiled rules of the [moderation] section
+#[derive(Default)]
+pub struct Moderator {
+    rules: Vec<Rule>,
+    classifier: Option<ClassifierConfig>,
+}
+
+impl Moderator {
+    pub fn new(config: &ModerationConfig) -> Result<Moderator, ConfigError> {
+        if !config.enabled {
+            return Ok(Moderator::default());
+        }
+        let invalid = |name: &str, err: regex::Error| ConfigError::InvalidModeration(format!("rule {name}: {err}"));
+        let mut rules = Vec::new();
+
+        let words: Vec<String> = config
+            .blocklist
+            .words
+            .iter()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .map(regex::escape)
+            .collect();
+        if !words.is_empty() {
+            let pattern = format!(r"(?i)\b(?:{})\b", words.join("|"));
+            rules.push(Rule {
+                name: "blocklist".to_string(),
+                pattern: Regex::new(&pattern).map_err(|err| invalid("blocklist", err))?,
+                is_match: Box::new(|_| true),
+                action: config.blocklist.action,
+                stages: config.blocklist.stages.clone(),
+            });
+        }
+        for rule in &config.rules {
+            rules.push(Rule {
+                name: rule.name.clone(),
+                pattern: Regex::new(&rule.pattern).map_err(|err| invalid(&rule.name, err))?,
+                is_match: Box::new(|_| true),
+                action: rule.action,
+                stages: rule.stages.clone(),
+            });
+        }
+        for pii in &config.pii {
+            let kind = pii.kind;
+            rules.push(Rule {
+                name: kind.name().to_string(),
+                pattern: Regex::new(kind.pattern()).expect("the PII patterns are valid"),
+                is_match: Box::new(move |found| kind.is_match(found)),
+                action: pii.action,
+                stages: pii.stages.clone(),
+            });
+        }
+
+        let classifier = config.classifier.enabled.then(|| config.classifier.clone());
+        if classifier.as_ref().is_some_and(|classifier| classifier.action == ModerationAction::Redact) {
+            return Err(ConfigError::InvalidModeration(
+                "the classifier can't redact, its action is block or flag".to_string(),
+            ));
+        }
+        Ok(Moderator { rules, classifier })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.classifier.is_none()
+    }
+
+    // Answers that may still be blocked or redacted can't be streamed token by token
+    pub fn holds_output(&self) -> bool {
+        let rules = self.rules.iter().map(|rule| (rule.action, &rule.stages));
+        let classifier = self.classifier.iter().map(|classifier| (classifier.action, &classifier.stages));
+        rules
+            .chain(classifier)
+            .any(|(action, stages)| action != ModerationAction::Flag && stages.contains(&Stage::Output))
+    }
+
+    pub fn classifier(&self, stage: Stage) -> Option<&ClassifierConfig> {
+        self.classifier.as_ref().filter(|classifier| classifier.stages.contains(&stage))
+    }
+
+    // Runs the rules in their order, a rule sees the redactions of the ones before it
+    pub fn check(&self, stage: Stage, text: &str) -> Checked {
+        let mut text = text.to_string();
+        let mut decisions = Vec::new();
+        for rule in self.rules.iter().filter(|rule| rule.stages.contains(&stage)) {
+            let found: Vec<_> = rule.pattern.find_iter(&text).filter(|found| (rule.is_match)(found.as_str())).collect();
+            if found.is_empty() {
+                continue;
+            }
+            decisions.push(Decision { rule: rule.name.clone(), action: rule.action, matches: found.len() });
+            if rule.action == ModerationAction::Redact {
+                let mut redacted = String::with_capacity(text.len());
+                let mut last = 0;
+                for found in &found {
+                    redacted.push_str(&text[last..found.start()]);
+                    redacted.push_str(&format!("[{} removed]", rule.name));
+                    last = found.end();
+                }
+                redacted.push_str(&text[last..]);
+                text = redacted;
+            }
+        }
+        Checked { text, decisions }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClassifierError {
+    #[error(transparent)]
+    Registry(#[from] RegistryError),
+    #[error(transparent)]
+    Generation(#[from] GenerationError),
+    #[error("The classifier answered neither yes nor no: {0:?}")]
+    Unclear(String),
+}
+
+// "yes" when the text is unsafe, anything else that isn't a "no" is unclear
+pub fn parse_verdict(answer: &str) -> Option<bool> {
+    let word: String = answer.trim_start().chars().take_while(|c| c.is_alphabetic()).collect();
+    match word.to_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+// Asks the local model whether `text` is unsafe. The question runs through the queue
+// like any answer and counts against the user's quota.
+pub async fn classify(
+    state: &AppState,
+    user_id: i32,
+    classifier: &ClassifierConfig,
+    text: &str,
+) -> Result<bool, ClassifierError> {
+    let model_config = match &classifier.model {
+        Some(id) => state.config.model(id).expect("checked when the config was loaded"),
+        None => state.config.default_model(),
+    };
+    let template = state.config.template(&model_config.template).expect("checked when the config was loaded");
+    let model = state.models.get(&model_config.id).await?;
+    let question = Conversation {
+        messages: vec![Message::user(classifier.prompt.replace("{text}", text))],
+        ..Conversation::new()
+    };
+    let job = GenerationJob {
+        user_id,
+        model_id: model_config.id.clone(),
+        model,
+        prompt: template.render(None, &question),
+        stop_sequences: template.stop_sequences.clone(),
+        max_tokens: CLASSIFIER_MAX_TOKENS,
+        // the same text always gets the same verdict, and a cached one
+        sampling: SamplingSettings { temperature: Some(0.0), ..Default::default() },
+        session_key: None,
+        session_bytes: model_config.session_bytes(),
+        session_config: model_config.session_config(),
+        tool_calls: false,
+        format: ResponseFormat::Text,
+        cache: CacheMode::Read,
+    };
+    let generation = Arc::new(state.generations.start(user_id));
+    // the few tokens fit into the channel, nobody has to read them
+    let (tx, _events) = mpsc::channel(16);
+    let output = run_generation(state, job, &generation, tx).await?;
+    parse_verdict(&output.text).ok_or(ClassifierError::Unclear(output.text))
+}
+
+// The rules and, if it covers `stage`, the classifier. A classifier that fails lets the
+// text through, the error is logged.
+pub async fn moderate(state: &AppState, user_id: i32, stage: Stage, text: &str) -> Checked {
+    let mut checked = state.moderation.check(stage, text);
+    if let Some(classifier) = state.moderation.classifier(stage) {
+        match classify(state, user_id, classifier, &checked.text).await {
+            Ok(true) => checked.decisions.push(Decision {
+                rule: "classifier".to_string(),
+                action: classifier.action,
+                matches: 1,
+            }),
+            Ok(false) => {}
+            Err(err) => eprintln!("Moderation classifier failed for user {user_id}: {err}"),
+        }
+    }
+    checked
+}
+
+// Passes everything but the tokens on to `tx`, for answers that are only sent once the
+// moderation rules have checked them
+pub fn hold_tokens(tx: mpsc::Sender<ChatEvent>) -> mpsc::Sender<ChatEvent> {
+    let (held_tx, mut held_rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                event = held_rx.recv() => match event {
+                    Some(ChatEvent::Token(_)) => {}
+                    Some(event) => {
+                        let _ = tx.send(event).await;
+                    }
+                    None => break,
+                },
+                // dropping `held_rx` stops the generation like a closed stream does
+                _ = tx.closed() => break,
+            }
+        }
+    });
+    held_tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::config::{BlocklistConfig, PiiRuleConfig, RegexRuleConfig};
+
+    fn moderator(config: ModerationConfig) -> Moderator {
+        Moderator::new(&ModerationConfig { enabled: true, ..config }).unwrap()
+    }
+
+    #[test]
+    fn pii_is_redacted() {
+        let moderator = moderator(ModerationConfig::default());
+        let checked = moderator.check(
+            Stage::Input,
+            "Mail jane.doe@example.com or call +49 30 1234567, pay to DE89 3704 0044 0532 0130 00 by 2024-05-01",
+        );
+        assert_eq!(
+            checked.text,
+            "Mail [email removed] or call [phone removed], pay to [iban removed] by 2024-05-01"
+        );
+        let rules: Vec<_> = checked.decisions.iter().map(|decision| decision.rule.as_str()).collect();
+        assert_eq!(rules, ["email", "iban", "phone"]);
+        assert!(!checked.blocked());
+    }
+
+    #[test]
+    fn numbers_that_only_look_like_pii_are_kept() {
+        let moderator = moderator(ModerationConfig::default());
+        // wrong IBAN check digits, a date, a short number
+        let text = "DE88 3704 0044 0532 0130 00 on 2024-05-01, order 12345";
+        let checked = moderator.check(Stage::Output, text);
+        assert_eq!(checked.text, text);
+        assert!(checked.decisions.is_empty());
+        assert!(is_phone_number("(555) 123-4567"));
+        assert!(is_iban("GB82WEST12345698765432"));
+    }
+
+    #[test]
+    fn rules_only_run_at_their_stages() {
+        let moderator = moderator(ModerationConfig {
+            blocklist: BlocklistConfig {
+                words: vec!["Secret Plan".to_string()],
+                action: ModerationAction::Block,
+                stages: vec![Stage::Input],
+            },
+            rules: vec![RegexRuleConfig {
+                name: "ticket".to_string(),
+                pattern: r"TICKET-\d+".to_string(),
+                action: ModerationAction::Flag,
+                stages: Stage::both(),
+            }],
+            pii: Vec::new(),
+            ..Default::default()
+        });
+        let input = moderator.check(Stage::Input, "About the secret plan in TICKET-12");
+        assert!(input.blocked());
+        assert_eq!(input.blocked_by(), "blocklist");
+        let ticket = Decision { rule: "ticket".to_string(), action: ModerationAction::Flag, matches: 1 };
+        assert_eq!(input.decisions[1], ticket);
+
+        let output = moderator.check(Stage::Output, "About the secret plan in TICKET-12");
+        assert!(!output.blocked());
+        assert_eq!(output.decisions.len(), 1);
+        // whole words only
+        assert!(moderator.check(Stage::Input, "secret planning").decisions.is_empty());
+    }
+
+    #[test]
+    fn only_blocking_or_redacting_output_rules_hold_the_answer_back() {
+        let flag = PiiRuleConfig { kind: PiiKind::Email, action: ModerationAction::Flag, stages: Stage::both() };
+        assert!(!moderator(ModerationConfig { pii: vec![flag.clone()], ..Default::default() }).holds_output());
+        let redact_input = PiiRuleConfig { action: ModerationAction::Redact, stages: vec![Stage::Input], ..flag };
+        assert!(!moderator(ModerationConfig { pii: vec![redact_input.clone()], ..Default::default() }).holds_output());
+        let redact = PiiRuleConfig { stages: Stage::both(), ..redact_input };
+        assert!(moderator(ModerationConfig { pii: vec![redact], ..Default::default() }).holds_output());
+        assert!(Moderator::new(&ModerationConfig::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn classifier_verdicts() {
+        assert_eq!(parse_verdict(" Yes."), Some(true));
+        assert_eq!(parse_verdict("no, it is fine"), Some(false));
+        assert_eq!(parse_verdict("Maybe"), None);
+    }
+}
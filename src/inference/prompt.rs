@@ -0,0 +1,247 @@
+use crate::components::jippity::{Conversation, ToolInvocation};
+use serde::{Deserialize, Serialize};
+
+// How tool calls and their results appear in the prompt, see inference::tools
+pub const TOOL_CALL_OPEN: &str = "<tool_call>";
+pub const TOOL_CALL_CLOSE: &str = "</tool_call>";
+pub const TOOL_RESULT_OPEN: &str = "<tool_result>";
+pub const TOOL_RESULT_CLOSE: &str = "</tool_result>";
+
+// A prompt template describes how a conversation is laid out for a given model family.
+// Every turn is wrapped in a prefix/suffix pair, and the template knows which strings
+// mark the end of the assistant's answer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptTemplate {
+    pub system_prefix: String,
+    pub system_suffix: String,
+    pub user_prefix: String,
+    pub user_suffix: String,
+    pub assistant_prefix: String,
+    pub assistant_suffix: String,
+    // Llama-2 has no dedicated system turn, the system prompt lives inside the first [INST]
+    pub system_in_first_user: bool,
+    pub stop_sequences: Vec<String>,
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        PromptTemplate::plain()
+    }
+}
+
+impl PromptTemplate {
+    pub fn builtin(name: &str) -> Option<PromptTemplate> {
+        match name.to_ascii_lowercase().as_str() {
+            "chatml" => Some(PromptTemplate::chatml()),
+            "llama2" | "llama-2" => Some(PromptTemplate::llama2()),
+            "alpaca" => Some(PromptTemplate::alpaca()),
+            "plain" => Some(PromptTemplate::plain()),
+            _ => None,
+        }
+    }
+
+    pub fn chatml() -> PromptTemplate {
+        PromptTemplate {
+            system_prefix: "<|im_start|>system\n".to_string(),
+            system_suffix: "<|im_end|>\n".to_string(),
+            user_prefix: "<|im_start|>user\n".to_string(),
+            user_suffix: "<|im_end|>\n".to_string(),
+            assistant_prefix: "<|im_start|>assistant\n".to_string(),
+            assistant_suffix: "<|im_end|>\n".to_string(),
+            system_in_first_user: false,
+            stop_sequences: vec!["<|im_end|>".to_string(), "<|im_start|>".to_string()],
+        }
+    }
+
+    pub fn llama2() -> PromptTemplate {
+        PromptTemplate {
+            system_prefix: "<<SYS>>\n".to_string(),
+            system_suffix: "\n<</SYS>>\n\n".to_string(),
+            user_prefix: "<s>[INST] ".to_string(),
+            user_suffix: " [/INST]".to_string(),
+            assistant_prefix: " ".to_string(),
+            assistant_suffix: " </s>".to_string(),
+            system_in_first_user: true,
+            stop_sequences: vec!["</s>".to_string(), "[INST]".to_string()],
+        }
+    }
+
+    pub fn alpaca() -> PromptTemplate {
+        PromptTemplate {
+            system_prefix: String::new(),
+            system_suffix: "\n\n".to_string(),
+            user_prefix: "### Instruction:\n".to_string(),
+            user_suffix: "\n\n".to_string(),
+            assistant_prefix: "### Response:\n".to_string(),
+            assistant_suffix: "\n\n".to_string(),
+            system_in_first_user: false,
+            stop_sequences: vec!["### Instruction:".to_string()],
+        }
+    }
+
+    pub fn plain() -> PromptTemplate {
+        PromptTemplate {
+            system_prefix: String::new(),
+            system_suffix: "\n".to_string(),
+            user_prefix: "User: ".to_string(),
+            user_suffix: "\n".to_string(),
+            assistant_prefix: "Jippity: ".to_string(),
+            assistant_suffix: "\n".to_string(),
+            system_in_first_user: false,
+            stop_sequences: vec!["User:".to_string()],
+        }
+    }
+
+    // Renders the whole conversation and leaves the prompt open at the assistant's turn
+    pub fn render(&self, system_prompt: Option<&str>, conversation: &Conversation) -> String {
+        let mut prompt = String::new();
+        let mut pending_system = system_prompt.filter(|s| !s.trim().is_empty());
+
+        if !self.system_in_first_user {
+            if let Some(system) = pending_system.take() {
+                self.push_system(&mut prompt, system);
+            }
+        }
+
+        for message in conversation.messages.iter() {
+            if let Some(tool) = &message.tool {
+                self.push_tool(&mut prompt, tool);
+            } else if message.from_llm {
+                prompt.push_str(&self.assistant_prefix);
+                prompt.push_str(&message.text);
+                prompt.push_str(&self.assistant_suffix);
+            } else {
+                prompt.push_str(&self.user_prefix);
+                if let Some(system) = pending_system.take() {
+                    self.push_system(&mut prompt, system);
+                }
+                prompt.push_str(&message.text);
+                prompt.push_str(&self.user_suffix);
+            }
+        }
+
+        prompt.push_str(&self.assistant_prefix);
+        prompt
+    }
+
+    fn push_system(&self, prompt: &mut String, system: &str) {
+        prompt.push_str(&self.system_prefix);
+        prompt.push_str(system);
+        prompt.push_str(&self.system_suffix);
+    }
+
+    // The call as an assistant turn, the result handed back in a user turn
+    fn push_tool(&self, prompt: &mut String, tool: &ToolInvocation) {
+        let name = serde_json::to_string(&tool.name).expect("strings always serialize");
+        prompt.push_str(&self.assistant_prefix);
+        prompt.push_str(&format!("{TOOL_CALL_OPEN}{{\"name\": {name}, \"arguments\": {}}}{TOOL_CALL_CLOSE}", tool.arguments));
+        prompt.push_str(&self.assistant_suffix);
+        prompt.push_str(&self.user_prefix);
+        prompt.push_str(&format!("{TOOL_RESULT_OPEN}{}{TOOL_RESULT_CLOSE}", tool.result));
+        prompt.push_str(&self.user_suffix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::jippity::Message;
+
+    fn conversation() -> Conversation {
+        Conversation {
+            id: None,
+            model: None,
+            collection: None,
+            persona: None,
+            messages: vec![
+                Message::user("Hi"),
+                Message::llm("Hello!"),
+                Message::user("What is Rust?"),
+            ],
+        }
+    }
+
+    #[test]
+    fn renders_chatml() {
+        let prompt = PromptTemplate::chatml().render(Some("Be brief."), &conversation());
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n\
+             <|im_start|>user\nWhat is Rust?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn renders_llama2_with_system_in_first_instruction() {
+        let prompt = PromptTemplate::llama2().render(Some("Be brief."), &conversation());
+        assert_eq!(
+            prompt,
+            "<s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s>\
+             <s>[INST] What is Rust? [/INST] "
+        );
+    }
+
+    #[test]
+    fn renders_alpaca() {
+        let prompt = PromptTemplate::alpaca().render(None, &conversation());
+        assert_eq!(
+            prompt,
+            "### Instruction:\nHi\n\n### Response:\nHello!\n\n\
+             ### Instruction:\nWhat is Rust?\n\n### Response:\n"
+        );
+    }
+
+    #[test]
+    fn plain_labels_llm_messages_as_jippity() {
+        let prompt = PromptTemplate::plain().render(None, &conversation());
+        assert_eq!(prompt, "User: Hi\nJippity: Hello!\nUser: What is Rust?\nJippity: ");
+    }
+
+    #[test]
+    fn blank_system_prompt_is_skipped() {
+        let prompt = PromptTemplate::chatml().render(Some("  "), &Conversation::new());
+        assert_eq!(prompt, "<|im_start|>assistant\n");
+    }
+
+    #[test]
+    fn custom_template_uses_its_own_markers() {
+        let template = PromptTemplate {
+            user_prefix: "<user>".to_string(),
+            user_suffix: "</user>".to_string(),
+            assistant_prefix: "<bot>".to_string(),
+            assistant_suffix: "</bot>".to_string(),
+            stop_sequences: vec!["<user>".to_string()],
+            ..PromptTemplate::plain()
+        };
+        let prompt = template.render(None, &conversation());
+        assert_eq!(prompt, "<user>Hi</user><bot>Hello!</bot><user>What is Rust?</user><bot>");
+    }
+
+    #[test]
+    fn renders_tool_calls_and_results() {
+        let mut conv = conversation();
+        conv.messages.push(Message::tool(ToolInvocation {
+            name: "calculator".to_string(),
+            arguments: r#"{"expression":"6*7"}"#.to_string(),
+            result: "42".to_string(),
+        }));
+        let prompt = PromptTemplate::plain().render(None, &conv);
+        assert!(prompt.ends_with(
+            "User: What is Rust?\n\
+             Jippity: <tool_call>{\"name\": \"calculator\", \"arguments\": {\"expression\":\"6*7\"}}</tool_call>\n\
+             User: <tool_result>42</tool_result>\n\
+             Jippity: "
+        ));
+    }
+
+    #[test]
+    fn builtin_lookup_is_case_insensitive() {
+        assert_eq!(PromptTemplate::builtin("ChatML"), Some(PromptTemplate::chatml()));
+        assert_eq!(PromptTemplate::builtin("llama-2"), Some(PromptTemplate::llama2()));
+        assert_eq!(PromptTemplate::builtin("vicuna"), None);
+    }
+}
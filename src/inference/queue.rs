@@ -0,0 +1,236 @@
+use crate::inference::config::QueueConfig;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("You already have {0} messages waiting for Jippity, please wait for them to finish")]
+    TooManyWaiting(usize),
+}
+
+// Whom a job belongs to, None is shared by all anonymous visitors
+pub type QueueKey = Option<i32>;
+
+// Work nobody waits for (embedding messages for the search) runs in the background:
+// it doesn't take a user's slot, goes after every waiting user job and only one
+// background job runs at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Slot {
+    User(QueueKey),
+    Background,
+}
+
+// Limits how many generations run on the shared model at once.
+// Waiting jobs are admitted fairly: the user with the fewest running jobs goes first,
+// arrival order breaks ties, so one busy user can't starve everybody else.
+pub struct InferenceQueue {
+    limits: QueueConfig,
+    state: Mutex<QueueState>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_ticket: u64,
+    running: HashMap<Slot, usize>,
+    running_total: usize,
+    // in arrival order
+    waiting: Vec<(u64, Slot)>,
+}
+
+// Held while a generation runs, frees the slot on drop
+pub struct QueuePermit {
+    queue: Arc<InferenceQueue>,
+    slot: Slot,
+}
+
+// Removes a ticket from the queue if the waiting request goes away (closed tab, stop button)
+struct WaitingTicket {
+    queue: Arc<InferenceQueue>,
+    ticket: u64,
+    admitted: bool,
+}
+
+impl QueueState {
+    fn running_for(&self, slot: &Slot) -> usize {
+        self.running.get(slot).copied().unwrap_or(0)
+    }
+
+    // the order in which the waiting tickets are going to be admitted
+    fn schedule(&self) -> Vec<u64> {
+        let mut running = self.running.clone();
+        let mut pending = self.waiting.clone();
+        let mut order = Vec::with_capacity(pending.len());
+
+        while !pending.is_empty() {
+            let next = pending
+                .iter()
+                .enumerate()
+                .min_by_key(|(arrival, (_, slot))| {
+                    (*slot == Slot::Background, running.get(slot).copied().unwrap_or(0), *arrival)
+                })
+                .map(|(arrival, _)| arrival)
+                .unwrap();
+            let (ticket, slot) = pending.remove(next);
+            *running.entry(slot).or_default() += 1;
+            order.push(ticket);
+        }
+        order
+    }
+
+    // whether `ticket` gets a slot now
+    fn admits(&self, limits: &QueueConfig, ticket: u64, slot: Slot) -> bool {
+        let per_slot = match slot {
+            Slot::User(_) => limits.max_per_user,
+            Slot::Background => 1,
+        };
+        self.schedule().first() == Some(&ticket)
+            && self.running_total < limits.max_concurrent
+            && self.running_for(&slot) < per_slot
+    }
+
+    fn enqueue(&mut self, slot: Slot) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiting.push((ticket, slot));
+        ticket
+    }
+
+    fn admit(&mut self, ticket: u64, slot: Slot) {
+        self.waiting.retain(|(t, _)| *t != ticket);
+        *self.running.entry(slot).or_default() += 1;
+        self.running_total += 1;
+    }
+}
+
+impl InferenceQueue {
+    pub fn new(limits: QueueConfig) -> InferenceQueue {
+        InferenceQueue {
+            limits,
+            state: Mutex::new(QueueState::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    // Waits for a free slot. `on_position` is called with the 1-based queue position
+    // whenever it changes, it is never called if a slot is free right away.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        key: QueueKey,
+        on_position: impl FnMut(usize),
+    ) -> Result<QueuePermit, QueueError> {
+        let slot = Slot::User(key);
+        let ticket = {
+            let mut state = self.state.lock().unwrap();
+            let waiting = state.waiting.iter().filter(|(_, waiting)| *waiting == slot).count();
+            if waiting >= self.limits.max_waiting_per_user {
+                return Err(QueueError::TooManyWaiting(waiting));
+            }
+            state.enqueue(slot)
+        };
+        Ok(self.wait(ticket, slot, on_position).await)
+    }
+
+    // Waits until no user job is waiting and no other background job runs
+    pub async fn acquire_background(self: &Arc<Self>) -> QueuePermit {
+        let ticket = self.state.lock().unwrap().enqueue(Slot::Background);
+        self.wait(ticket, Slot::Background, |_| {}).await
+    }
+
+    async fn wait(self: &Arc<Self>, ticket: u64, slot: Slot, mut on_position: impl FnMut(usize)) -> QueuePermit {
+        let mut waiting = WaitingTicket { queue: self.clone(), ticket, admitted: false };
+        let mut last_position = None;
+
+        loop {
+            // register for wake ups before looking at the state, so no change is missed
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.admits(&self.limits, ticket, slot) {
+                    state.admit(ticket, slot);
+                    waiting.admitted = true;
+                    drop(state);
+
+                    // everybody behind us moved up one position
+                    self.changed.notify_waiters();
+                    return QueuePermit { queue: self.clone(), slot };
+                }
+
+                let position = state
+                    .schedule()
+                    .iter()
+                    .position(|t| *t == ticket)
+                    .expect("waiting ticket is scheduled");
+                if last_position != Some(position) {
+                    last_position = Some(position);
+                    on_position(position + 1);
+                }
+            }
+
+            notified.await;
+        }
+    }
+}
+
+impl Drop for QueuePermit {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        if let Some(running) = state.running.get_mut(&self.slot) {
+            *running -= 1;
+            if *running == 0 {
+                state.running.remove(&self.slot);
+            }
+        }
+        state.running_total -= 1;
+        drop(state);
+        self.queue.changed.notify_waiters();
+    }
+}
+
+impl Drop for WaitingTicket {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        self.queue.state.lock().unwrap().waiting.retain(|(t, _)| *t != self.ticket);
+        self.queue.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> QueueConfig {
+        QueueConfig { max_concurrent: 3, max_per_user: 1, max_waiting_per_user: 3 }
+    }
+
+    #[test]
+    fn background_jobs_wait_behind_every_user_job() {
+        let mut state = QueueState::default();
+        state.waiting = vec![(0, Slot::Background), (1, Slot::User(Some(1))), (2, Slot::User(Some(1)))];
+        assert_eq!(state.schedule(), vec![1, 2, 0]);
+        assert!(!state.admits(&limits(), 0, Slot::Background));
+        assert!(state.admits(&limits(), 1, Slot::User(Some(1))));
+    }
+
+    #[test]
+    fn background_jobs_take_no_user_slot() {
+        let mut state = QueueState::default();
+        state.waiting = vec![(0, Slot::Background), (1, Slot::Background), (2, Slot::User(Some(1)))];
+        state.admit(2, Slot::User(Some(1)));
+        // the user's one slot is taken, the index job of their message still runs
+        assert!(state.admits(&limits(), 0, Slot::Background));
+        state.admit(0, Slot::Background);
+        // one background job at a time, the last slot stays free for users
+        assert!(!state.admits(&limits(), 1, Slot::Background));
+        state.waiting.push((3, Slot::User(Some(2))));
+        assert_eq!(state.schedule(), vec![3, 1]);
+        assert!(state.admits(&limits(), 3, Slot::User(Some(2))));
+    }
+}
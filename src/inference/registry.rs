@@ -0,0 +1,288 @@
+use crate::components::jippity::ModelStatus;
+use crate::inference::config::{JippityConfig, ModelConfig};
+use llm::{Model, ModelArchitecture};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("Unknown model: {0}")]
+    UnknownModel(String),
+    #[error("Model {id} has an unknown architecture: {architecture}")]
+    UnknownArchitecture { id: String, architecture: String },
+    #[error("Failed to load model {id}: {message}")]
+    Load { id: String, message: String },
+    #[error("Model {id}: {path:?} {reason}")]
+    InvalidFile { id: String, path: std::path::PathBuf, reason: String },
+    #[error("Jippity is still loading the model {0}, please try again in a moment")]
+    Loading(String),
+    #[error("Jippity is unavailable, the model {0} failed to load")]
+    Unavailable(String),
+}
+
+// Every configured model, loaded on first use or in the background at startup.
+// When loading one more model would exceed the memory limit, the least recently used
+// idle models are unloaded.
+pub struct ModelRegistry {
+    specs: Vec<ModelConfig>,
+    memory_limit: u64,
+    loaded: Mutex<LoadedModels>,
+    // one load at a time, so two models are never read in parallel
+    load_lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct LoadedModels {
+    tick: u64,
+    models: HashMap<String, LoadedModel>,
+    // models being loaded or failed to load; a failed model stays failed until a restart
+    pending: HashMap<String, ModelStatus>,
+}
+
+struct LoadedModel {
+    model: Arc<dyn Model>,
+    bytes: u64,
+    last_used: u64,
+}
+
+impl LoadedModels {
+    fn touch(&mut self, id: &str) -> Option<Arc<dyn Model>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.models.get_mut(id).map(|loaded| {
+            loaded.last_used = tick;
+            loaded.model.clone()
+        })
+    }
+
+    fn bytes(&self) -> u64 {
+        self.models.values().map(|loaded| loaded.bytes).sum()
+    }
+}
+
+impl ModelRegistry {
+    pub fn new(config: &JippityConfig) -> ModelRegistry {
+        ModelRegistry {
+            specs: config.models.clone(),
+            memory_limit: config.registry.memory_limit_mb as u64 * 1024 * 1024,
+            loaded: Mutex::new(LoadedModels::default()),
+            load_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn status(&self, id: &str) -> ModelStatus {
+        let loaded = self.loaded.lock().unwrap();
+        if loaded.models.contains_key(id) {
+            return ModelStatus::Ready;
+        }
+        loaded.pending.get(id).cloned().unwrap_or(ModelStatus::Unloaded)
+    }
+
+    // Fails like `get` for a model that is loading or failed to load, without loading it
+    pub fn check_available(&self, id: &str) -> Result<(), RegistryError> {
+        match self.status(id) {
+            ModelStatus::Loading => Err(RegistryError::Loading(id.to_string())),
+            ModelStatus::Failed(_) => Err(RegistryError::Unavailable(id.to_string())),
+            ModelStatus::Unloaded | ModelStatus::Ready => Ok(()),
+        }
+    }
+
+    // Loads the models one after the other without blocking startup, failures are logged
+    pub fn preload(self: &Arc<Self>, ids: Vec<String>) {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            for id in ids {
+                match registry.get(&id).await {
+                    Ok(_) => tracing::info!(model = %id, "model ready"),
+                    Err(err) => tracing::error!(model = %id, "{err}"),
+                }
+            }
+        });
+    }
+
+    // A model that is still loading or failed to load is an error right away, so
+    // nobody waits on a load they didn't start
+    pub async fn get(self: &Arc<Self>, id: &str) -> Result<Arc<dyn Model>, RegistryError> {
+        let spec = self
+            .specs
+            .iter()
+            .find(|spec| spec.id == id)
+            .cloned()
+            .ok_or_else(|| RegistryError::UnknownModel(id.to_string()))?;
+        {
+            let mut loaded = self.loaded.lock().unwrap();
+            if let Some(model) = loaded.touch(id) {
+                return Ok(model);
+            }
+            match loaded.pending.get(id) {
+                Some(ModelStatus::Loading) => return Err(RegistryError::Loading(id.to_string())),
+                Some(ModelStatus::Failed(_)) => return Err(RegistryError::Unavailable(id.to_string())),
+                _ => {}
+            }
+            loaded.pending.insert(id.to_string(), ModelStatus::Loading);
+        }
+
+        // the load runs on even if the request that started it goes away
+        let registry = self.clone();
+        let load = tokio::spawn(async move {
+            let _loading = registry.load_lock.lock().await;
+            let id = spec.id.clone();
+            let bytes = model_bytes(&spec);
+            registry.make_room(bytes);
+            let result = tokio::task::spawn_blocking(move || get_language_model(&spec))
+                .await
+                .map_err(|err| RegistryError::Load { id: id.clone(), message: err.to_string() })
+                .and_then(|result| result);
+
+            let mut loaded = registry.loaded.lock().unwrap();
+            match &result {
+                Ok(model) => {
+                    loaded.pending.remove(&id);
+                    loaded.models.insert(id.clone(), LoadedModel { model: model.clone(), bytes, last_used: 0 });
+                    loaded.touch(&id);
+                }
+                Err(err) => {
+                    loaded.pending.insert(id.clone(), ModelStatus::Failed(err.to_string()));
+                }
+            }
+            result
+        });
+        load.await.map_err(|err| RegistryError::Load { id: id.to_string(), message: err.to_string() })?
+    }
+
+    // Unloads idle models, oldest first, until `bytes` more fit into the limit.
+    // Models still used by a generation stay; the limit is a target, not a hard cap.
+    fn make_room(&self, bytes: u64) {
+        let mut loaded = self.loaded.lock().unwrap();
+        while loaded.bytes() + bytes > self.memory_limit {
+            let idle = loaded
+                .models
+                .iter()
+                .filter(|(_, candidate)| Arc::strong_count(&candidate.model) == 1)
+                .min_by_key(|(_, candidate)| candidate.last_used)
+                .map(|(id, _)| id.clone());
+            match idle {
+                Some(id) => {
+                    loaded.models.remove(&id);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+fn model_bytes(spec: &ModelConfig) -> u64 {
+    match spec.memory_mb {
+        Some(mb) => mb as u64 * 1024 * 1024,
+        None => std::fs::metadata(&spec.path).map(|meta| meta.len()).unwrap_or(0),
+    }
+}
+
+// Magic numbers of the GGML container formats, read as a little endian u32
+const MODEL_MAGICS: [(u32, &str); 4] =
+    [(0x6767_6d6c, "ggml"), (0x6767_6d66, "ggmf"), (0x6767_6a74, "ggjt"), (0x4655_4747, "gguf")];
+const LORA_MAGIC: u32 = 0x6767_6c61;
+
+fn file_format(magic: [u8; 4]) -> Option<&'static str> {
+    let magic = u32::from_le_bytes(magic);
+    MODEL_MAGICS.iter().find(|(known, _)| *known == magic).map(|(_, name)| *name)
+}
+
+fn read_magic(path: &Path) -> Result<[u8; 4], String> {
+    let mut file = std::fs::File::open(path).map_err(|err| format!("can't be opened: {err}"))?;
+    if !file.metadata().map_err(|err| err.to_string())?.is_file() {
+        return Err("is not a file".to_string());
+    }
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic).map_err(|_| "is too short to be a model".to_string())?;
+    Ok(magic)
+}
+
+// A wrong path or a file that isn't a model fails here with a readable error
+// instead of somewhere inside llm's loader
+fn check_files(spec: &ModelConfig) -> Result<(), RegistryError> {
+    let invalid = |path: &Path, reason: String| RegistryError::InvalidFile {
+        id: spec.id.clone(),
+        path: path.to_path_buf(),
+        reason,
+    };
+    if spec.path.as_os_str().is_empty() {
+        return Err(invalid(&spec.path, "is not set, add a [[models]] entry or set LLM_PATH".to_string()));
+    }
+    let magic = read_magic(&spec.path).map_err(|reason| invalid(&spec.path, reason))?;
+    if file_format(magic).is_none() {
+        return Err(invalid(&spec.path, "is not a GGML model file".to_string()));
+    }
+    for adapter in &spec.lora_adapters {
+        let magic = read_magic(adapter).map_err(|reason| invalid(adapter, reason))?;
+        if u32::from_le_bytes(magic) != LORA_MAGIC {
+            return Err(invalid(adapter, "is not a GGML LoRA adapter".to_string()));
+        }
+    }
+    Ok(())
+}
+
+// Tensor progress is logged in steps of 10%
+fn log_progress(id: &str) -> impl FnMut(llm::LoadProgress) + '_ {
+    let mut logged = 0;
+    move |progress| match progress {
+        llm::LoadProgress::HyperparametersLoaded => tracing::debug!(model = id, "hyperparameters loaded"),
+        llm::LoadProgress::ContextSize { bytes } => {
+            tracing::info!(model = id, "context takes {} MB", bytes / (1024 * 1024))
+        }
+        llm::LoadProgress::LoraApplied { name, source } => {
+            tracing::info!(model = id, "applied LoRA to {name} from {}", source.display())
+        }
+        llm::LoadProgress::TensorLoaded { current_tensor, tensor_count } => {
+            let percent = (current_tensor + 1) * 100 / tensor_count.max(1);
+            if percent >= logged + 10 {
+                logged = percent - percent % 10;
+                tracing::info!(model = id, "loaded {percent}% of {tensor_count} tensors");
+            }
+        }
+        llm::LoadProgress::Loaded { file_size, tensor_count } => tracing::info!(
+            model = id,
+            "loaded {tensor_count} tensors ({} MB)",
+            file_size / (1024 * 1024)
+        ),
+    }
+}
+
+pub fn get_language_model(spec: &ModelConfig) -> Result<Arc<dyn Model>, RegistryError> {
+    let architecture: ModelArchitecture = spec.architecture.parse().map_err(|_| {
+        RegistryError::UnknownArchitecture {
+            id: spec.id.clone(),
+            architecture: spec.architecture.clone(),
+        }
+    })?;
+    check_files(spec)?;
+    tracing::info!(model = %spec.id, path = %spec.path.display(), gpu = spec.use_gpu, "loading model");
+
+    llm::load_dynamic(
+        Some(architecture),
+        &spec.path,
+        llm::TokenizerSource::Embedded,
+        spec.parameters(),
+        log_progress(&spec.id),
+    )
+    .map(Arc::from)
+    .map_err(|err| RegistryError::Load { id: spec.id.clone(), message: err.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_model_formats() {
+        assert_eq!(file_format(*b"tjgg"), Some("ggjt"));
+        assert_eq!(file_format(*b"GGUF"), Some("gguf"));
+        assert_eq!(file_format(*b"lmgg"), Some("ggml"));
+        assert_eq!(file_format(*b"PK\x03\x04"), None);
+        // a LoRA adapter is no model
+        assert_eq!(file_format(LORA_MAGIC.to_le_bytes()), None);
+    }
+}
@@ -0,0 +1,226 @@
+// Retrieval augmented generation: uploads are turned into embedded chunks, and the
+// chunks closest to a question are put in front of it for the model to cite.
+use crate::app::ssr::AppState;
+use crate::components::jippity::{Conversation, Source};
+use crate::documents::{collection_chunks, store_chunk};
+use crate::inference::embeddings::{cosine_similarity, embed};
+use leptos::ServerFnError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DocumentError {
+    #[error("Unsupported file type: {0}, upload text, Markdown or PDF files")]
+    Unsupported(String),
+    #[error("{0} is not valid UTF-8 text")]
+    Encoding(String),
+    #[error("Could not read the PDF: {0}")]
+    Pdf(String),
+    #[error("{0} contains no text")]
+    Empty(String),
+}
+
+pub fn extract_text(file_name: &str, bytes: &[u8]) -> Result<String, DocumentError> {
+    let extension = file_name.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).unwrap_or_default();
+    let text = match extension.as_str() {
+        "txt" | "text" | "md" | "markdown" => String::from_utf8(bytes.to_vec())
+            .map_err(|_| DocumentError::Encoding(file_name.to_string()))?,
+        "pdf" => pdf_extract::extract_text_from_mem(bytes).map_err(|err| DocumentError::Pdf(err.to_string()))?,
+        _ => return Err(DocumentError::Unsupported(file_name.to_string())),
+    };
+    if text.trim().is_empty() {
+        return Err(DocumentError::Empty(file_name.to_string()));
+    }
+    Ok(text)
+}
+
+// Splits `text` into chunks of at most `chunk_chars` characters, preferring to break
+// at whitespace. Consecutive chunks share about `overlap` characters, so a sentence
+// cut at a boundary is still whole in one of them.
+pub fn chunk_text(text: &str, chunk_chars: usize, overlap: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < chars.len() {
+        let mut end = (start + chunk_chars).min(chars.len());
+        if end < chars.len() {
+            // the last whitespace in the second half of the chunk, if any
+            if let Some(space) = (start + chunk_chars / 2..end).rev().find(|&i| chars[i].is_whitespace()) {
+                end = space + 1;
+            }
+        }
+        let chunk: String = chars[start..end].iter().collect();
+        if !chunk.trim().is_empty() {
+            chunks.push(chunk.trim().to_string());
+        }
+        if end == chars.len() {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+    chunks
+}
+
+// Embeds and stores the chunks of an uploaded document. Runs in the background,
+// the documents page shows how many chunks are done.
+pub async fn index_document(state: AppState, user_id: i32, document_id: i64, chunks: Vec<String>) {
+    let model_config = state.config.embedding_model().clone();
+    for (position, chunk) in chunks.iter().enumerate() {
+        let stored = match embed(&state, Some(user_id), &model_config, chunk).await {
+            Ok(embedding) => store_chunk(document_id, position, chunk, &model_config.id, &embedding.vector).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = stored {
+            eprintln!("Could not index chunk {position} of document {document_id}: {err}");
+        }
+    }
+}
+
+// The `top_k` chunks of the collection closest to `query`, numbered for citing
+pub async fn retrieve(
+    state: &AppState,
+    user_id: i32,
+    collection_id: i64,
+    query: &str,
+) -> Result<Vec<Source>, ServerFnError> {
+    let model_config = state.config.embedding_model().clone();
+    let query = embed(state, Some(user_id), &model_config, query).await?;
+
+    let mut ranked: Vec<_> = collection_chunks(collection_id, &model_config.id)
+        .await?
+        .into_iter()
+        .map(|chunk| (cosine_similarity(&query.vector, &chunk.embedding), chunk))
+        .collect();
+    ranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    ranked.truncate(state.config.retrieval.top_k);
+
+    Ok(ranked
+        .into_iter()
+        .enumerate()
+        .map(|(i, (_, chunk))| Source {
+            index: i + 1,
+            chunk_id: chunk.id,
+            document: chunk.document,
+            excerpt: chunk.text,
+        })
+        .collect())
+}
+
+// The system prompt of a conversation with a collection. It doesn't change from turn to
+// turn, so the inference session of the conversation can be reused.
+pub fn grounded_system_prompt(system_prompt: &str, grounded: bool) -> String {
+    if !grounded {
+        return system_prompt.to_string();
+    }
+    format!(
+        "{system_prompt}\n\nSome messages come with numbered excerpts from the user's documents. Answer with \
+         their help and cite them by their number, like [1]. If they don't contain the answer, say so."
+    )
+}
+
+// The question with the excerpts it was answered with in front of it
+fn grounded_question(question: &str, sources: &[Source]) -> String {
+    if sources.is_empty() {
+        return question.to_string();
+    }
+    let mut text = "Excerpts:\n".to_string();
+    for source in sources {
+        text.push_str(&format!("\n[{}] {}:\n{}\n", source.index, source.document, source.excerpt.trim()));
+    }
+    text.push_str(&format!("\nQuestion: {question}"));
+    text
+}
+
+// The conversation as the model sees it: every user message with the excerpts of its
+// answer, the stored ones for earlier turns and `pending` for the newest one. Earlier
+// turns come out the same every time, so the prompt only grows at its end.
+pub fn grounded_history(history: &Conversation, pending: &[Source]) -> Conversation {
+    let mut grounded = history.clone();
+    for i in 0..grounded.messages.len() {
+        if grounded.messages[i].from_llm {
+            continue;
+        }
+        let answers: Vec<_> = grounded.messages[i + 1..].iter().take_while(|message| message.from_llm).collect();
+        let sources = match answers.iter().find(|answer| !answer.sources.is_empty()) {
+            Some(answer) => answer.sources.clone(),
+            None if answers.is_empty() => pending.to_vec(),
+            None => Vec::new(),
+        };
+        grounded.messages[i].text = grounded_question(&grounded.messages[i].text, &sources);
+    }
+    grounded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::jippity::Message;
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(chunk_text("  hello world \n", 100, 10), vec!["hello world"]);
+        assert!(chunk_text("", 100, 10).is_empty());
+    }
+
+    #[test]
+    fn chunks_are_bounded_break_at_whitespace_and_overlap() {
+        let text = "alpha beta gamma delta epsilon zeta eta theta iota kappa";
+        let chunks = chunk_text(text, 20, 6);
+        assert!(chunks.len() > 2);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 20);
+        }
+        assert_eq!(chunks[0], "alpha beta gamma");
+        // the start of the second chunk repeats the end of the first one
+        assert!(chunks[1].starts_with("gamma"));
+        assert!(chunks.last().unwrap().ends_with("kappa"));
+    }
+
+    #[test]
+    fn chunks_multibyte_text_without_whitespace() {
+        let text = "äöü".repeat(10);
+        let chunks = chunk_text(&text, 8, 2);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 8));
+        assert!(chunks.last().unwrap().ends_with('ü'));
+    }
+
+    fn source(index: usize, excerpt: &str) -> Source {
+        Source { index, chunk_id: index as i64, document: "handbook.md".to_string(), excerpt: excerpt.to_string() }
+    }
+
+    #[test]
+    fn grounded_prompt_keeps_the_system_prompt_first() {
+        let prompt = grounded_system_prompt("Be brief.", true);
+        assert!(prompt.starts_with("Be brief.\n\n"));
+        assert!(prompt.contains("like [1]"));
+        assert_eq!(grounded_system_prompt("Be brief.", false), "Be brief.");
+    }
+
+    #[test]
+    fn excerpts_go_with_the_question_they_answer() {
+        let mut answer = Message::llm("Two weeks [1].");
+        answer.sources = vec![source(1, "Vacation needs two weeks notice.")];
+        let mut history = Conversation::new();
+        history.messages = vec![Message::user("Vacation?"), answer, Message::user("Thanks")];
+
+        let grounded = grounded_history(&history, &[source(1, "Be polite.")]);
+        assert_eq!(
+            grounded.messages[0].text,
+            "Excerpts:\n\n[1] handbook.md:\nVacation needs two weeks notice.\n\nQuestion: Vacation?"
+        );
+        assert_eq!(grounded.messages[1].text, "Two weeks [1].");
+        assert!(grounded.messages[2].text.ends_with("Be polite.\n\nQuestion: Thanks"));
+
+        // the earlier turn renders the same whatever the newest question retrieves
+        let without = grounded_history(&history, &[]);
+        assert_eq!(without.messages[0].text, grounded.messages[0].text);
+        assert_eq!(without.messages[2].text, "Thanks");
+    }
+
+    #[test]
+    fn extracts_text_by_extension() {
+        assert_eq!(extract_text("notes.MD", b"# Notes").unwrap(), "# Notes");
+        assert!(matches!(extract_text("image.png", b"x"), Err(DocumentError::Unsupported(_))));
+        assert!(matches!(extract_text("empty.txt", b"  "), Err(DocumentError::Empty(_))));
+    }
+}
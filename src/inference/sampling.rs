@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+// Sampling knobs a request may set, unset ones keep llm's defaults
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct SamplingSettings {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<usize>,
+    pub repeat_penalty: Option<f32>,
+    // the same seed, prompt and settings give the same answer, a random one if unset
+    pub seed: Option<u64>,
+}
+
+#[cfg(feature = "ssr")]
+impl SamplingSettings {
+    // sampler options in the `name:key=value` syntax of llm::samplers::build_sampler
+    fn sampler_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(penalty) = self.repeat_penalty {
+            args.push(format!("repetition:penalty={penalty}"));
+        }
+        match self.temperature {
+            // temperature 0 means "always the most likely token"
+            Some(temperature) if temperature <= 0.0 => args.push("topk:k=1".to_string()),
+            temperature => {
+                if let Some(k) = self.top_k {
+                    args.push(format!("topk:k={k}"));
+                }
+                if let Some(p) = self.top_p {
+                    args.push(format!("topp:p={p}"));
+                }
+                if let Some(temperature) = temperature {
+                    args.push(format!("temperature:temperature={temperature}"));
+                }
+            }
+        }
+        args
+    }
+
+    pub fn to_parameters(&self, model: &dyn llm::Model) -> Result<llm::InferenceParameters, String> {
+        let args = self.sampler_args();
+        if args.is_empty() {
+            return Ok(llm::InferenceParameters::default());
+        }
+        let sampler = llm::samplers::build_sampler(model.tokenizer().len(), &[], &args)
+            .map_err(|err| format!("Invalid sampling settings: {err}"))?;
+        Ok(llm::InferenceParameters { sampler })
+    }
+}
@@ -0,0 +1,76 @@
+use llm::InferenceSession;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// An inference session that already holds the KV state for `fed`,
+// i.e. the rendered prompt plus everything the model generated after it.
+pub struct CachedSession {
+    pub model_id: String,
+    pub session: InferenceSession,
+    pub fed: String,
+}
+
+impl CachedSession {
+    // The part of `prompt` the session hasn't seen yet, None if the session can't be
+    // continued (other model, history got truncated or edited) and must be rebuilt.
+    pub fn unfed<'a>(&self, model_id: &str, prompt: &'a str) -> Option<&'a str> {
+        if self.model_id != model_id {
+            return None;
+        }
+        prompt.strip_prefix(self.fed.as_str())
+    }
+}
+
+// Least recently used sessions per conversation, bounded by a memory budget.
+// A session is taken out while a generation runs on it and put back afterwards.
+pub struct SessionCache {
+    budget_bytes: usize,
+    entries: Mutex<SessionEntries>,
+}
+
+#[derive(Default)]
+struct SessionEntries {
+    tick: u64,
+    sessions: HashMap<i64, CacheEntry>,
+}
+
+struct CacheEntry {
+    last_used: u64,
+    // a session allocates its whole KV memory up front, so this is fixed per model
+    bytes: usize,
+    session: CachedSession,
+}
+
+impl SessionCache {
+    pub fn new(budget_bytes: usize) -> SessionCache {
+        SessionCache {
+            budget_bytes,
+            entries: Mutex::new(SessionEntries::default()),
+        }
+    }
+
+    pub fn take(&self, conversation: i64) -> Option<CachedSession> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.sessions.remove(&conversation).map(|entry| entry.session)
+    }
+
+    pub fn put(&self, conversation: i64, session: CachedSession, bytes: usize) {
+        if bytes > self.budget_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let last_used = entries.tick;
+        entries.sessions.insert(conversation, CacheEntry { last_used, bytes, session });
+
+        while entries.sessions.values().map(|entry| entry.bytes).sum::<usize>() > self.budget_bytes {
+            let oldest = entries
+                .sessions
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| *id)
+                .unwrap();
+            entries.sessions.remove(&oldest);
+        }
+    }
+}
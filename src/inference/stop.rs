@@ -0,0 +1,173 @@
+// Finds stop sequences in streamed text. Tokens rarely line up with a stop sequence:
+// it may start mid-token, span several tokens or share a prefix with ordinary text.
+// The matcher holds back only the tail that could still become a sequence and
+// releases everything before it.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Scan {
+    // no sequence matched, the text can be streamed (it may be empty)
+    Text(String),
+    // sequence `index` matched: `before` can be streamed, `after` followed the
+    // sequence in the pushed text and was not streamed
+    Matched { before: String, index: usize, after: String },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StopMatcher {
+    sequences: Vec<String>,
+    held: String,
+}
+
+impl StopMatcher {
+    // Empty sequences are ignored, they would match anywhere
+    pub fn new<I, S>(sequences: I) -> StopMatcher
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let sequences = sequences.into_iter().map(Into::into).filter(|s: &String| !s.is_empty()).collect();
+        StopMatcher { sequences, held: String::new() }
+    }
+
+    pub fn sequence(&self, index: usize) -> &str {
+        &self.sequences[index]
+    }
+
+    pub fn push(&mut self, text: &str) -> Scan {
+        self.held.push_str(text);
+
+        // the earliest match, the longest sequence if several start there
+        let matched = self
+            .sequences
+            .iter()
+            .enumerate()
+            .filter_map(|(index, sequence)| self.held.find(sequence.as_str()).map(|start| (start, index)))
+            .min_by_key(|&(start, index)| (start, std::cmp::Reverse(self.sequences[index].len())));
+        if let Some((start, index)) = matched {
+            let after = self.held[start + self.sequences[index].len()..].to_string();
+            self.held.truncate(start);
+            let before = std::mem::take(&mut self.held);
+            return Scan::Matched { before, index, after };
+        }
+
+        // the longest tail that is the start of some sequence stays held
+        let keep = self
+            .held
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| self.sequences.iter().any(|sequence| sequence.starts_with(&self.held[i..])))
+            .unwrap_or(self.held.len());
+        let tail = self.held.split_off(keep);
+        Scan::Text(std::mem::replace(&mut self.held, tail))
+    }
+
+    // The held tail, for when the generation ends without completing a sequence
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Streams `tokens` and returns the released text and the matched sequence, if any
+    fn run(sequences: &[&str], tokens: &[&str]) -> (String, Option<usize>) {
+        let mut matcher = StopMatcher::new(sequences.iter().copied());
+        let mut streamed = String::new();
+        for token in tokens {
+            match matcher.push(token) {
+                Scan::Text(text) => streamed.push_str(&text),
+                Scan::Matched { before, index, .. } => {
+                    streamed.push_str(&before);
+                    return (streamed, Some(index));
+                }
+            }
+        }
+        streamed.push_str(&matcher.flush());
+        (streamed, None)
+    }
+
+    #[test]
+    fn text_without_sequences_passes_through() {
+        let mut matcher = StopMatcher::new(["</s>"]);
+        assert_eq!(matcher.push("Hello"), Scan::Text("Hello".to_string()));
+        assert_eq!(matcher.push(" world"), Scan::Text(" world".to_string()));
+        assert_eq!(matcher.flush(), "");
+    }
+
+    #[test]
+    fn sequence_within_one_token() {
+        assert_eq!(run(&["</s>"], &["Bye</s>"]), ("Bye".to_string(), Some(0)));
+        assert_eq!(run(&["</s>"], &["</s>"]), (String::new(), Some(0)));
+    }
+
+    #[test]
+    fn sequence_spanning_tokens_mid_word() {
+        assert_eq!(run(&["User:"], &["Done.\nUs", "er", ": next"]), ("Done.\n".to_string(), Some(0)));
+        assert_eq!(run(&["<|im_end|>"], &["ok<", "|im", "_", "end", "|>"]), ("ok".to_string(), Some(0)));
+    }
+
+    #[test]
+    fn only_the_ambiguous_tail_is_held() {
+        let mut matcher = StopMatcher::new(["### Instruction:"]);
+        assert_eq!(matcher.push("Answer #"), Scan::Text("Answer ".to_string()));
+        assert_eq!(matcher.push("## Instr"), Scan::Text(String::new()));
+        // the sequence breaks off, the held text is released
+        assert_eq!(matcher.push("uments"), Scan::Text("### Instruments".to_string()));
+    }
+
+    #[test]
+    fn partial_matches_do_not_leak_or_get_lost() {
+        // "</" looks like the start of "</s>" but continues differently
+        assert_eq!(run(&["</s>"], &["a </", "b> c"]), ("a </b> c".to_string(), None));
+        // a failed start followed by a real one within the held text
+        assert_eq!(run(&["abc"], &["xa", "bab", "c!"]), ("xab".to_string(), Some(0)));
+    }
+
+    #[test]
+    fn held_tail_is_released_at_the_end() {
+        assert_eq!(run(&["</s>"], &["fine </"]), ("fine </".to_string(), None));
+    }
+
+    #[test]
+    fn earliest_of_several_sequences_wins() {
+        let sequences = ["[INST]", "</s>"];
+        assert_eq!(run(&sequences, &["one</s>two[INST]"]), ("one".to_string(), Some(1)));
+        assert_eq!(run(&sequences, &["one[IN", "ST]two</s>"]), ("one".to_string(), Some(0)));
+    }
+
+    #[test]
+    fn longest_sequence_wins_at_the_same_start() {
+        let mut matcher = StopMatcher::new(["User", "User:"]);
+        assert_eq!(
+            matcher.push("hi User: x"),
+            Scan::Matched { before: "hi ".to_string(), index: 1, after: " x".to_string() }
+        );
+        assert_eq!(matcher.sequence(1), "User:");
+    }
+
+    #[test]
+    fn text_after_the_match_is_returned() {
+        let mut matcher = StopMatcher::new(["<tool_call>"]);
+        assert_eq!(matcher.push("Let me check. <tool"), Scan::Text("Let me check. ".to_string()));
+        assert_eq!(
+            matcher.push("_call>{\"name\""),
+            Scan::Matched { before: String::new(), index: 0, after: "{\"name\"".to_string() }
+        );
+    }
+
+    #[test]
+    fn multibyte_text_is_split_on_char_boundaries() {
+        assert_eq!(run(&["ünd"], &["grü", "ne", " ü", "nd"]), ("grüne ".to_string(), Some(0)));
+        let mut matcher = StopMatcher::new(["→END"]);
+        assert_eq!(matcher.push("a→"), Scan::Text("a".to_string()));
+        assert_eq!(matcher.flush(), "→");
+    }
+
+    #[test]
+    fn empty_sequences_are_ignored() {
+        assert_eq!(run(&["", "</s>"], &["a", "b"]), ("ab".to_string(), None));
+        assert_eq!(run(&[], &["a", "b"]), ("ab".to_string(), None));
+    }
+}
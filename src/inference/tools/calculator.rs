@@ -0,0 +1,195 @@
+use super::{Tool, ToolContext, ToolError};
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+
+// Arithmetic with + - * / % ^, parentheses and decimals, evaluated without any eval
+pub struct Calculator;
+
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluates an arithmetic expression with + - * / % ^ and parentheses."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": { "expression": { "type": "string" } },
+            "required": ["expression"],
+        })
+    }
+
+    fn execute<'a>(&'a self, _context: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<String, ToolError>> {
+        Box::pin(async move {
+            let expression = arguments
+                .get("expression")
+                .and_then(Value::as_str)
+                .ok_or_else(|| ToolError::InvalidArguments("expression must be a string".to_string()))?;
+            evaluate(expression).map(format_number).map_err(ToolError::Failed)
+        })
+    }
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{value}")
+    }
+}
+
+// Parentheses, signs and powers nested deeper than this are rejected. The model writes
+// the expression, and every level is a recursion of the parser.
+const MAX_DEPTH: usize = 64;
+
+pub fn evaluate(expression: &str) -> Result<f64, String> {
+    let chars = expression.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut parser = Parser { chars, pos: 0, depth: 0 };
+    let value = parser.expression()?;
+    if parser.pos < parser.chars.len() {
+        return Err(format!("Unexpected '{}'", parser.chars[parser.pos]));
+    }
+    if !value.is_finite() {
+        return Err("The result is not a finite number".to_string());
+    }
+    Ok(value)
+}
+
+// expression := term (('+' | '-') term)*
+// term       := power (('*' | '/' | '%') power)*
+// power      := unary ('^' power)?
+// unary      := ('-' | '+') unary | number | '(' expression ')'
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    // runs `parse` one level deeper
+    fn nested(&mut self, parse: fn(&mut Parser) -> Result<f64, String>) -> Result<f64, String> {
+        if self.depth == MAX_DEPTH {
+            return Err("Expression is nested too deeply".to_string());
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.power()?;
+            if op != '*' && rhs == 0.0 {
+                return Err("Division by zero".to_string());
+            }
+            value = match op {
+                '*' => value * rhs,
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.unary()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            // right associative: 2^3^2 = 2^9
+            return Ok(base.powf(self.nested(Parser::power)?));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.nested(Parser::unary)?)
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.nested(Parser::unary)
+            }
+            Some('(') => {
+                self.pos += 1;
+                let value = self.nested(Parser::expression)?;
+                if self.peek() != Some(')') {
+                    return Err("Missing ')'".to_string());
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number.parse().map_err(|_| format!("Invalid number: {number}"))
+            }
+            Some(c) => Err(format!("Unexpected '{c}'")),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respects_precedence_and_parentheses() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(evaluate("10 % 4 - 1.5").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("2 +").is_err());
+        assert!(evaluate("1.2.3").is_err());
+        assert!(evaluate("rm -rf").is_err());
+    }
+
+    #[test]
+    fn rejects_deep_nesting_instead_of_overflowing_the_stack() {
+        let deep = 200_000;
+        let parentheses = format!("{}1{}", "(".repeat(deep), ")".repeat(deep));
+        assert_eq!(evaluate(&parentheses), Err("Expression is nested too deeply".to_string()));
+        assert!(evaluate(&format!("{}1", "-".repeat(deep))).is_err());
+        assert!(evaluate(&format!("1{}", "^1".repeat(deep))).is_err());
+
+        let nested = MAX_DEPTH - 1;
+        assert_eq!(evaluate(&format!("{}1{}", "(".repeat(nested), ")".repeat(nested))).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn formats_whole_numbers_without_fraction() {
+        assert_eq!(format_number(4.0), "4");
+        assert_eq!(format_number(0.25), "0.25");
+    }
+}
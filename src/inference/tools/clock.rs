@@ -0,0 +1,23 @@
+use super::{Tool, ToolContext, ToolError};
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+
+pub struct CurrentTime;
+
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Returns the current date and time in UTC."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn execute<'a>(&'a self, _context: &'a ToolContext, _arguments: Value) -> BoxFuture<'a, Result<String, ToolError>> {
+        Box::pin(async move { Ok(chrono::Utc::now().format("%A, %Y-%m-%d %H:%M:%S UTC").to_string()) })
+    }
+}
@@ -0,0 +1,62 @@
+use super::{Tool, ToolContext, ToolError};
+use crate::history::find_messages;
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+
+const DEFAULT_LIMIT: usize = 5;
+const MAX_LIMIT: usize = 20;
+const SNIPPET_CHARS: usize = 200;
+
+// Read-only text search over the calling user's own conversations
+pub struct SearchConversations;
+
+impl Tool for SearchConversations {
+    fn name(&self) -> &'static str {
+        "search_conversations"
+    }
+
+    fn description(&self) -> &'static str {
+        "Finds messages containing a text in the user's earlier conversations."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string" },
+                "limit": { "type": "integer", "minimum": 1, "maximum": MAX_LIMIT },
+            },
+            "required": ["query"],
+        })
+    }
+
+    fn execute<'a>(&'a self, context: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<String, ToolError>> {
+        Box::pin(async move {
+            let query = arguments
+                .get("query")
+                .and_then(Value::as_str)
+                .filter(|query| !query.trim().is_empty())
+                .ok_or_else(|| ToolError::InvalidArguments("query must be a non-empty string".to_string()))?;
+            let limit = arguments
+                .get("limit")
+                .and_then(Value::as_u64)
+                .map_or(DEFAULT_LIMIT, |limit| (limit as usize).clamp(1, MAX_LIMIT));
+
+            let found = find_messages(context.user_id, query.trim(), limit)
+                .await
+                .map_err(|err| ToolError::Failed(err.to_string()))?;
+            if found.is_empty() {
+                return Ok("No matching messages".to_string());
+            }
+            Ok(found
+                .into_iter()
+                .map(|(title, message)| {
+                    let who = if message.from_llm { "Jippity" } else { "User" };
+                    let snippet: String = message.text.chars().take(SNIPPET_CHARS).collect();
+                    format!("[{title}] {who}: {snippet}")
+                })
+                .collect::<Vec<_>>()
+                .join("\n"))
+        })
+    }
+}
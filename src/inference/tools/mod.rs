@@ -0,0 +1,126 @@
+// Server side functions Jippity may call in the middle of an answer. The model writes
+// <tool_call>{"name": ..., "arguments": {...}}</tool_call>, generation halts there,
+// the tool runs and its result is appended to the conversation before generation resumes.
+mod calculator;
+mod clock;
+mod conversations;
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub use calculator::Calculator;
+pub use clock::CurrentTime;
+pub use conversations::SearchConversations;
+
+pub use crate::inference::prompt::{TOOL_CALL_CLOSE, TOOL_CALL_OPEN};
+use crate::inference::prompt::{TOOL_RESULT_CLOSE, TOOL_RESULT_OPEN};
+
+#[derive(Debug, Error)]
+pub enum ToolError {
+    #[error("Unknown tool: {0}")]
+    UnknownTool(String),
+    #[error("Invalid tool call: {0}")]
+    InvalidCall(String),
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+// Whom a tool runs for, tools only ever see this user's data
+pub struct ToolContext {
+    pub user_id: i32,
+}
+
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    // JSON schema of the arguments object
+    fn parameters(&self) -> Value;
+    fn execute<'a>(&'a self, context: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<String, ToolError>>;
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    // `body` is what the model wrote between the tool call tags
+    pub fn parse(body: &str) -> Result<ToolCall, ToolError> {
+        serde_json::from_str(body.trim()).map_err(|err| ToolError::InvalidCall(err.to_string()))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn builtin() -> ToolRegistry {
+        let mut registry = ToolRegistry::default();
+        registry.register(Calculator);
+        registry.register(CurrentTime);
+        registry.register(SearchConversations);
+        registry
+    }
+
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.push(Arc::new(tool));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub async fn execute(&self, context: &ToolContext, call: ToolCall) -> Result<String, ToolError> {
+        let tool = self
+            .tools
+            .iter()
+            .find(|tool| tool.name() == call.name)
+            .ok_or_else(|| ToolError::UnknownTool(call.name.clone()))?;
+        tool.execute(context, call.arguments).await
+    }
+
+    // Appended to the system prompt, so the model knows what it can call and how
+    pub fn system_prompt(&self) -> String {
+        let mut prompt = format!(
+            "You can use tools. To call one, answer with {TOOL_CALL_OPEN}{{\"name\": \"<tool>\", \"arguments\": {{...}}}}{TOOL_CALL_CLOSE} \
+             and nothing after it; the result is given to you as {TOOL_RESULT_OPEN}...{TOOL_RESULT_CLOSE}. Available tools:\n"
+        );
+        for tool in &self.tools {
+            prompt.push_str(&format!("- {}: {} Arguments: {}\n", tool.name(), tool.description(), tool.parameters()));
+        }
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_tool_calls() {
+        let call = ToolCall::parse(r#" {"name": "calculator", "arguments": {"expression": "1 + 1"}} "#).unwrap();
+        assert_eq!(call.name, "calculator");
+        assert_eq!(call.arguments, json!({ "expression": "1 + 1" }));
+
+        assert_eq!(ToolCall::parse(r#"{"name": "current_time"}"#).unwrap().arguments, Value::Null);
+        assert!(matches!(ToolCall::parse("calculator(1 + 1)"), Err(ToolError::InvalidCall(_))));
+    }
+
+    #[test]
+    fn system_prompt_lists_every_tool() {
+        let prompt = ToolRegistry::builtin().system_prompt();
+        for name in ["calculator", "current_time", "search_conversations"] {
+            assert!(prompt.contains(&format!("- {name}: ")));
+        }
+    }
+}
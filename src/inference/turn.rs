@@ -0,0 +1,194 @@
+// One answer of Jippity in a stored conversation. The generation may stop at a tool
+// call; then the tool runs, the call and its result become a message of their own,
+// and generation resumes with the result in the prompt. Answers pass the output
+// moderation before they are stored.
+use crate::app::ssr::AppState;
+use crate::components::jippity::{Conversation, Message, Source, ToolInvocation};
+use crate::documents::store_sources;
+use crate::history::append_message;
+use crate::inference::cache::CacheMode;
+use crate::inference::config::ModelConfig;
+use crate::inference::context::{fit_prompt, ContextUsage, FittedPrompt};
+use crate::inference::embeddings::index_message;
+use crate::inference::engine::{count_tokens, run_generation, FinishReason, GenerationJob};
+use crate::inference::events::ChatEvent;
+use crate::inference::generation::Generation;
+use crate::inference::grammar::ResponseFormat;
+use crate::inference::moderation::{hold_tokens, moderate, Stage};
+use crate::inference::prompt::PromptTemplate;
+use crate::inference::retrieval::grounded_history;
+use crate::inference::sampling::SamplingSettings;
+use crate::inference::tools::{ToolCall, ToolContext};
+use crate::moderation::{record_decisions, AuditSource};
+use leptos::ServerFnError;
+use llm::Model;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// tool output beyond this is cut off, it has to fit into the prompt
+const TOOL_RESULT_CHARS: usize = 2000;
+
+pub struct ChatTurn {
+    pub state: AppState,
+    pub user_id: i32,
+    // stored, with the user's new message as the last one
+    pub history: Conversation,
+    pub model_config: ModelConfig,
+    pub template: PromptTemplate,
+    pub model: Arc<dyn Model>,
+    pub system_prompt: String,
+    pub sampling: SamplingSettings,
+    // the answer has to be JSON, no tools are offered then
+    pub format: ResponseFormat,
+    // stored with the first answer message of the turn
+    pub sources: Vec<Source>,
+    // Refresh for a replay, which has to run the model again
+    pub cache: CacheMode,
+}
+
+impl ChatTurn {
+    pub fn fit(&self) -> FittedPrompt {
+        fit_prompt(
+            &self.template,
+            Some(&self.system_prompt),
+            &grounded_history(&self.history, &self.sources),
+            self.model_config.prompt_budget(),
+            |text| count_tokens(self.model.as_ref(), text),
+        )
+    }
+
+    pub fn fits(&self, fitted: &FittedPrompt) -> bool {
+        fitted.prompt_tokens <= self.model_config.prompt_budget()
+    }
+
+    // Streams Token and Tool events to `tx` and returns the final Done or Error event
+    pub async fn run(mut self, generation: Arc<Generation>, tx: mpsc::Sender<ChatEvent>) -> ChatEvent {
+        let conversation_id = self.history.id.expect("stored conversations have an id");
+        let max_tool_calls = if self.state.tools.is_empty() { 0 } else { self.state.config.tools.max_calls_per_answer };
+        let mut tool_calls = 0;
+        let hold = self.state.moderation.holds_output();
+
+        loop {
+            let fitted = self.fit();
+            if !self.fits(&fitted) {
+                return ChatEvent::Error("The conversation no longer fits into Jippity's context window".to_string());
+            }
+            let job = GenerationJob {
+                user_id: self.user_id,
+                model_id: self.model_config.id.clone(),
+                model: self.model.clone(),
+                prompt: fitted.prompt,
+                stop_sequences: self.template.stop_sequences.clone(),
+                max_tokens: self.model_config.max_response_tokens,
+                sampling: self.sampling.clone(),
+                session_key: Some(conversation_id),
+                session_bytes: self.model_config.session_bytes(),
+                session_config: self.model_config.session_config(),
+                tool_calls: tool_calls < max_tool_calls,
+                format: self.format.clone(),
+                cache: self.cache,
+            };
+            let events = if hold { hold_tokens(tx.clone()) } else { tx.clone() };
+            let output = match run_generation(&self.state, job, &generation, events).await {
+                Ok(output) => output,
+                Err(err) => return ChatEvent::Error(err.to_string()),
+            };
+            // the rest of the turn uses the same seed, a replay of any of its messages
+            // runs the whole turn again
+            self.sampling.seed = Some(output.seed);
+            let context = ContextUsage {
+                used: fitted.prompt_tokens + output.completion_tokens,
+                size: self.model_config.context_size,
+                dropped_messages: fitted.dropped_messages,
+            };
+
+            // text before a tool call is an answer message of its own
+            if !output.text.is_empty() {
+                let checked = moderate(&self.state, self.user_id, Stage::Output, &output.text).await;
+                let user_id = self.user_id;
+                let audit = move |message_id, decisions| {
+                    let source = AuditSource::Chat { conversation_id: Some(conversation_id), message_id };
+                    record_decisions(user_id, source, Stage::Output, decisions)
+                };
+                if checked.blocked() {
+                    let blocked_by = checked.blocked_by();
+                    tokio::spawn(audit(None, checked.decisions));
+                    return ChatEvent::Error(format!("The answer was withheld by the moderation rules ({blocked_by})"));
+                }
+                if hold {
+                    let _ = tx.send(ChatEvent::Token(checked.text.clone())).await;
+                }
+                let mut answer = Message::llm(checked.text);
+                answer.sources = std::mem::take(&mut self.sources);
+                match self.store(answer).await {
+                    Ok(message_id) => {
+                        tokio::spawn(audit(Some(message_id), checked.decisions));
+                    }
+                    Err(err) => return ChatEvent::Error(format!("Could not store the answer: {err}")),
+                }
+            }
+
+            let Some(call) = output.tool_call else {
+                if self.format != ResponseFormat::Text && output.finish_reason == FinishReason::Length {
+                    return ChatEvent::Error(
+                        "The answer reached the token limit before its JSON was complete, it won't parse".to_string(),
+                    );
+                }
+                let cancelled = output.finish_reason == FinishReason::Cancelled;
+                return ChatEvent::Done { context, cancelled };
+            };
+            tool_calls += 1;
+            let invocation = self.call_tool(&call).await;
+            let _ = tx.send(ChatEvent::Tool(invocation.clone())).await;
+            if let Err(err) = self.store(Message::tool(invocation)).await {
+                return ChatEvent::Error(format!("Could not store the tool call: {err}"));
+            }
+        }
+    }
+
+    // Errors go back to the model as the result, so it can correct itself
+    async fn call_tool(&self, body: &str) -> ToolInvocation {
+        let call = match ToolCall::parse(body) {
+            Ok(call) => call,
+            Err(err) => {
+                return ToolInvocation {
+                    name: "invalid_call".to_string(),
+                    arguments: serde_json::Value::from(body.trim()).to_string(),
+                    result: format!("Error: {err}"),
+                }
+            }
+        };
+        let name = call.name.clone();
+        let arguments = call.arguments.to_string();
+        let context = ToolContext { user_id: self.user_id };
+        let mut result = match self.state.tools.execute(&context, call).await {
+            Ok(result) => result,
+            Err(err) => format!("Error: {err}"),
+        };
+        if let Some((cut, _)) = result.char_indices().nth(TOOL_RESULT_CHARS) {
+            result.truncate(cut);
+            result.push_str("...");
+        }
+        ToolInvocation { name, arguments, result }
+    }
+
+    // Stores a message of Jippity with everything a replay of it needs
+    async fn store(&mut self, mut message: Message) -> Result<i64, ServerFnError> {
+        let conversation_id = self.history.id.expect("stored conversations have an id");
+        message.sampling = Some(self.sampling.clone());
+        message.model = Some(self.model_config.id.clone());
+        message.format = Some(self.format.clone());
+        let message_id = append_message(conversation_id, &message).await?;
+        message.id = Some(message_id);
+        if !message.sources.is_empty() {
+            if let Err(err) = store_sources(message_id, &message.sources).await {
+                eprintln!("Could not store the sources of message {message_id}: {err}");
+            }
+        }
+        if message.tool.is_none() {
+            tokio::spawn(index_message(self.state.clone(), message_id, message.text.clone()));
+        }
+        self.history.messages.push(message);
+        Ok(message_id)
+    }
+}
@@ -1,7 +1,28 @@
+#[cfg(feature = "ssr")]
+pub mod api;
 pub mod app;
+#[cfg(feature = "ssr")]
+pub mod chatgpt;
+#[cfg(feature = "ssr")]
+pub mod cli;
 pub mod components;
+#[cfg(feature = "ssr")]
+pub mod documents;
 pub mod error_template;
 #[cfg(feature = "ssr")]
+pub mod feedback;
+#[cfg(feature = "ssr")]
+pub mod history;
+pub mod inference;
+#[cfg(feature = "ssr")]
+pub mod moderation;
+#[cfg(feature = "ssr")]
+pub mod personas;
+#[cfg(feature = "ssr")]
+pub mod shares;
+#[cfg(feature = "ssr")]
+pub mod usage;
+#[cfg(feature = "ssr")]
 pub mod fileserv;
 
 #[cfg(feature = "hydrate")]
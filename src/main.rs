@@ -1,29 +1,55 @@
 use axum::{
-    extract::Extension,
+    extract::{DefaultBodyLimit, Extension},
     Router,
-    routing::get,
+    routing::{get, post},
 };
 use leptos::*;
 use leptos_axum::{generate_route_list, LeptosRoutes};
-use leptos_axum_proj::app::ssr::create_db_conn;
+use leptos_axum_proj::api;
+use leptos_axum_proj::app::ssr::{create_db_conn, AppState};
 use leptos_axum_proj::app::*;
+use leptos_axum_proj::cli;
 use leptos_axum_proj::fileserv::file_and_error_handler;
+use leptos_axum_proj::inference::cache::ResponseCache;
+use leptos_axum_proj::inference::config::JippityConfig;
+use leptos_axum_proj::inference::moderation::Moderator;
+use leptos_axum_proj::inference::queue::InferenceQueue;
+use leptos_axum_proj::inference::registry::ModelRegistry;
+use leptos_axum_proj::inference::sessions::SessionCache;
+use leptos_axum_proj::inference::tools::ToolRegistry;
 use std::sync::Arc;
-use llm::models::Llama;
-
-type AppState = Arc<Llama>;
 
 #[cfg(feature = "ssr")]
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // model loading reports its progress through tracing
+    tracing_subscriber::fmt::init();
+
     // Load configuration
     let conf = get_configuration(None).await.unwrap();
     let leptos_options = conf.leptos_options;
     let addr = leptos_options.site_addr;
     let routes = generate_route_list(App);
 
-    // Load the LLM model
-    let model = Arc::new(get_language_model());
+    // Load the Jippity config (models, prompt templates etc.), the models themselves
+    // are loaded in the background once the server is up
+    let config = JippityConfig::load().unwrap_or_else(|err| panic!("{err}"));
+    // room for the multipart framing around a maximum sized document upload
+    let body_limit = (config.retrieval.max_upload_mb + 1) * 1024 * 1024;
+    let state = AppState {
+        models: Arc::new(ModelRegistry::new(&config)),
+        queue: Arc::new(InferenceQueue::new(config.queue.clone())),
+        sessions: Arc::new(SessionCache::new(config.sessions.memory_budget_mb * 1024 * 1024)),
+        tools: Arc::new(if config.tools.enabled { ToolRegistry::builtin() } else { ToolRegistry::default() }),
+        cache: Arc::new(ResponseCache::new(config.cache.clone())),
+        moderation: Arc::new(Moderator::new(&config.moderation).expect("checked when the config was loaded")),
+        config: Arc::new(config),
+        generations: Arc::default(),
+    };
+    let models = state.models.clone();
+    let mut preload = vec![state.config.default_model().id.clone()];
+    preload.push(state.config.embedding_model().id.clone());
+    preload.dedup();
 
     // DB: Connection pool for PostgreSQL
     let db_conn_pool = create_db_conn().await.expect("DB connection failed");
@@ -32,57 +58,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
          Err(err) => eprintln!("Migration error: {:?}", err),
     }
 
+    // admin commands (see src/cli.rs) run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args).await;
+    }
+
     // Build our application with a route
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" })) // Add a dummy route for testing
-        .app_data(Extension(model.clone())) // Provide the model as application state
+        // OpenAI compatible API, authenticated with API tokens
+        .route("/v1/chat/completions", post(api::chat_completions))
+        .route("/v1/completions", post(api::completions))
+        .route("/v1/embeddings", post(api::embeddings))
+        .route("/v1/models", get(api::models))
         .leptos_routes(&leptos_options, routes, App)
         .fallback(file_and_error_handler)
+        .layer(DefaultBodyLimit::max(body_limit))
+        .layer(Extension(state)) // Provide the models and config as application state
         .with_state(leptos_options);
 
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     logging::log!("listening on http://{}", &addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    // until a model is ready, Jippity answers that it is loading; login and the rest work
+    models.preload(preload);
+    axum::serve(listener, app.into_make_service())
         .await
         .unwrap();
 
     Ok(())
 }
 
-// Configuration and model loading functions
-cfg_if! {
-    if #[cfg(feature = "ssr")] {
-        use llm::models::Llama;
-        use std::env;
-        use dotenv::dotenv;
-        pub fn get_language_model() -> Llama {
-            use std::path::PathBuf;
-            dotenv().ok();
-            let model_path = env::var("LLM_PATH").expect("LLM_PATH must be set");
-            let model_parameters = llm::ModelParameters {
-                prefer_mmap: true,
-                context_size: 2048,
-                lora_adapters: None,
-                use_gpu: true,
-                gpu_layers: None,
-                rope_overrides: None,
-                n_gqa: None,
-            };
-
-            llm::load::<Llama>(
-                &PathBuf::from(&model_path),
-                llm::TokenizerSource::Embedded,
-                model_parameters,
-                llm::load_progress_callback_stdout,
-            )
-            .unwrap_or_else(|err| {
-                panic!("Failed to load model from {model_path:?}: {err}")
-            })
-        }
-    }
-}
-
 #[cfg(not(feature = "ssr"))]
 pub fn main() {
     // no client-side main function
@@ -0,0 +1,94 @@
+// The moderation audit log and the review of flagged messages
+use crate::app::ssr::create_db_conn;
+use crate::components::admin::FlaggedMessage;
+use crate::inference::moderation::{Decision, Stage};
+use leptos::ServerFnError;
+
+// Where the moderated text came from
+#[derive(Clone, Copy, Debug)]
+pub enum AuditSource {
+    // None for a message that was blocked before it was stored, or that would have
+    // started a new conversation
+    Chat { conversation_id: Option<i64>, message_id: Option<i64> },
+    // a request to the OpenAI compatible API, nothing of it is stored
+    Api,
+}
+
+// Runs in the background like record_usage, a failure is logged
+pub async fn record_decisions(user_id: i32, source: AuditSource, stage: Stage, decisions: Vec<Decision>) {
+    if decisions.is_empty() {
+        return;
+    }
+    let stored = async {
+        let pool = create_db_conn().await?;
+        let rules: Vec<&str> = decisions.iter().map(|decision| decision.rule.as_str()).collect();
+        let actions: Vec<&str> = decisions.iter().map(|decision| decision.action.as_str()).collect();
+        let matches: Vec<i32> = decisions.iter().map(|decision| decision.matches as i32).collect();
+        let (origin, conversation_id, message_id) = match source {
+            AuditSource::Chat { conversation_id, message_id } => ("chat", conversation_id, message_id),
+            AuditSource::Api => ("api", None, None),
+        };
+        sqlx::query(
+            "INSERT INTO moderation_audit (user_id, origin, conversation_id, message_id, stage, rule, action, matches)
+             SELECT $1, $2, $3, $4, $5, d.rule, d.action, d.matches
+             FROM UNNEST($6::VARCHAR[], $7::VARCHAR[], $8::INTEGER[]) AS d (rule, action, matches)"
+        )
+        .bind(user_id)
+        .bind(origin)
+        .bind(conversation_id)
+        .bind(message_id)
+        .bind(stage.as_str())
+        .bind(&rules)
+        .bind(&actions)
+        .bind(&matches)
+        .execute(&pool)
+        .await?;
+        Ok::<_, ServerFnError>(())
+    };
+    if let Err(err) = stored.await {
+        eprintln!("Could not record the moderation decisions for user {user_id}: {err}");
+    }
+}
+
+type FlaggedRow = (i64, String, String, Option<i64>, String, String, Option<String>, String);
+
+// Flags nobody reviewed yet, oldest first
+pub async fn unreviewed_flags(limit: i64) -> Result<Vec<FlaggedMessage>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let rows: Vec<FlaggedRow> = sqlx::query_as(
+        "SELECT a.id, u.username, a.origin, a.conversation_id, a.stage, a.rule, m.text,
+                to_char(a.created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI')
+         FROM moderation_audit a
+         JOIN user_table u ON u.id = a.user_id
+         LEFT JOIN message m ON m.id = a.message_id
+         WHERE a.action = 'flag' AND a.reviewed_at IS NULL
+         ORDER BY a.created_at
+         LIMIT $1"
+    )
+    .bind(limit)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, username, origin, conversation_id, stage, rule, text, created_at)| FlaggedMessage {
+            id,
+            username,
+            origin,
+            conversation_id,
+            stage,
+            rule,
+            text,
+            created_at,
+        })
+        .collect())
+}
+
+pub async fn mark_reviewed(id: i64) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query("UPDATE moderation_audit SET reviewed_at = now() WHERE id = $1 AND reviewed_at IS NULL")
+        .bind(id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,97 @@
+// Saved personas. Users see their own ones plus the global ones admins published.
+use crate::app::ssr::create_db_conn;
+use crate::components::personas::Persona;
+use crate::inference::sampling::SamplingSettings;
+use leptos::ServerFnError;
+
+type PersonaRow = (i64, i32, String, String, Option<String>, Option<f32>, Option<f32>, Option<i32>, Option<f32>, bool);
+
+const PERSONA_COLUMNS: &str =
+    "id, user_id, name, system_prompt, model_id, temperature, top_p, top_k, repeat_penalty, global";
+
+fn persona(row: PersonaRow, user_id: i32) -> Persona {
+    let (id, owner, name, system_prompt, model, temperature, top_p, top_k, repeat_penalty, global) = row;
+    Persona {
+        id: Some(id),
+        name,
+        system_prompt,
+        model,
+        sampling: SamplingSettings {
+            temperature,
+            top_p,
+            top_k: top_k.map(|k| k as usize),
+            repeat_penalty,
+            // a persona samples randomly, seeds are set per request
+            seed: None,
+        },
+        global,
+        own: owner == user_id,
+    }
+}
+
+pub async fn list_personas(user_id: i32) -> Result<Vec<Persona>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let rows: Vec<PersonaRow> = sqlx::query_as(&format!(
+        "SELECT {PERSONA_COLUMNS} FROM persona WHERE user_id = $1 OR global ORDER BY global DESC, name"
+    ))
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await?;
+    Ok(rows.into_iter().map(|row| persona(row, user_id)).collect())
+}
+
+// None if the persona doesn't exist or isn't visible to the user
+pub async fn load_persona(id: i64, user_id: i32) -> Result<Option<Persona>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let row: Option<PersonaRow> = sqlx::query_as(&format!(
+        "SELECT {PERSONA_COLUMNS} FROM persona WHERE id = $1 AND (user_id = $2 OR global)"
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&pool)
+    .await?;
+    Ok(row.map(|row| persona(row, user_id)))
+}
+
+// Inserts a persona without id, otherwise updates it if it belongs to the user.
+// Returns None if there was nothing to update.
+pub async fn save_persona(user_id: i32, persona: &Persona) -> Result<Option<i64>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let sampling = &persona.sampling;
+    // both statements take the same binds, the INSERT ignores the id in $1
+    let query = match persona.id {
+        None => sqlx::query_as(
+            "INSERT INTO persona (user_id, name, system_prompt, model_id, temperature, top_p, top_k, repeat_penalty, global)
+             VALUES ($2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id"
+        ),
+        Some(_) => sqlx::query_as(
+            "UPDATE persona SET name = $3, system_prompt = $4, model_id = $5, temperature = $6, top_p = $7,
+             top_k = $8, repeat_penalty = $9, global = $10
+             WHERE id = $1 AND user_id = $2 RETURNING id"
+        ),
+    };
+    let saved: Option<(i64,)> = query
+        .bind(persona.id)
+        .bind(user_id)
+        .bind(&persona.name)
+        .bind(&persona.system_prompt)
+        .bind(&persona.model)
+        .bind(sampling.temperature)
+        .bind(sampling.top_p)
+        .bind(sampling.top_k.map(|k| k as i32))
+        .bind(sampling.repeat_penalty)
+        .bind(persona.global)
+        .fetch_optional(&pool)
+        .await?;
+    Ok(saved.map(|(id,)| id))
+}
+
+pub async fn delete_persona(id: i64, user_id: i32) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query("DELETE FROM persona WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
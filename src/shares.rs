@@ -0,0 +1,68 @@
+// Share links: a snapshot of a conversation anybody with the token can read
+use crate::app::ssr::{create_db_conn, new_session_token};
+use crate::components::jippity::Message;
+use crate::components::share::{ShareLink, SharedConversation};
+use leptos::ServerFnError;
+
+// Stores the messages if the user owns the conversation. Returns the token of the new link.
+pub async fn create_share(
+    user_id: i32,
+    conversation_id: i64,
+    messages: &[Message],
+) -> Result<Option<String>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let token = new_session_token();
+    let created = sqlx::query(
+        "INSERT INTO share_link (token, user_id, conversation_id, title, snapshot)
+         SELECT $1, user_id, id, title, $4 FROM conversation WHERE id = $2 AND user_id = $3"
+    )
+    .bind(&token)
+    .bind(conversation_id)
+    .bind(user_id)
+    .bind(serde_json::to_string(messages)?)
+    .execute(&pool)
+    .await?
+    .rows_affected();
+    Ok((created > 0).then_some(token))
+}
+
+pub async fn list_shares(user_id: i32) -> Result<Vec<ShareLink>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let rows: Vec<(String, i64, String, String)> = sqlx::query_as(
+        "SELECT token, conversation_id, title, to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI')
+         FROM share_link WHERE user_id = $1 ORDER BY created_at DESC"
+    )
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(token, conversation_id, title, created_at)| ShareLink { token, conversation_id, title, created_at })
+        .collect())
+}
+
+pub async fn delete_share(token: &str, user_id: i32) -> Result<(), ServerFnError> {
+    let pool = create_db_conn().await?;
+    sqlx::query("DELETE FROM share_link WHERE token = $1 AND user_id = $2")
+        .bind(token)
+        .bind(user_id)
+        .execute(&pool)
+        .await?;
+    Ok(())
+}
+
+// None for an unknown or revoked token
+pub async fn load_share(token: &str) -> Result<Option<SharedConversation>, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let row: Option<(String, String, String)> = sqlx::query_as(
+        "SELECT title, to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD'), snapshot FROM share_link WHERE token = $1"
+    )
+    .bind(token)
+    .fetch_optional(&pool)
+    .await?;
+    let Some((title, shared_at, snapshot)) = row else {
+        return Ok(None);
+    };
+    Ok(Some(SharedConversation { title, shared_at, messages: serde_json::from_str(&snapshot)? }))
+}
@@ -0,0 +1,76 @@
+// Token usage of every inference, and the quotas checked against it.
+// Days and months are those of the database server.
+use crate::app::ssr::{create_db_conn, AppState};
+use crate::components::usage::{Budget, UsageInfo};
+use leptos::ServerFnError;
+use std::time::Duration;
+
+// Runs in the background after an inference, a failure only loses the record.
+// `cached` answers came from the response cache and don't count against the quotas.
+pub async fn record_usage(
+    user_id: i32,
+    model_id: String,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    wall: Duration,
+    cached: bool,
+) {
+    let stored = async {
+        let pool = create_db_conn().await?;
+        sqlx::query(
+            "INSERT INTO usage (user_id, model_id, prompt_tokens, completion_tokens, wall_ms, cached)
+             VALUES ($1, $2, $3, $4, $5, $6)"
+        )
+        .bind(user_id)
+        .bind(&model_id)
+        .bind(prompt_tokens as i32)
+        .bind(completion_tokens as i32)
+        .bind(wall.as_millis() as i32)
+        .bind(cached)
+        .execute(&pool)
+        .await?;
+        Ok::<_, ServerFnError>(())
+    };
+    if let Err(err) = stored.await {
+        eprintln!("Could not record the usage of user {user_id}: {err}");
+    }
+}
+
+// Tokens used today and this month, with the limits of the user's role or their own ones
+pub async fn usage_info(state: &AppState, user_id: i32) -> Result<UsageInfo, ServerFnError> {
+    let pool = create_db_conn().await?;
+    let (is_admin, daily_limit, monthly_limit): (bool, Option<i64>, Option<i64>) = sqlx::query_as(
+        "SELECT u.is_admin, q.daily_tokens, q.monthly_tokens
+         FROM user_table u LEFT JOIN user_quota q ON q.user_id = u.id
+         WHERE u.id = $1"
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await?;
+    let (daily, monthly): (i64, i64) = sqlx::query_as(
+        "SELECT COALESCE(SUM(prompt_tokens + completion_tokens) FILTER (WHERE created_at >= date_trunc('day', now())), 0),
+                COALESCE(SUM(prompt_tokens + completion_tokens), 0)
+         FROM usage WHERE user_id = $1 AND NOT cached AND created_at >= date_trunc('month', now())"
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await?;
+
+    let role = state.config.quotas.for_role(is_admin);
+    Ok(UsageInfo {
+        daily: Budget {
+            used: daily as u64,
+            limit: daily_limit.map(|limit| limit as u64).or(role.daily_tokens),
+        },
+        monthly: Budget {
+            used: monthly as u64,
+            limit: monthly_limit.map(|limit| limit as u64).or(role.monthly_tokens),
+        },
+    })
+}
+
+// A ServerFnError with the QuotaExceeded message once a budget is used up
+pub async fn check_quota(state: &AppState, user_id: i32) -> Result<(), ServerFnError> {
+    usage_info(state, user_id).await?.check()?;
+    Ok(())
+}